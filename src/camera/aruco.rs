@@ -0,0 +1,555 @@
+use crate::camera::calibration::CameraIntrinsics;
+use crate::camera::composite;
+use crate::camera::utils;
+use opencv::core::{self, Mat, Point2f, Point3f, Scalar, Size, Vector};
+use opencv::objdetect::{
+    self, ArucoDetector, CharucoBoard, CharucoDetector, DetectorParameters, Dictionary,
+    PredefinedDictionaryType, RefineParameters,
+};
+use opencv::{calib3d, imgcodecs, prelude::*};
+use std::time::SystemTime;
+
+// 姿勢を推定するのに必要なChArUcoのコーナー数
+const MIN_BOARD_CORNERS: usize = 4;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub enum ArucoDictionary {
+    #[serde(rename = "4x4_50")]
+    Aruco4x4_50,
+    #[serde(rename = "4x4_100")]
+    Aruco4x4_100,
+    #[serde(rename = "4x4_250")]
+    Aruco4x4_250,
+    #[serde(rename = "4x4_1000")]
+    Aruco4x4_1000,
+    #[serde(rename = "5x5_50")]
+    Aruco5x5_50,
+    #[serde(rename = "5x5_100")]
+    Aruco5x5_100,
+    #[serde(rename = "5x5_250")]
+    Aruco5x5_250,
+    #[serde(rename = "5x5_1000")]
+    Aruco5x5_1000,
+    #[serde(rename = "6x6_50")]
+    Aruco6x6_50,
+    #[serde(rename = "6x6_100")]
+    Aruco6x6_100,
+    #[serde(rename = "6x6_250")]
+    Aruco6x6_250,
+    #[serde(rename = "6x6_1000")]
+    Aruco6x6_1000,
+    #[serde(rename = "7x7_50")]
+    Aruco7x7_50,
+    #[serde(rename = "7x7_100")]
+    Aruco7x7_100,
+    #[serde(rename = "7x7_250")]
+    Aruco7x7_250,
+    #[serde(rename = "7x7_1000")]
+    Aruco7x7_1000,
+    #[serde(rename = "aruco_original")]
+    ArucoOriginal,
+    #[serde(rename = "apriltag_16h5")]
+    AprilTag16h5,
+    #[serde(rename = "apriltag_25h9")]
+    AprilTag25h9,
+    #[serde(rename = "apriltag_36h10")]
+    AprilTag36h10,
+    #[serde(rename = "apriltag_36h11")]
+    AprilTag36h11,
+}
+
+impl ArucoDictionary {
+    fn value(&self) -> PredefinedDictionaryType {
+        use PredefinedDictionaryType::*;
+        match self {
+            ArucoDictionary::Aruco4x4_50 => DICT_4X4_50,
+            ArucoDictionary::Aruco4x4_100 => DICT_4X4_100,
+            ArucoDictionary::Aruco4x4_250 => DICT_4X4_250,
+            ArucoDictionary::Aruco4x4_1000 => DICT_4X4_1000,
+            ArucoDictionary::Aruco5x5_50 => DICT_5X5_50,
+            ArucoDictionary::Aruco5x5_100 => DICT_5X5_100,
+            ArucoDictionary::Aruco5x5_250 => DICT_5X5_250,
+            ArucoDictionary::Aruco5x5_1000 => DICT_5X5_1000,
+            ArucoDictionary::Aruco6x6_50 => DICT_6X6_50,
+            ArucoDictionary::Aruco6x6_100 => DICT_6X6_100,
+            ArucoDictionary::Aruco6x6_250 => DICT_6X6_250,
+            ArucoDictionary::Aruco6x6_1000 => DICT_6X6_1000,
+            ArucoDictionary::Aruco7x7_50 => DICT_7X7_50,
+            ArucoDictionary::Aruco7x7_100 => DICT_7X7_100,
+            ArucoDictionary::Aruco7x7_250 => DICT_7X7_250,
+            ArucoDictionary::Aruco7x7_1000 => DICT_7X7_1000,
+            ArucoDictionary::ArucoOriginal => DICT_ARUCO_ORIGINAL,
+            ArucoDictionary::AprilTag16h5 => DICT_APRILTAG_16h5,
+            ArucoDictionary::AprilTag25h9 => DICT_APRILTAG_25h9,
+            ArucoDictionary::AprilTag36h10 => DICT_APRILTAG_36h10,
+            ArucoDictionary::AprilTag36h11 => DICT_APRILTAG_36h11,
+        }
+    }
+
+    pub fn dictionary(&self) -> Result<Dictionary, opencv::Error> {
+        objdetect::get_predefined_dictionary(self.value())
+    }
+}
+
+/*
+* ChArUcoボード
+* {"dictionary": "4x4_50", "squares_x": 5, "squares_y": 7, "square_length": 0.04, "marker_length": 0.02}
+* square_length, marker_length -> チェスボードの1マスとマーカーの辺の長さ(メートル)
+*/
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct CharucoBoardParams {
+    pub dictionary: ArucoDictionary,
+    pub squares_x: i32,
+    pub squares_y: i32,
+    pub square_length: f32,
+    pub marker_length: f32,
+}
+
+impl Default for CharucoBoardParams {
+    fn default() -> Self {
+        Self {
+            dictionary: ArucoDictionary::Aruco4x4_50,
+            squares_x: 5,
+            squares_y: 7,
+            square_length: 0.04,
+            marker_length: 0.02,
+        }
+    }
+}
+
+impl CharucoBoardParams {
+    pub fn create(&self) -> Result<CharucoBoard, opencv::Error> {
+        CharucoBoard::new_def(
+            Size::new(self.squares_x, self.squares_y),
+            self.square_length,
+            self.marker_length,
+            &self.dictionary.dictionary()?,
+        )
+    }
+}
+
+/*
+* {"dictionary": "6x6_250", "marker_length": 0.05, "calibration": "cam0"}
+* marker_length -> マーカーの辺の長さ(メートル)。姿勢の並進tvecはこの単位になる
+* calibration -> calibration/<名前>.json の内部パラメータを使って姿勢を推定する(省略時は推定しない)
+* axis_length -> 描く座標軸の長さ(0の場合はmarker_lengthの半分)
+* board -> 指定した場合はChArUcoボードを検出し、ボード全体の姿勢も推定する
+*/
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct ArucoParams {
+    pub dictionary: ArucoDictionary,
+    pub marker_length: f32,
+    pub calibration: Option<String>,
+    pub axis_length: f32,
+    pub board: Option<CharucoBoardParams>,
+}
+
+impl Default for ArucoParams {
+    fn default() -> Self {
+        Self {
+            dictionary: ArucoDictionary::Aruco4x4_50,
+            marker_length: 0.05,
+            calibration: None,
+            axis_length: 0.0,
+            board: None,
+        }
+    }
+}
+
+/*
+* rvec -> 回転ベクトル(Rodrigues), tvec -> カメラ座標系での位置
+* euler -> x, y, z軸回りの回転角(度), distance -> カメラからの距離
+*/
+#[derive(Debug, Clone, Copy, serde::Serialize)]
+pub struct Pose {
+    pub rvec: [f64; 3],
+    pub tvec: [f64; 3],
+    pub euler: [f64; 3],
+    pub distance: f64,
+}
+
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct Marker {
+    pub id: i32,
+    pub corners: [[f32; 2]; 4],
+    pub center: [f32; 2],
+    pub pose: Option<Pose>,
+}
+
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct BoardResult {
+    pub corners: usize,
+    pub pose: Option<Pose>,
+}
+
+pub struct ArucoResult {
+    pub markers: Vec<Marker>,
+    pub board: Option<BoardResult>,
+    marker_corners: Vector<Vector<Point2f>>,
+    marker_ids: Vector<i32>,
+    board_corners: Vector<Point2f>,
+    board_ids: Vector<i32>,
+    intrinsics: Option<CameraIntrinsics>,
+}
+
+// 検出器と内部パラメータをノードの状態として保持し、パラメータが変わった場合のみ作り直す
+#[derive(Default)]
+pub struct ArucoCache {
+    dictionary: Option<ArucoDictionary>,
+    detector: Option<ArucoDetector>,
+    board_params: Option<CharucoBoardParams>,
+    board: Option<(CharucoBoard, CharucoDetector)>,
+    // 読み込みに失敗した場合もNoneとして保持し、ファイルが更新されるまで読み直さない
+    intrinsics: Option<(String, Option<SystemTime>, Option<CameraIntrinsics>)>,
+}
+
+impl ArucoCache {
+    fn detector(&mut self, dictionary: ArucoDictionary) -> Result<&ArucoDetector, opencv::Error> {
+        if self.detector.is_none() || self.dictionary != Some(dictionary) {
+            self.detector = Some(ArucoDetector::new(
+                &dictionary.dictionary()?,
+                &DetectorParameters::default()?,
+                RefineParameters::new_def()?,
+            )?);
+            self.dictionary = Some(dictionary);
+        }
+        Ok(self.detector.as_ref().unwrap())
+    }
+
+    fn board(
+        &mut self,
+        params: &CharucoBoardParams,
+    ) -> Result<&(CharucoBoard, CharucoDetector), opencv::Error> {
+        if self.board.is_none() || self.board_params.as_ref() != Some(params) {
+            let board = params.create()?;
+            let detector = CharucoDetector::new_def(&board)?;
+            self.board = Some((board, detector));
+            self.board_params = Some(params.clone());
+        }
+        Ok(self.board.as_ref().unwrap())
+    }
+
+    fn intrinsics(&mut self, name: &str) -> Option<&CameraIntrinsics> {
+        let modified = CameraIntrinsics::modified(name);
+        let loaded = self
+            .intrinsics
+            .as_ref()
+            .map(|(loaded, loaded_modified, _)| (loaded.as_str(), *loaded_modified));
+        if loaded != Some((name, modified)) {
+            let intrinsics = CameraIntrinsics::load(name)
+                .map_err(|err| println!("WARN: {}", err.message))
+                .ok();
+            self.intrinsics = Some((name.to_string(), modified, intrinsics));
+        }
+        self.intrinsics
+            .as_ref()
+            .and_then(|(_, _, intrinsics)| intrinsics.as_ref())
+    }
+}
+
+pub fn detect(
+    frame: &Mat,
+    cache: &mut ArucoCache,
+    params: &ArucoParams,
+) -> Result<ArucoResult, opencv::Error> {
+    let mut marker_corners = Vector::<Vector<Point2f>>::new();
+    let mut marker_ids = Vector::<i32>::new();
+    cache.detector(params.dictionary)?.detect_markers(
+        frame,
+        &mut marker_corners,
+        &mut marker_ids,
+        &mut core::no_array(),
+    )?;
+
+    let size = frame.size()?;
+    let intrinsics = match &params.calibration {
+        Some(name) => cache
+            .intrinsics(name)
+            .map(|intrinsics| intrinsics.scaled(size)),
+        None => None,
+    };
+    let mut markers = vec![];
+    for (id, corners) in marker_ids.iter().zip(marker_corners.iter()) {
+        let pose = match &intrinsics {
+            Some(intrinsics) => Some(marker_pose(&corners, params.marker_length, intrinsics)?),
+            None => None,
+        };
+        let corners: Vec<Point2f> = corners.to_vec();
+        let center = corners.iter().fold([0.0, 0.0], |sum, corner| {
+            [sum[0] + corner.x / 4.0, sum[1] + corner.y / 4.0]
+        });
+        markers.push(Marker {
+            id,
+            corners: [0, 1, 2, 3].map(|i| [corners[i].x, corners[i].y]),
+            center,
+            pose,
+        });
+    }
+
+    let mut board_corners = Vector::<Point2f>::new();
+    let mut board_ids = Vector::<i32>::new();
+    let board = match &params.board {
+        Some(board_params) => {
+            let (board, detector) = cache.board(board_params)?;
+            detector.detect_board(
+                frame,
+                &mut board_corners,
+                &mut board_ids,
+                &mut Vector::<Vector<Point2f>>::new(),
+                &mut Vector::<i32>::new(),
+            )?;
+            let pose = match &intrinsics {
+                Some(intrinsics) if board_corners.len() >= MIN_BOARD_CORNERS => {
+                    board_pose(board, &board_corners, &board_ids, intrinsics)?
+                }
+                _ => None,
+            };
+            Some(BoardResult {
+                corners: board_corners.len(),
+                pose,
+            })
+        }
+        None => None,
+    };
+
+    Ok(ArucoResult {
+        markers,
+        board,
+        marker_corners,
+        marker_ids,
+        board_corners,
+        board_ids,
+        intrinsics,
+    })
+}
+
+// マーカーの中心を原点とし、IPPE_SQUAREが要求する順(左上から時計回り)に角を並べる
+fn marker_pose(
+    corners: &Vector<Point2f>,
+    marker_length: f32,
+    intrinsics: &CameraIntrinsics,
+) -> Result<Pose, opencv::Error> {
+    let half = marker_length / 2.0;
+    let object_points = Vector::<Point3f>::from_iter([
+        Point3f::new(-half, half, 0.0),
+        Point3f::new(half, half, 0.0),
+        Point3f::new(half, -half, 0.0),
+        Point3f::new(-half, -half, 0.0),
+    ]);
+    solve_pose(
+        &object_points,
+        corners,
+        intrinsics,
+        calib3d::SOLVEPNP_IPPE_SQUARE,
+    )
+}
+
+fn board_pose(
+    board: &CharucoBoard,
+    corners: &Vector<Point2f>,
+    ids: &Vector<i32>,
+    intrinsics: &CameraIntrinsics,
+) -> Result<Option<Pose>, opencv::Error> {
+    let mut object_points = Vector::<Point3f>::new();
+    let mut image_points = Vector::<Point2f>::new();
+    board.match_image_points(corners, ids, &mut object_points, &mut image_points)?;
+    if object_points.len() < MIN_BOARD_CORNERS {
+        return Ok(None);
+    }
+    let pose = solve_pose(
+        &object_points,
+        &image_points,
+        intrinsics,
+        calib3d::SOLVEPNP_ITERATIVE,
+    )?;
+    Ok(Some(pose))
+}
+
+fn solve_pose(
+    object_points: &Vector<Point3f>,
+    image_points: &Vector<Point2f>,
+    intrinsics: &CameraIntrinsics,
+    flags: i32,
+) -> Result<Pose, opencv::Error> {
+    let mut rvec = Mat::default();
+    let mut tvec = Mat::default();
+    calib3d::solve_pnp(
+        object_points,
+        image_points,
+        &intrinsics.camera_matrix()?,
+        &intrinsics.dist_coeffs()?,
+        &mut rvec,
+        &mut tvec,
+        false,
+        flags,
+    )?;
+    let rvec: [f64; 3] = to_array(&rvec)?;
+    let tvec: [f64; 3] = to_array(&tvec)?;
+    Ok(Pose {
+        rvec,
+        tvec,
+        euler: euler_angles(&rvec)?,
+        distance: tvec.iter().map(|value| value * value).sum::<f64>().sqrt(),
+    })
+}
+
+fn to_array(vector: &Mat) -> Result<[f64; 3], opencv::Error> {
+    let values = vector.data_typed::<f64>()?;
+    Ok([values[0], values[1], values[2]])
+}
+
+// 回転ベクトルから x, y, z軸回りの回転角(度)を求める
+fn euler_angles(rvec: &[f64; 3]) -> Result<[f64; 3], opencv::Error> {
+    let mut rotation = Mat::default();
+    calib3d::rodrigues(
+        &Mat::from_slice(rvec)?,
+        &mut rotation,
+        &mut core::no_array(),
+    )?;
+    let r = |row: i32, col: i32| rotation.at_2d::<f64>(row, col).copied();
+    let sy = r(0, 0)?.hypot(r(1, 0)?);
+    let angles = match sy > 1e-6 {
+        true => [
+            r(2, 1)?.atan2(r(2, 2)?),
+            (-r(2, 0)?).atan2(sy),
+            r(1, 0)?.atan2(r(0, 0)?),
+        ],
+        // ジンバルロック
+        false => [(-r(1, 2)?).atan2(r(1, 1)?), (-r(2, 0)?).atan2(sy), 0.0],
+    };
+    Ok(angles.map(f64::to_degrees))
+}
+
+pub fn draw(frame: &Mat, result: &ArucoResult, params: &ArucoParams) -> Result<Mat, opencv::Error> {
+    let mut drawn = composite::to_bgr(frame)?;
+    objdetect::draw_detected_markers(
+        &mut drawn,
+        &result.marker_corners,
+        &result.marker_ids,
+        Scalar::new(0.0, 255.0, 0.0, 0.0),
+    )?;
+    if !result.board_corners.is_empty() {
+        objdetect::draw_detected_corners_charuco(
+            &mut drawn,
+            &result.board_corners,
+            &result.board_ids,
+            Scalar::new(255.0, 0.0, 0.0, 0.0),
+        )?;
+    }
+
+    let Some(intrinsics) = &result.intrinsics else {
+        return Ok(drawn);
+    };
+    let axis_length = match params.axis_length > 0.0 {
+        true => params.axis_length,
+        false => params.marker_length / 2.0,
+    };
+    let board_pose = result.board.as_ref().and_then(|board| board.pose);
+    let poses = result.markers.iter().filter_map(|marker| marker.pose);
+    for pose in poses.chain(board_pose) {
+        calib3d::draw_frame_axes(
+            &mut drawn,
+            &intrinsics.camera_matrix()?,
+            &intrinsics.dist_coeffs()?,
+            &Mat::from_slice(&pose.rvec)?.try_clone()?,
+            &Mat::from_slice(&pose.tvec)?.try_clone()?,
+            axis_length,
+            2,
+        )?;
+    }
+    Ok(drawn)
+}
+
+/*
+* 印刷用のマーカー画像(PNG)
+* {"dictionary": "4x4_50", "id": 0, "size": 200, "border_bits": 1}
+*/
+#[derive(Debug, Clone, serde::Deserialize)]
+#[serde(default)]
+pub struct MarkerImageParams {
+    pub dictionary: ArucoDictionary,
+    pub id: i32,
+    pub size: i32,
+    pub border_bits: i32,
+}
+
+impl Default for MarkerImageParams {
+    fn default() -> Self {
+        Self {
+            dictionary: ArucoDictionary::Aruco4x4_50,
+            id: 0,
+            size: 200,
+            border_bits: 1,
+        }
+    }
+}
+
+pub fn marker_image(params: &MarkerImageParams) -> Result<Vec<u8>, opencv::Error> {
+    utils::check_image_size("size", params.size)?;
+    let mut image = Mat::default();
+    objdetect::generate_image_marker(
+        &params.dictionary.dictionary()?,
+        params.id,
+        params.size,
+        &mut image,
+        params.border_bits,
+    )?;
+    encode_png(&image)
+}
+
+/*
+* 印刷用のChArUcoボード画像(PNG)
+* {"dictionary": "4x4_50", "squares_x": 5, "squares_y": 7, "width": 1000, "height": 1400, "margin": 20}
+* 画像の大きさはマスの数の比に合わせる(マスの大きさは印刷時に実測してsquare_lengthに指定する)
+*/
+#[derive(Debug, Clone, serde::Deserialize)]
+#[serde(default)]
+pub struct BoardImageParams {
+    pub dictionary: ArucoDictionary,
+    pub squares_x: i32,
+    pub squares_y: i32,
+    pub width: i32,
+    pub height: i32,
+    pub margin: i32,
+}
+
+impl Default for BoardImageParams {
+    fn default() -> Self {
+        let board = CharucoBoardParams::default();
+        Self {
+            dictionary: board.dictionary,
+            squares_x: board.squares_x,
+            squares_y: board.squares_y,
+            width: 1000,
+            height: 1400,
+            margin: 20,
+        }
+    }
+}
+
+pub fn board_image(params: &BoardImageParams) -> Result<Vec<u8>, opencv::Error> {
+    utils::check_image_size("width", params.width)?;
+    utils::check_image_size("height", params.height)?;
+    let board = CharucoBoardParams {
+        dictionary: params.dictionary,
+        squares_x: params.squares_x,
+        squares_y: params.squares_y,
+        ..Default::default()
+    };
+    let mut image = Mat::default();
+    board.create()?.generate_image(
+        Size::new(params.width, params.height),
+        &mut image,
+        params.margin,
+        1,
+    )?;
+    encode_png(&image)
+}
+
+fn encode_png(image: &Mat) -> Result<Vec<u8>, opencv::Error> {
+    let mut buf = Vector::new();
+    imgcodecs::imencode(".png", image, &mut buf, &Vector::new())?;
+    Ok(buf.to_vec())
+}
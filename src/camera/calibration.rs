@@ -0,0 +1,435 @@
+use crate::camera::aruco::CharucoBoardParams;
+use crate::camera::source;
+use crate::camera::{composite, utils};
+use opencv::core::{
+    self, Mat, Point2f, Point3f, Scalar, Size, StsBadArg, StsError, TermCriteria, Vector,
+};
+use opencv::objdetect::{self, CharucoBoard, CharucoDetector};
+use opencv::{calib3d, imgproc, prelude::*};
+use std::time::SystemTime;
+
+pub const CALIBRATION_DIR: &str = "calibration";
+// 内部パラメータを求めるのに必要な画像の枚数
+const MIN_CALIBRATION_VIEWS: usize = 5;
+// ChArUcoボードの1枚の画像で必要なコーナー数
+const MIN_CHARUCO_CORNERS: usize = 6;
+
+/*
+* カメラの内部パラメータ(calibration/<名前>.json)
+* {"image_size": [640, 480],
+*  "camera_matrix": [[fx, 0, cx], [0, fy, cy], [0, 0, 1]],
+*  "dist_coeffs": [k1, k2, p1, p2, k3], "rms": 0.3}
+* rms -> キャリブレーション時の再投影誤差(画素)
+*/
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct CameraIntrinsics {
+    pub image_size: [i32; 2],
+    pub camera_matrix: [[f64; 3]; 3],
+    pub dist_coeffs: Vec<f64>,
+    #[serde(default)]
+    pub rms: f64,
+}
+
+impl CameraIntrinsics {
+    pub fn load(name: &str) -> Result<Self, opencv::Error> {
+        let path = calibration_path(name)?;
+        let text = std::fs::read_to_string(&path).map_err(|err| {
+            opencv::Error::new(StsError, format!("failed to read {}: {}", path, err))
+        })?;
+        serde_json::from_str(&text).map_err(|err| {
+            opencv::Error::new(StsBadArg, format!("invalid calibration {}: {}", path, err))
+        })
+    }
+
+    // 保存したファイルのパスを返す
+    pub fn save(&self, name: &str) -> Result<String, opencv::Error> {
+        let path = calibration_path(name)?;
+        let text = serde_json::to_string_pretty(self)
+            .map_err(|err| opencv::Error::new(StsError, err.to_string()))?;
+        std::fs::create_dir_all(CALIBRATION_DIR)
+            .and_then(|_| std::fs::write(&path, text))
+            .map_err(|err| {
+                opencv::Error::new(StsError, format!("failed to write {}: {}", path, err))
+            })?;
+        Ok(path)
+    }
+
+    // ファイルの更新日時(再キャリブレーションされたかどうかの判定に使う)
+    pub fn modified(name: &str) -> Option<SystemTime> {
+        let path = calibration_path(name).ok()?;
+        std::fs::metadata(path)
+            .and_then(|meta| meta.modified())
+            .ok()
+    }
+
+    // キャリブレーション時と解像度が異なる場合は、焦点距離と主点を比率で合わせる
+    pub fn scaled(&self, size: Size) -> Self {
+        let scale_x = size.width as f64 / self.image_size[0].max(1) as f64;
+        let scale_y = size.height as f64 / self.image_size[1].max(1) as f64;
+        let mut camera_matrix = self.camera_matrix;
+        camera_matrix[0][0] *= scale_x;
+        camera_matrix[0][2] *= scale_x;
+        camera_matrix[1][1] *= scale_y;
+        camera_matrix[1][2] *= scale_y;
+        Self {
+            image_size: [size.width, size.height],
+            camera_matrix,
+            dist_coeffs: self.dist_coeffs.clone(),
+            rms: self.rms,
+        }
+    }
+
+    pub fn camera_matrix(&self) -> Result<Mat, opencv::Error> {
+        Mat::from_slice_2d(&self.camera_matrix)
+    }
+
+    pub fn dist_coeffs(&self) -> Result<Mat, opencv::Error> {
+        Mat::from_slice(&self.dist_coeffs)?.try_clone()
+    }
+}
+
+// キャリブレーションディレクトリの外のファイルは読み込まない
+pub fn calibration_path(name: &str) -> Result<String, opencv::Error> {
+    if name.is_empty() || name.contains('/') || name.contains('\\') || name.starts_with('.') {
+        let message = format!("invalid calibration name: {}", name);
+        return Err(opencv::Error::new(StsBadArg, message));
+    }
+    Ok(format!("{}/{}.json", CALIBRATION_DIR, name))
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CalibrationPattern {
+    #[default]
+    Chessboard,
+    Charuco,
+}
+
+/*
+* {"pattern": "chessboard", "columns": 9, "rows": 6, "square_length": 0.025}
+* {"pattern": "charuco", "board": {"dictionary": "4x4_50", "squares_x": 5, "squares_y": 7}}
+* columns, rows -> チェスボードの内側の交点の数
+* square_length -> チェスボードの1マスの長さ(メートル)
+*/
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct CalibrationSettings {
+    pub pattern: CalibrationPattern,
+    pub columns: i32,
+    pub rows: i32,
+    pub square_length: f32,
+    pub board: CharucoBoardParams,
+}
+
+impl Default for CalibrationSettings {
+    fn default() -> Self {
+        Self {
+            pattern: CalibrationPattern::Chessboard,
+            columns: 9,
+            rows: 6,
+            square_length: 0.025,
+            board: CharucoBoardParams::default(),
+        }
+    }
+}
+
+// views -> 追加した画像の枚数, detected -> 直前のフレームでパターンを検出できたか
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct CalibrationStatus {
+    pub pattern: CalibrationPattern,
+    pub views: usize,
+    pub required: usize,
+    pub detected: bool,
+}
+
+// 溜めた対応点。内部パラメータの計算は時間がかかるため、セッションから複製して別スレッドで行う
+pub struct CalibrationViews {
+    image_size: Size,
+    object_points: Vector<Vector<Point3f>>,
+    image_points: Vector<Vector<Point2f>>,
+}
+
+// ライブ映像からパターンを検出し、クライアントの指示で1枚ずつ対応点を溜める
+pub struct CalibrationSession {
+    settings: CalibrationSettings,
+    board: Option<(CharucoBoard, CharucoDetector)>,
+    image_size: Size,
+    object_points: Vector<Vector<Point3f>>,
+    image_points: Vector<Vector<Point2f>>,
+    // 直前のフレームで検出できた対応点(captureで採用する)
+    detected: Option<(Vector<Point3f>, Vector<Point2f>)>,
+}
+
+impl CalibrationSession {
+    pub fn new(settings: CalibrationSettings) -> Result<Self, opencv::Error> {
+        let board = match settings.pattern {
+            CalibrationPattern::Chessboard => {
+                if settings.columns < 2 || settings.rows < 2 {
+                    let message = "chessboard needs at least 2x2 inner corners";
+                    return Err(opencv::Error::new(StsBadArg, message));
+                }
+                None
+            }
+            CalibrationPattern::Charuco => {
+                let board = settings.board.create()?;
+                let detector = CharucoDetector::new_def(&board)?;
+                Some((board, detector))
+            }
+        };
+        Ok(Self {
+            settings,
+            board,
+            image_size: Size::default(),
+            object_points: Vector::new(),
+            image_points: Vector::new(),
+            detected: None,
+        })
+    }
+
+    // パターンを検出し、検出結果を描いたフレームを返す
+    pub fn detect(&mut self, frame: &Mat) -> Result<Mat, opencv::Error> {
+        let size = frame.size()?;
+        if size != self.image_size && !self.image_points.is_empty() {
+            println!("WARN: resolution changed during calibration, views are discarded");
+            self.object_points.clear();
+            self.image_points.clear();
+        }
+        self.image_size = size;
+
+        let gray_frame = utils::to_gray(frame)?;
+        let mut drawn = composite::to_bgr(frame)?;
+        self.detected = match &self.board {
+            Some((board, detector)) => detect_charuco(&gray_frame, &mut drawn, board, detector)?,
+            None => detect_chessboard(&gray_frame, &mut drawn, &self.settings)?,
+        };
+        Ok(drawn)
+    }
+
+    // 直前に検出できた対応点を1枚分として追加する
+    pub fn capture(&mut self) -> Result<(), opencv::Error> {
+        let (object_points, image_points) = self
+            .detected
+            .take()
+            .ok_or_else(|| opencv::Error::new(StsError, "calibration pattern is not detected"))?;
+        self.object_points.push(object_points);
+        self.image_points.push(image_points);
+        Ok(())
+    }
+
+    pub fn status(&self) -> CalibrationStatus {
+        CalibrationStatus {
+            pattern: self.settings.pattern,
+            views: self.image_points.len(),
+            required: MIN_CALIBRATION_VIEWS,
+            detected: self.detected.is_some(),
+        }
+    }
+
+    pub fn views(&self) -> CalibrationViews {
+        CalibrationViews {
+            image_size: self.image_size,
+            object_points: self.object_points.clone(),
+            image_points: self.image_points.clone(),
+        }
+    }
+}
+
+impl CalibrationViews {
+    pub fn compute(&self) -> Result<CameraIntrinsics, opencv::Error> {
+        if self.image_points.len() < MIN_CALIBRATION_VIEWS {
+            let message = format!(
+                "calibration needs at least {} views ({} captured)",
+                MIN_CALIBRATION_VIEWS,
+                self.image_points.len()
+            );
+            return Err(opencv::Error::new(StsError, message));
+        }
+        let mut camera_matrix = Mat::default();
+        let mut dist_coeffs = Mat::default();
+        let rms = calib3d::calibrate_camera_def(
+            &self.object_points,
+            &self.image_points,
+            self.image_size,
+            &mut camera_matrix,
+            &mut dist_coeffs,
+            &mut Vector::<Mat>::new(),
+            &mut Vector::<Mat>::new(),
+        )?;
+        let mut matrix = [[0.0; 3]; 3];
+        for (row, values) in matrix.iter_mut().enumerate() {
+            for (col, value) in values.iter_mut().enumerate() {
+                *value = *camera_matrix.at_2d::<f64>(row as i32, col as i32)?;
+            }
+        }
+        Ok(CameraIntrinsics {
+            image_size: [self.image_size.width, self.image_size.height],
+            camera_matrix: matrix,
+            dist_coeffs: dist_coeffs.data_typed::<f64>()?.to_vec(),
+            rms,
+        })
+    }
+}
+
+// 内側の交点を左上から行ごとに並べ、z=0の平面上の点とする
+fn detect_chessboard(
+    gray_frame: &Mat,
+    drawn: &mut Mat,
+    settings: &CalibrationSettings,
+) -> Result<Option<(Vector<Point3f>, Vector<Point2f>)>, opencv::Error> {
+    let pattern_size = Size::new(settings.columns, settings.rows);
+    let mut corners = Vector::<Point2f>::new();
+    let flags = calib3d::CALIB_CB_ADAPTIVE_THRESH
+        | calib3d::CALIB_CB_NORMALIZE_IMAGE
+        | calib3d::CALIB_CB_FAST_CHECK;
+    let found = calib3d::find_chessboard_corners(gray_frame, pattern_size, &mut corners, flags)?;
+    if found {
+        let criteria =
+            TermCriteria::new(core::TermCriteria_EPS + core::TermCriteria_COUNT, 30, 0.001)?;
+        imgproc::corner_sub_pix(
+            gray_frame,
+            &mut corners,
+            Size::new(11, 11),
+            Size::new(-1, -1),
+            criteria,
+        )?;
+    }
+    calib3d::draw_chessboard_corners(drawn, pattern_size, &corners, found)?;
+    if !found {
+        return Ok(None);
+    }
+    let square = settings.square_length;
+    let object_points = (0..settings.rows)
+        .flat_map(|row| {
+            (0..settings.columns)
+                .map(move |col| Point3f::new(col as f32 * square, row as f32 * square, 0.0))
+        })
+        .collect();
+    Ok(Some((object_points, corners)))
+}
+
+// ボードの一部が隠れていても、検出できたコーナーが十分あれば使う
+fn detect_charuco(
+    gray_frame: &Mat,
+    drawn: &mut Mat,
+    board: &CharucoBoard,
+    detector: &CharucoDetector,
+) -> Result<Option<(Vector<Point3f>, Vector<Point2f>)>, opencv::Error> {
+    let mut corners = Vector::<Point2f>::new();
+    let mut ids = Vector::<i32>::new();
+    detector.detect_board(
+        gray_frame,
+        &mut corners,
+        &mut ids,
+        &mut Vector::<Vector<Point2f>>::new(),
+        &mut Vector::<i32>::new(),
+    )?;
+    if corners.is_empty() {
+        return Ok(None);
+    }
+    objdetect::draw_detected_corners_charuco(
+        drawn,
+        &corners,
+        &ids,
+        Scalar::new(255.0, 0.0, 0.0, 0.0),
+    )?;
+    if corners.len() < MIN_CHARUCO_CORNERS {
+        return Ok(None);
+    }
+    let mut object_points = Vector::<Point3f>::new();
+    let mut image_points = Vector::<Point2f>::new();
+    board.match_image_points(&corners, &ids, &mut object_points, &mut image_points)?;
+    Ok(Some((object_points, image_points)))
+}
+
+/*
+* {"calibration": "cam0", "alpha": 0.0}
+* calibration -> calibration/<名前>.json の内部パラメータで歪みを補正する(省略時は既定のソース名)
+* alpha -> 0.0で補正後に無効な画素が出ないよう拡大し、1.0で元の画素をすべて残す
+*/
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct UndistortParams {
+    pub calibration: String,
+    pub alpha: f64,
+}
+
+impl Default for UndistortParams {
+    fn default() -> Self {
+        Self {
+            calibration: source::default_source_name(),
+            alpha: 0.0,
+        }
+    }
+}
+
+// 補正用のマップをノードの状態として保持し、ファイル・解像度・alphaが変わった場合のみ作り直す
+#[derive(Default)]
+pub struct UndistortCache {
+    key: Option<(String, Option<SystemTime>, Size, f64)>,
+    // 読み込みに失敗した場合もNoneとして保持し、毎フレーム読み直さない
+    maps: Option<(Mat, Mat)>,
+}
+
+impl UndistortCache {
+    fn maps(&mut self, params: &UndistortParams, size: Size) -> Option<&(Mat, Mat)> {
+        let modified = CameraIntrinsics::modified(&params.calibration);
+        let key = (params.calibration.clone(), modified, size, params.alpha);
+        if self.key.as_ref() != Some(&key) {
+            self.maps = undistort_maps(&params.calibration, size, params.alpha)
+                .map_err(|err| println!("WARN: {}", err.message))
+                .ok();
+            self.key = Some(key);
+        }
+        self.maps.as_ref()
+    }
+}
+
+fn undistort_maps(name: &str, size: Size, alpha: f64) -> Result<(Mat, Mat), opencv::Error> {
+    let intrinsics = CameraIntrinsics::load(name)?.scaled(size);
+    let camera_matrix = intrinsics.camera_matrix()?;
+    let dist_coeffs = intrinsics.dist_coeffs()?;
+    let new_camera_matrix = calib3d::get_optimal_new_camera_matrix(
+        &camera_matrix,
+        &dist_coeffs,
+        size,
+        alpha,
+        size,
+        None,
+        false,
+    )?;
+    let mut map1 = Mat::default();
+    let mut map2 = Mat::default();
+    calib3d::init_undistort_rectify_map(
+        &camera_matrix,
+        &dist_coeffs,
+        &core::no_array(),
+        &new_camera_matrix,
+        size,
+        core::CV_16SC2,
+        &mut map1,
+        &mut map2,
+    )?;
+    Ok((map1, map2))
+}
+
+// 内部パラメータが読み込めない場合はそのまま返す
+pub fn undistort(
+    frame: &Mat,
+    cache: &mut UndistortCache,
+    params: &UndistortParams,
+) -> Result<Mat, opencv::Error> {
+    let Some((map1, map2)) = cache.maps(params, frame.size()?) else {
+        return Ok(frame.clone());
+    };
+    let mut undistorted = Mat::default();
+    imgproc::remap(
+        frame,
+        &mut undistorted,
+        map1,
+        map2,
+        imgproc::INTER_LINEAR,
+        core::BORDER_CONSTANT,
+        Scalar::default(),
+    )?;
+    Ok(undistorted)
+}
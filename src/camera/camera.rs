@@ -1,50 +1,319 @@
-use crate::camera::frame_handler;
-use opencv::prelude::{MatTraitConst, VideoCaptureTrait, VideoCaptureTraitConst};
-use opencv::videoio::{
-    VideoCapture, CAP_PROP_FRAME_HEIGHT as CAP_H, CAP_PROP_FRAME_WIDTH as CAP_W,
+use crate::camera::calibration::{
+    CalibrationSession, CalibrationSettings, CalibrationStatus, CalibrationViews,
 };
-use opencv::{core::Mat, videoio};
+use crate::camera::color_tracker::{self, ColorSample};
+use crate::camera::composite;
+use crate::camera::pipeline::{self, PipelineError, ProcessNode, Stage};
+use crate::camera::pixel_format::PixelFormat;
+use crate::camera::properties::{self, PropertyError};
+use crate::camera::ptz::Ptz;
+use crate::camera::source::{NamedSource, SourceReader};
+use opencv::core::{Mat, StsError};
+use serde_json::{Map, Value};
+use std::collections::VecDeque;
+
+// カメラから取得されるフレームの形式
+const CAMERA_FORMAT: PixelFormat = PixelFormat::Bgr8;
+// タップ・デバッググリッドでカメラ出力そのものを指す名前
+const SOURCE_STAGE_NAME: &str = "camera";
+// REST APIで参照できる読み取ったコードの件数
+const CODE_HISTORY: usize = 100;
+// 送信されないまま溜まったイベントはこれを超えると古いものから捨てる
+const MAX_PENDING_EVENTS: usize = 256;
+// 色のサンプリングで入力を参照するステージ
+const COLOR_TRACKER_STAGE_NAME: &str = "color_tracker";
+
+// Stream -> 別ストリームとして送信, Pip -> 出力の右下に小窓で重ねる
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TapMode {
+    #[default]
+    Stream,
+    Pip,
+}
+
+#[derive(Debug, Clone)]
+pub struct Tap {
+    pub node: String,
+    pub mode: TapMode,
+}
 
 pub struct Camera {
     pub frame: Mat,
-    capture: videoio::VideoCapture,
-    process_chain: Vec<String>,
+    // パイプラインとePTZを通す前のフレーム(キャリブレーションに使う)
+    source_frame: Mat,
+    pub tap_frame: Option<Mat>,
+    // color_trackerに入力されたフレーム(色のサンプリングに使う)
+    tracker_input: Option<Mat>,
+    // 複数の場合は各ソースを格子状に並べた1枚をframeとする
+    readers: Vec<SourceReader>,
+    pipeline: Vec<Stage>,
+    // タップ・メタデータ・イベントで各ステージを指す名前(重複しない)
+    stage_names: Vec<String>,
+    tap: Option<Tap>,
+    debug_grid: bool,
+    // ステージが出力したクライアントへ送るイベントと、読み取ったコードの履歴
+    events: Vec<Value>,
+    decoded_codes: VecDeque<Value>,
+    // キャリブレーション中はパターンの検出結果を描いたソースの画像をframeとする
+    calibration: Option<CalibrationSession>,
+    // ソースの画像から表示範囲を切り出してからパイプラインに渡す
+    ptz: Ptz,
 }
 
 impl Camera {
-    pub fn new(camera_index: i32) -> Self {
-        let mut capture = VideoCapture::new(camera_index, videoio::CAP_ANY).expect("Error: Camera");
-        capture.is_opened().expect("Error: Camera Init");
-        capture.set(CAP_W, 640.0).expect("Error: Width Size");
-        capture.set(CAP_H, 480.0).expect("Error: Height Size");
-
-        Self {
-            capture,
+    pub fn open(sources: &[NamedSource]) -> Result<Self, opencv::Error> {
+        let readers = sources
+            .iter()
+            .map(SourceReader::open)
+            .collect::<Result<Vec<SourceReader>, opencv::Error>>()?;
+
+        Ok(Self {
+            readers,
             frame: Mat::default(),
-            process_chain: vec![],
-        }
+            source_frame: Mat::default(),
+            tap_frame: None,
+            tracker_input: None,
+            pipeline: vec![],
+            stage_names: vec![],
+            tap: None,
+            debug_grid: false,
+            events: vec![],
+            decoded_codes: VecDeque::new(),
+            calibration: None,
+            ptz: Ptz::default(),
+        })
     }
 
     pub fn capture_frame(&mut self) -> Result<(), opencv::Error> {
-        self.capture.read(&mut self.frame).expect("Error: read");
-        if self.frame.empty() {
-            panic!("Error: read");
+        self.source_frame = self.read_sources()?;
+        // キャリブレーション中はパイプラインを通さず、検出結果を描いたソースの画像を配信する
+        if let Some(session) = self.calibration.as_mut() {
+            self.frame = session.detect(&self.source_frame)?;
+            self.tap_frame = None;
+            self.tracker_input = None;
+            return Ok(());
+        }
+        self.frame = self.ptz.apply(&self.source_frame)?;
+        self.process_frame_by_pipeline()?;
+        self.collect_events();
+        Ok(())
+    }
+
+    // イベントにステージ名を付けて溜める。コードの読み取りは履歴にも残す
+    fn collect_events(&mut self) {
+        for (stage, name) in self.pipeline.iter_mut().zip(&self.stage_names) {
+            for mut event in stage.take_events() {
+                if let Some(object) = event.as_object_mut() {
+                    object.insert("stage".to_string(), Value::String(name.clone()));
+                }
+                if event["type"] == "code" {
+                    self.decoded_codes.push_front(event.clone());
+                    self.decoded_codes.truncate(CODE_HISTORY);
+                }
+                self.events.push(event);
+            }
+        }
+        if self.events.len() > MAX_PENDING_EVENTS {
+            let overflow = self.events.len() - MAX_PENDING_EVENTS;
+            self.events.drain(..overflow);
+        }
+    }
+
+    fn read_sources(&mut self) -> Result<Mat, opencv::Error> {
+        let mut frames: Vec<(String, Mat)> = vec![];
+        for reader in self.readers.iter_mut() {
+            frames.push((reader.name.clone(), reader.read()?));
+        }
+        match frames.len() {
+            1 => Ok(frames.remove(0).1),
+            _ => composite::mosaic(&frames),
+        }
+    }
+
+    fn process_frame_by_pipeline(&mut self) -> Result<(), opencv::Error> {
+        // タップ・デバッググリッドが有効な場合のみ各ステージの出力を保持する
+        let record = self.debug_grid || self.tap.is_some();
+        let mut stage_outputs: Vec<(String, Mat)> = vec![];
+        if record {
+            stage_outputs.push((SOURCE_STAGE_NAME.to_string(), self.frame.clone()));
+        }
+        self.tracker_input = None;
+        for (stage, name) in self.pipeline.iter_mut().zip(&self.stage_names) {
+            if name == COLOR_TRACKER_STAGE_NAME {
+                self.tracker_input = Some(self.frame.clone());
+            }
+            self.frame = stage.apply(&self.frame)?;
+            if record {
+                stage_outputs.push((name.clone(), self.frame.clone()));
+            }
+        }
+
+        self.tap_frame = None;
+        if let Some(tap) = &self.tap {
+            if let Some((name, output)) = stage_outputs.iter().find(|(name, _)| *name == tap.node) {
+                match tap.mode {
+                    TapMode::Stream => self.tap_frame = Some(output.clone()),
+                    TapMode::Pip => {
+                        self.frame = composite::picture_in_picture(&self.frame, output, name)?
+                    }
+                }
+            }
+        }
+        if self.debug_grid {
+            self.frame = composite::mosaic(&stage_outputs)?;
         }
-        self.process_frame_by_process_chain()?;
         Ok(())
     }
 
-    fn process_frame_by_process_chain(&mut self) -> Result<(), opencv::Error> {
-        for process in self.process_chain.iter() {
-            match frame_handler::search_frame_handler(&process) {
-                Some(frame_handler) => self.frame = frame_handler(&self.frame)?,
-                None => {}
+    // 形式の変換を挟んだ後のステージ名の一覧を返す
+    pub fn set_process_chain(
+        &mut self,
+        new_process_chain: Vec<ProcessNode>,
+    ) -> Result<Vec<String>, PipelineError> {
+        let pipeline =
+            pipeline::build_pipeline(&new_process_chain, CAMERA_FORMAT, &mut self.pipeline)?;
+        self.stage_names = pipeline::unique_stage_names(&pipeline);
+        self.pipeline = pipeline;
+        // タップしていたステージが新しいパイプラインにない場合はタップを解除する
+        if let Some(tap) = &self.tap {
+            if !self.has_stage(&tap.node) {
+                self.tap = None;
+            }
+        }
+        Ok(self.stage_names.clone())
+    }
+
+    // パイプラインに存在しないノードはタップできない
+    pub fn set_tap(&mut self, tap: Option<Tap>) -> Result<(), PipelineError> {
+        if let Some(tap) = &tap {
+            if !self.has_stage(&tap.node) {
+                return Err(PipelineError::UnknownStage(tap.node.clone()));
             }
         }
+        self.tap = tap;
         Ok(())
     }
 
-    pub fn set_process_chain(&mut self, new_process_chain: Vec<String>) {
-        self.process_chain = new_process_chain;
+    fn has_stage(&self, name: &str) -> bool {
+        name == SOURCE_STAGE_NAME || self.stage_names.iter().any(|stage| stage == name)
+    }
+
+    pub fn set_debug_grid(&mut self, enabled: bool) {
+        self.debug_grid = enabled;
+    }
+
+    // ステージ名ごとの、直前のフレームのメタデータ
+    pub fn take_metadata(&mut self) -> Map<String, Value> {
+        self.pipeline
+            .iter_mut()
+            .zip(&self.stage_names)
+            .filter_map(|(stage, name)| Some((name.clone(), stage.take_metadata()?)))
+            .collect()
+    }
+
+    pub fn take_events(&mut self) -> Vec<Value> {
+        std::mem::take(&mut self.events)
+    }
+
+    // 新しい順にlimit件
+    pub fn decoded_codes(&self, limit: usize) -> Vec<Value> {
+        self.decoded_codes.iter().take(limit).cloned().collect()
+    }
+
+    /*
+     * x, yは表示中の画像に対する相対位置(0.0〜1.0)
+     * 追跡に使われる色と一致するよう、color_trackerに入力されたフレームから取得する
+     * ePTZはパイプラインの前に適用されるため、表示中の画像の位置がそのまま入力の位置になる
+     */
+    pub fn sample_color(&self, x: f64, y: f64) -> Result<ColorSample, opencv::Error> {
+        if !self.has_stage(COLOR_TRACKER_STAGE_NAME) {
+            return Err(opencv::Error::new(
+                StsError,
+                "color sampling requires a color_tracker stage",
+            ));
+        }
+        let frame = self
+            .tracker_input
+            .as_ref()
+            .ok_or_else(|| opencv::Error::new(StsError, "no frame captured yet"))?;
+        color_tracker::sample_color(frame, x, y)
+    }
+
+    pub fn ptz(&mut self) -> &mut Ptz {
+        &mut self.ptz
+    }
+
+    // キャリブレーションはソースが1つの場合のみ行う
+    pub fn start_calibration(
+        &mut self,
+        settings: CalibrationSettings,
+    ) -> Result<CalibrationStatus, opencv::Error> {
+        if self.readers.len() != 1 {
+            return Err(opencv::Error::new(
+                StsError,
+                "calibration requires a single source",
+            ));
+        }
+        let session = CalibrationSession::new(settings)?;
+        let status = session.status();
+        self.calibration = Some(session);
+        Ok(status)
+    }
+
+    pub fn capture_calibration_view(&mut self) -> Result<CalibrationStatus, opencv::Error> {
+        let session = self.calibration_session()?;
+        session.capture()?;
+        Ok(session.status())
+    }
+
+    // 内部パラメータの計算に使うソース名と、溜めた対応点の複製
+    pub fn calibration_views(&mut self) -> Result<(String, CalibrationViews), opencv::Error> {
+        let views = self.calibration_session()?.views();
+        Ok((self.readers[0].name.clone(), views))
+    }
+
+    pub fn cancel_calibration(&mut self) {
+        self.calibration = None;
+    }
+
+    fn calibration_session(&mut self) -> Result<&mut CalibrationSession, opencv::Error> {
+        self.calibration
+            .as_mut()
+            .ok_or_else(|| opencv::Error::new(StsError, "calibration is not started"))
+    }
+
+    // カメラのプロパティは最初のソースに対して取得・設定する
+    pub fn query_properties(&self) -> Result<Value, PropertyError> {
+        let capture = self
+            .readers
+            .first()
+            .and_then(|reader| reader.capture.as_ref())
+            .ok_or(PropertyError::NotConnected)?;
+        Ok(properties::query_capabilities(capture))
+    }
+
+    pub fn set_properties(
+        &mut self,
+        requested: &Map<String, Value>,
+    ) -> Result<Value, PropertyError> {
+        let capture = self
+            .readers
+            .first_mut()
+            .and_then(|reader| reader.capture.as_mut())
+            .ok_or(PropertyError::NotConnected)?;
+        properties::set_properties(capture, requested)
+    }
+
+    pub fn source_status(&self) -> Vec<Value> {
+        self.readers.iter().map(|reader| reader.status()).collect()
+    }
+
+    // 前回の呼び出しから接続状態が変化したソースの状態
+    pub fn take_source_status_changes(&mut self) -> Vec<Value> {
+        self.readers
+            .iter_mut()
+            .filter_map(|reader| reader.take_status_change())
+            .collect()
     }
 }
@@ -0,0 +1,155 @@
+use crate::camera::utils;
+use opencv::core::{Mat, Rect, Scalar, Size, StsBadArg, Vector};
+use opencv::objdetect::{self, CascadeClassifier};
+use opencv::{imgproc, prelude::*};
+
+pub const MODEL_DIR: &str = "model";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CascadeFlag {
+    DoCannyPruning,
+    ScaleImage,
+    FindBiggestObject,
+    DoRoughSearch,
+}
+
+impl CascadeFlag {
+    fn value(&self) -> i32 {
+        match self {
+            CascadeFlag::DoCannyPruning => objdetect::CASCADE_DO_CANNY_PRUNING,
+            CascadeFlag::ScaleImage => objdetect::CASCADE_SCALE_IMAGE,
+            CascadeFlag::FindBiggestObject => objdetect::CASCADE_FIND_BIGGEST_OBJECT,
+            CascadeFlag::DoRoughSearch => objdetect::CASCADE_DO_ROUGH_SEARCH,
+        }
+    }
+}
+
+/*
+* {"model": "haarcascade_smile.xml", "scale_factor": 1.2, "min_neighbors": 5,
+*  "flags": ["scale_image"], "min_size": [30, 30], "max_size": [0, 0], "color": [0, 255, 0]}
+* max_sizeが[0, 0]の場合は上限なし
+*/
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct CascadeParams {
+    pub model: String,
+    pub scale_factor: f64,
+    pub min_neighbors: i32,
+    pub flags: Vec<CascadeFlag>,
+    pub min_size: [i32; 2],
+    pub max_size: [i32; 2],
+    pub color: [f64; 3], // BGR
+}
+
+impl Default for CascadeParams {
+    fn default() -> Self {
+        Self {
+            model: "haarcascade_frontalface_default.xml".to_string(),
+            scale_factor: 1.1,
+            min_neighbors: 3,
+            flags: vec![],
+            min_size: [30, 30],
+            max_size: [0, 0],
+            color: [0.0, 255.0, 0.0],
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, serde::Serialize)]
+pub struct Detection {
+    pub x: i32,
+    pub y: i32,
+    pub width: i32,
+    pub height: i32,
+}
+
+impl From<Rect> for Detection {
+    fn from(rect: Rect) -> Self {
+        Self {
+            x: rect.x,
+            y: rect.y,
+            width: rect.width,
+            height: rect.height,
+        }
+    }
+}
+
+impl From<Detection> for Rect {
+    fn from(detection: Detection) -> Self {
+        Rect::new(detection.x, detection.y, detection.width, detection.height)
+    }
+}
+
+// 読み込んだ分類器をノードの状態として保持し、モデルが変わった場合のみ読み直す
+#[derive(Default)]
+pub struct CascadeCache {
+    model: String,
+    classifier: Option<CascadeClassifier>,
+}
+
+impl CascadeCache {
+    pub fn get(&mut self, model: &str) -> Result<&mut CascadeClassifier, opencv::Error> {
+        if self.classifier.is_none() || self.model != model {
+            self.classifier = Some(CascadeClassifier::new(&model_path(model)?)?);
+            self.model = model.to_string();
+        }
+        Ok(self.classifier.as_mut().unwrap())
+    }
+}
+
+// モデルディレクトリの外のファイルは読み込まない
+pub fn model_path(model: &str) -> Result<String, opencv::Error> {
+    if model.contains('/') || model.contains('\\') || model.starts_with('.') {
+        let message = format!("invalid model name: {}", model);
+        return Err(opencv::Error::new(StsBadArg, message));
+    }
+    Ok(format!("{}/{}", MODEL_DIR, model))
+}
+
+// モデルディレクトリ内のカスケード(*.xml)の一覧
+pub fn list_models() -> Vec<String> {
+    let Ok(entries) = std::fs::read_dir(MODEL_DIR) else {
+        return vec![];
+    };
+    let mut models: Vec<String> = entries
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.file_name().to_string_lossy().to_string())
+        .filter(|name| name.ends_with(".xml"))
+        .collect();
+    models.sort();
+    models
+}
+
+pub fn detect(
+    frame: &Mat,
+    classifier: &mut CascadeClassifier,
+    params: &CascadeParams,
+) -> Result<Vec<Rect>, opencv::Error> {
+    let gray_frame = utils::to_gray(frame)?;
+    let flags = params
+        .flags
+        .iter()
+        .fold(0, |flags, flag| flags | flag.value());
+
+    let mut objects = Vector::<Rect>::new();
+    classifier.detect_multi_scale(
+        &gray_frame,
+        &mut objects,
+        params.scale_factor,
+        params.min_neighbors,
+        flags,
+        Size::new(params.min_size[0], params.min_size[1]),
+        Size::new(params.max_size[0], params.max_size[1]),
+    )?;
+    Ok(objects.to_vec())
+}
+
+pub fn draw_detections(frame: &Mat, rects: &[Rect], color: [f64; 3]) -> Result<Mat, opencv::Error> {
+    let mut detected_frame = frame.clone();
+    let color = Scalar::new(color[0], color[1], color[2], 0.0);
+    for rect in rects.iter() {
+        imgproc::rectangle(&mut detected_frame, *rect, color, 2, imgproc::LINE_8, 0)?;
+    }
+    Ok(detected_frame)
+}
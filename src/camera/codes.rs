@@ -0,0 +1,285 @@
+use opencv::core::{Mat, Point, Point2f, Scalar, StsBadArg, Vector};
+use opencv::objdetect::{BarcodeDetector, QRCodeDetector};
+use opencv::{imgproc, prelude::*};
+use std::collections::HashMap;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+// QRCodeDetectorが返す種類名
+const QR_CODE_TYPE: &str = "QR_CODE";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Symbology {
+    Qr,
+    Barcode, // EAN-8, EAN-13, UPC-A, UPC-E
+}
+
+/*
+* {"symbologies": ["qr", "barcode"], "dedup_seconds": 5.0, "color": [0, 255, 0], "label": true}
+* dedup_seconds -> 同じコードを読み続けている間、または見えなくなってからこの秒数以内はイベントを送らない
+*                  0未満は0として扱う
+*/
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct CodeReaderParams {
+    pub symbologies: Vec<Symbology>,
+    pub dedup_seconds: f64,
+    pub color: [f64; 3], // BGR
+    pub label: bool,
+}
+
+impl Default for CodeReaderParams {
+    fn default() -> Self {
+        Self {
+            symbologies: vec![Symbology::Qr, Symbology::Barcode],
+            dedup_seconds: 5.0,
+            color: [0.0, 255.0, 0.0],
+            label: true,
+        }
+    }
+}
+
+impl CodeReaderParams {
+    // Durationで表せない大きさの秒数はエラーにする
+    pub fn dedup_window(&self) -> Result<Duration, opencv::Error> {
+        Duration::try_from_secs_f64(self.dedup_seconds.max(0.0)).map_err(|_| {
+            let message = format!("invalid dedup_seconds: {}", self.dedup_seconds);
+            opencv::Error::new(StsBadArg, message)
+        })
+    }
+}
+
+// cornersはコードを囲む4点
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct DecodedCode {
+    pub kind: String,
+    pub data: String,
+    pub corners: [[f32; 2]; 4],
+}
+
+// 読み取ったコードをクライアントに送るイベント。timestampはUNIX時間(ミリ秒)
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct CodeEvent<'a> {
+    #[serde(rename = "type")]
+    pub event_type: &'static str,
+    pub timestamp: u128,
+    #[serde(flatten)]
+    pub code: &'a DecodedCode,
+}
+
+// 検出器と、コードごとの最後に読み取った時刻をノードの状態として保持する
+#[derive(Default)]
+pub struct CodeReaderState {
+    qr: Option<QRCodeDetector>,
+    barcode: Option<BarcodeDetector>,
+    last_seen: HashMap<(String, String), Instant>,
+}
+
+impl CodeReaderState {
+    fn qr(&mut self) -> Result<&QRCodeDetector, opencv::Error> {
+        if self.qr.is_none() {
+            self.qr = Some(QRCodeDetector::default()?);
+        }
+        Ok(self.qr.as_ref().unwrap())
+    }
+
+    fn barcode(&mut self) -> Result<&BarcodeDetector, opencv::Error> {
+        if self.barcode.is_none() {
+            self.barcode = Some(BarcodeDetector::default()?);
+        }
+        Ok(self.barcode.as_ref().unwrap())
+    }
+
+    // 重複除去の期間内に読み取っていないコードのみ返す
+    pub fn filter_new<'a>(
+        &mut self,
+        codes: &'a [DecodedCode],
+        window: Duration,
+    ) -> Vec<&'a DecodedCode> {
+        let now = Instant::now();
+        self.last_seen
+            .retain(|_, seen| now.duration_since(*seen) <= window);
+        let mut new_codes = vec![];
+        for code in codes.iter() {
+            let key = (code.kind.clone(), code.data.clone());
+            if self.last_seen.insert(key, now).is_none() {
+                new_codes.push(code);
+            }
+        }
+        new_codes
+    }
+}
+
+pub fn read_codes(
+    frame: &Mat,
+    state: &mut CodeReaderState,
+    params: &CodeReaderParams,
+) -> Result<Vec<DecodedCode>, opencv::Error> {
+    let mut codes = vec![];
+    for symbology in params.symbologies.iter() {
+        let mut decoded_info = Vector::<String>::new();
+        let mut decoded_type = Vector::<String>::new();
+        let mut points = Vector::<Point2f>::new();
+        match symbology {
+            Symbology::Qr => {
+                state.qr()?.detect_and_decode_multi(
+                    frame,
+                    &mut decoded_info,
+                    &mut points,
+                    &mut opencv::core::no_array(),
+                )?;
+            }
+            Symbology::Barcode => {
+                state.barcode()?.detect_and_decode_with_type(
+                    frame,
+                    &mut decoded_info,
+                    &mut decoded_type,
+                    &mut points,
+                )?;
+            }
+        }
+        // 1つのコードにつき4点。検出できても読み取れなかったものは空文字列になる
+        let corners: Vec<Point2f> = points.to_vec();
+        for (index, (data, quad)) in decoded_info.iter().zip(corners.chunks(4)).enumerate() {
+            if data.is_empty() || quad.len() != 4 {
+                continue;
+            }
+            let kind = match symbology {
+                Symbology::Qr => QR_CODE_TYPE.to_string(),
+                Symbology::Barcode => decoded_type.get(index).unwrap_or_default(),
+            };
+            codes.push(DecodedCode {
+                kind,
+                data,
+                corners: [0, 1, 2, 3].map(|i| [quad[i].x, quad[i].y]),
+            });
+        }
+    }
+    Ok(codes)
+}
+
+pub fn code_event(code: &DecodedCode) -> CodeEvent<'_> {
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_millis())
+        .unwrap_or_default();
+    CodeEvent {
+        event_type: "code",
+        timestamp,
+        code,
+    }
+}
+
+pub fn draw_codes(
+    frame: &Mat,
+    codes: &[DecodedCode],
+    params: &CodeReaderParams,
+) -> Result<Mat, opencv::Error> {
+    let mut result = frame.clone();
+    let color = Scalar::new(params.color[0], params.color[1], params.color[2], 0.0);
+    for code in codes.iter() {
+        let quad: Vector<Point> = code
+            .corners
+            .iter()
+            .map(|[x, y]| Point::new(*x as i32, *y as i32))
+            .collect();
+        let mut polygons = Vector::<Vector<Point>>::new();
+        polygons.push(quad);
+        imgproc::polylines(&mut result, &polygons, true, color, 2, imgproc::LINE_AA, 0)?;
+        if params.label {
+            let [x, y] = code.corners[0];
+            imgproc::put_text(
+                &mut result,
+                &code.data,
+                Point::new(x as i32, y as i32 - 5),
+                imgproc::FONT_HERSHEY_SIMPLEX,
+                0.5,
+                color,
+                1,
+                imgproc::LINE_AA,
+                false,
+            )?;
+        }
+    }
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const WINDOW: Duration = Duration::from_secs(5);
+
+    fn code(kind: &str, data: &str) -> DecodedCode {
+        DecodedCode {
+            kind: kind.to_string(),
+            data: data.to_string(),
+            corners: [[0.0, 0.0]; 4],
+        }
+    }
+
+    fn new_data(
+        state: &mut CodeReaderState,
+        codes: &[DecodedCode],
+        window: Duration,
+    ) -> Vec<String> {
+        state
+            .filter_new(codes, window)
+            .iter()
+            .map(|code| code.data.clone())
+            .collect()
+    }
+
+    #[test]
+    fn codes_seen_within_the_window_are_filtered() {
+        let mut state = CodeReaderState::default();
+        let codes = [code(QR_CODE_TYPE, "a"), code(QR_CODE_TYPE, "b")];
+        assert_eq!(new_data(&mut state, &codes, WINDOW), ["a", "b"]);
+        assert!(new_data(&mut state, &codes, WINDOW).is_empty());
+
+        let codes = [code(QR_CODE_TYPE, "b"), code(QR_CODE_TYPE, "c")];
+        assert_eq!(new_data(&mut state, &codes, WINDOW), ["c"]);
+    }
+
+    #[test]
+    fn same_data_with_different_kind_is_new() {
+        let mut state = CodeReaderState::default();
+        assert_eq!(
+            new_data(&mut state, &[code(QR_CODE_TYPE, "123")], WINDOW),
+            ["123"]
+        );
+        assert_eq!(
+            new_data(&mut state, &[code("EAN_13", "123")], WINDOW),
+            ["123"]
+        );
+    }
+
+    #[test]
+    fn duplicates_in_one_frame_are_reported_once() {
+        let mut state = CodeReaderState::default();
+        let codes = [code(QR_CODE_TYPE, "a"), code(QR_CODE_TYPE, "a")];
+        assert_eq!(new_data(&mut state, &codes, WINDOW), ["a"]);
+    }
+
+    #[test]
+    fn codes_are_reported_again_after_the_window() {
+        let mut state = CodeReaderState::default();
+        let codes = [code(QR_CODE_TYPE, "a")];
+        let window = Duration::from_millis(1);
+        assert_eq!(new_data(&mut state, &codes, window), ["a"]);
+        std::thread::sleep(Duration::from_millis(5));
+        assert_eq!(new_data(&mut state, &codes, window), ["a"]);
+    }
+
+    #[test]
+    fn dedup_window_rejects_unrepresentable_seconds() {
+        let params = |dedup_seconds: f64| CodeReaderParams {
+            dedup_seconds,
+            ..Default::default()
+        };
+        assert_eq!(params(5.0).dedup_window().unwrap(), WINDOW);
+        assert_eq!(params(-1.0).dedup_window().unwrap(), Duration::ZERO);
+        assert!(params(1e20).dedup_window().is_err());
+        assert!(params(f64::INFINITY).dedup_window().is_err());
+    }
+}
@@ -0,0 +1,281 @@
+use crate::camera::composite;
+use opencv::core::{merge, split, Mat, StsBadArg, Vector};
+use opencv::{imgproc, prelude::*};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ColorSpace {
+    Bgr,
+    Rgb,
+    Hsv,
+    Hls,
+    Lab,
+    Ycrcb,
+    Xyz,
+}
+
+impl ColorSpace {
+    // HSV, HLSは色相を0〜255に割り当てる(FULL)
+    fn code(&self) -> Option<i32> {
+        match self {
+            ColorSpace::Bgr => None,
+            ColorSpace::Rgb => Some(imgproc::COLOR_BGR2RGB),
+            ColorSpace::Hsv => Some(imgproc::COLOR_BGR2HSV_FULL),
+            ColorSpace::Hls => Some(imgproc::COLOR_BGR2HLS_FULL),
+            ColorSpace::Lab => Some(imgproc::COLOR_BGR2Lab),
+            ColorSpace::Ycrcb => Some(imgproc::COLOR_BGR2YCrCb),
+            ColorSpace::Xyz => Some(imgproc::COLOR_BGR2XYZ),
+        }
+    }
+
+    pub fn channel_names(&self) -> [&'static str; 3] {
+        match self {
+            ColorSpace::Bgr => ["B", "G", "R"],
+            ColorSpace::Rgb => ["R", "G", "B"],
+            ColorSpace::Hsv => ["H", "S", "V"],
+            ColorSpace::Hls => ["H", "L", "S"],
+            ColorSpace::Lab => ["L", "a", "b"],
+            ColorSpace::Ycrcb => ["Y", "Cr", "Cb"],
+            ColorSpace::Xyz => ["X", "Y", "Z"],
+        }
+    }
+
+    pub fn convert(&self, frame: &Mat) -> Result<Mat, opencv::Error> {
+        let Some(code) = self.code() else {
+            return Ok(frame.clone());
+        };
+        let mut converted = Mat::default();
+        imgproc::cvt_color(frame, &mut converted, code, 0)?;
+        Ok(converted)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Colormap {
+    Gray,
+    Jet,
+    Turbo,
+    Hot,
+    Viridis,
+}
+
+impl Colormap {
+    // 1チャンネルの画像を疑似カラーで表示する
+    pub fn apply(&self, channel: &Mat) -> Result<Mat, opencv::Error> {
+        let mut result = Mat::default();
+        let colormap = match self {
+            Colormap::Gray => return composite::to_bgr(channel),
+            Colormap::Jet => imgproc::COLORMAP_JET,
+            Colormap::Turbo => imgproc::COLORMAP_TURBO,
+            Colormap::Hot => imgproc::COLORMAP_HOT,
+            Colormap::Viridis => imgproc::COLORMAP_VIRIDIS,
+        };
+        imgproc::apply_color_map(channel, &mut result, colormap)?;
+        Ok(result)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ColorSpaceView {
+    Converted, // 変換後の3チャンネルをそのままBGRとして表示
+    Channels,  // 各チャンネルをラベル付きで並べる
+    Channel,   // channelで指定した1チャンネルを疑似カラーで表示
+}
+
+/*
+* {"space": "hsv", "view": "channel", "channel": 0, "colormap": "jet"}
+* channelは変換後のチャンネル番号(HSVの場合 0: H, 1: S, 2: V)
+*/
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct ColorSpaceParams {
+    pub space: ColorSpace,
+    pub view: ColorSpaceView,
+    pub channel: usize,
+    pub colormap: Colormap,
+}
+
+impl Default for ColorSpaceParams {
+    fn default() -> Self {
+        Self {
+            space: ColorSpace::Hsv,
+            view: ColorSpaceView::Channels,
+            channel: 0,
+            colormap: Colormap::Jet,
+        }
+    }
+}
+
+pub fn view_color_space(frame: &Mat, params: &ColorSpaceParams) -> Result<Mat, opencv::Error> {
+    let converted = params.space.convert(frame)?;
+    if params.view == ColorSpaceView::Converted {
+        return Ok(converted);
+    }
+
+    let mut channels: Vector<Mat> = Vector::new();
+    split(&converted, &mut channels)?;
+    let names = params.space.channel_names();
+    match params.view {
+        ColorSpaceView::Channels => {
+            let labeled_frames: Vec<(String, Mat)> = names
+                .iter()
+                .zip(channels.iter())
+                .map(|(name, channel)| (name.to_string(), channel))
+                .collect();
+            composite::mosaic(&labeled_frames)
+        }
+        _ => {
+            if params.channel >= names.len() {
+                let message = format!("invalid channel: {}", params.channel);
+                return Err(opencv::Error::new(StsBadArg, message));
+            }
+            params.colormap.apply(&channels.get(params.channel)?)
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Channel {
+    Blue,
+    Green,
+    Red,
+}
+
+impl Channel {
+    fn index(&self) -> usize {
+        match self {
+            Channel::Blue => 0,
+            Channel::Green => 1,
+            Channel::Red => 2,
+        }
+    }
+}
+
+/*
+* BGR画像のチャンネル操作。gain, offsetは[B, G, R]の順
+* {"order": ["red", "green", "blue"], "gain": [1.0, 1.2, 1.0], "offset": [0, 0, -10], "remove": ["red"]}
+* order  -> 出力の各チャンネルに入れる入力チャンネル(上の例はRとBの入れ替え)
+* remove -> 0にする入力チャンネル
+* 処理の順番は gain, offset -> remove -> order
+*/
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct ChannelParams {
+    pub order: [Channel; 3],
+    pub gain: [f64; 3],
+    pub offset: [f64; 3],
+    pub remove: Vec<Channel>,
+}
+
+impl Default for ChannelParams {
+    fn default() -> Self {
+        Self {
+            order: [Channel::Blue, Channel::Green, Channel::Red],
+            gain: [1.0, 1.0, 1.0],
+            offset: [0.0, 0.0, 0.0],
+            remove: vec![],
+        }
+    }
+}
+
+pub fn apply_channel_operations(frame: &Mat, params: &ChannelParams) -> Result<Mat, opencv::Error> {
+    let mut channels: Vector<Mat> = Vector::new();
+    split(frame, &mut channels)?;
+
+    let mut adjusted: Vec<Mat> = vec![];
+    for (index, channel) in channels.iter().enumerate() {
+        let mut result = Mat::default();
+        if params.remove.iter().any(|removed| removed.index() == index) {
+            channel.convert_to(&mut result, -1, 0.0, 0.0)?;
+        } else {
+            // 8bitの範囲を超えた値は飽和させる
+            channel.convert_to(&mut result, -1, params.gain[index], params.offset[index])?;
+        }
+        adjusted.push(result);
+    }
+
+    let ordered: Vector<Mat> = params
+        .order
+        .iter()
+        .map(|channel| adjusted[channel.index()].clone())
+        .collect();
+    let mut result = Mat::default();
+    merge(&ordered, &mut result)?;
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use opencv::core::{Scalar, Vec3b, CV_8UC3};
+
+    fn bgr_pixel(b: f64, g: f64, r: f64) -> Mat {
+        Mat::new_rows_cols_with_default(1, 1, CV_8UC3, Scalar::new(b, g, r, 0.0)).unwrap()
+    }
+
+    fn pixel_of(frame: &Mat) -> [u8; 3] {
+        frame.at_2d::<Vec3b>(0, 0).unwrap().0
+    }
+
+    #[test]
+    fn default_channel_params_keep_the_frame() {
+        let frame = bgr_pixel(10.0, 20.0, 30.0);
+        let result = apply_channel_operations(&frame, &ChannelParams::default()).unwrap();
+        assert_eq!(pixel_of(&result), [10, 20, 30]);
+    }
+
+    #[test]
+    fn order_swaps_channels() {
+        let params = ChannelParams {
+            order: [Channel::Red, Channel::Green, Channel::Blue],
+            ..Default::default()
+        };
+        let result = apply_channel_operations(&bgr_pixel(10.0, 20.0, 30.0), &params).unwrap();
+        assert_eq!(pixel_of(&result), [30, 20, 10]);
+    }
+
+    #[test]
+    fn gain_and_offset_saturate() {
+        let params = ChannelParams {
+            gain: [2.0, 1.0, 1.0],
+            offset: [0.0, 50.0, -10.0],
+            ..Default::default()
+        };
+        let result = apply_channel_operations(&bgr_pixel(200.0, 220.0, 5.0), &params).unwrap();
+        assert_eq!(pixel_of(&result), [255, 255, 0]);
+    }
+
+    #[test]
+    fn removed_channels_are_zeroed_before_reordering() {
+        let params = ChannelParams {
+            order: [Channel::Red, Channel::Red, Channel::Blue],
+            gain: [1.0, 1.0, 2.0],
+            remove: vec![Channel::Red],
+            ..Default::default()
+        };
+        let result = apply_channel_operations(&bgr_pixel(10.0, 20.0, 30.0), &params).unwrap();
+        assert_eq!(pixel_of(&result), [0, 0, 10]);
+    }
+
+    #[test]
+    fn channel_view_rejects_out_of_range_channel() {
+        let params = ColorSpaceParams {
+            view: ColorSpaceView::Channel,
+            channel: 3,
+            ..Default::default()
+        };
+        assert!(view_color_space(&bgr_pixel(10.0, 20.0, 30.0), &params).is_err());
+    }
+
+    #[test]
+    fn rgb_conversion_reverses_channels() {
+        let converted = ColorSpace::Rgb
+            .convert(&bgr_pixel(10.0, 20.0, 30.0))
+            .unwrap();
+        assert_eq!(pixel_of(&converted), [30, 20, 10]);
+        assert_eq!(ColorSpace::Hsv.channel_names(), ["H", "S", "V"]);
+    }
+}
@@ -0,0 +1,309 @@
+use crate::camera::cascade::Detection;
+use crate::camera::filters::KernelShape;
+use opencv::core::{self, Mat, Point, Rect, Scalar, Vec3b, Vector, CV_8UC1, CV_8UC3};
+use opencv::{imgproc, prelude::*};
+use std::collections::VecDeque;
+
+// OpenCVの8bitのHSVは H: 0〜180, S: 0〜255, V: 0〜255
+const HUE_MAX: f64 = 180.0;
+// クリックした色から範囲を作る際の許容幅
+const HUE_TOLERANCE: f64 = 10.0;
+const SATURATION_TOLERANCE: f64 = 60.0;
+const VALUE_TOLERANCE: f64 = 60.0;
+// クリックした位置の周囲この大きさの平均色を取る
+const SAMPLE_SIZE: i32 = 5;
+
+#[derive(Debug, Clone, Copy, serde::Serialize, serde::Deserialize)]
+pub struct HsvRange {
+    pub lower: [f64; 3],
+    pub upper: [f64; 3],
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TrackerOutput {
+    Overlay, // 元画像に枠・重心・軌跡を描く
+    Mask,    // 色の範囲に入った画素の白黒画像
+}
+
+/*
+* {"ranges": [{"lower": [0, 120, 70], "upper": [10, 255, 255]},
+*             {"lower": [170, 120, 70], "upper": [180, 255, 255]}],
+*  "open_size": 5, "close_size": 5, "min_area": 200, "max_blobs": 3, "trail": 32}
+* 赤のように色相が0と180をまたぐ色は範囲を2つに分けて指定する
+* open_size, close_size -> マスクのノイズ除去・穴埋めの大きさ(0の場合は行わない)
+* trail -> 最も大きい領域の重心の軌跡を残すフレーム数(0の場合は描かない)
+*/
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct ColorTrackerParams {
+    pub ranges: Vec<HsvRange>,
+    pub open_size: i32,
+    pub close_size: i32,
+    pub min_area: f64,
+    pub max_blobs: usize,
+    pub trail: usize,
+    pub output: TrackerOutput,
+    pub color: [f64; 3], // BGR
+}
+
+impl Default for ColorTrackerParams {
+    fn default() -> Self {
+        Self {
+            ranges: vec![
+                HsvRange {
+                    lower: [0.0, 120.0, 70.0],
+                    upper: [10.0, 255.0, 255.0],
+                },
+                HsvRange {
+                    lower: [170.0, 120.0, 70.0],
+                    upper: [180.0, 255.0, 255.0],
+                },
+            ],
+            open_size: 5,
+            close_size: 5,
+            min_area: 200.0,
+            max_blobs: 3,
+            trail: 32,
+            output: TrackerOutput::Overlay,
+            color: [0.0, 255.0, 0.0],
+        }
+    }
+}
+
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct Blob {
+    pub centroid: [f64; 2],
+    pub area: f64,
+    pub bbox: Detection,
+}
+
+// 最も大きい領域の重心の軌跡をノードの状態として保持する
+#[derive(Default)]
+pub struct TrackerState {
+    trail: VecDeque<Point>,
+}
+
+impl TrackerState {
+    // 見失った間は古い点から消していく
+    pub fn update(&mut self, blobs: &[Blob], length: usize) {
+        match blobs.first() {
+            Some(blob) => self
+                .trail
+                .push_back(Point::new(blob.centroid[0] as i32, blob.centroid[1] as i32)),
+            None => {
+                self.trail.pop_front();
+            }
+        }
+        while self.trail.len() > length {
+            self.trail.pop_front();
+        }
+    }
+}
+
+pub fn create_mask(frame: &Mat, params: &ColorTrackerParams) -> Result<Mat, opencv::Error> {
+    let mut hsv_frame = Mat::default();
+    imgproc::cvt_color(frame, &mut hsv_frame, imgproc::COLOR_BGR2HSV, 0)?;
+
+    let mut mask = Mat::new_size_with_default(frame.size()?, CV_8UC1, Scalar::all(0.0))?;
+    for range in params.ranges.iter() {
+        let lower = Scalar::new(range.lower[0], range.lower[1], range.lower[2], 0.0);
+        let upper = Scalar::new(range.upper[0], range.upper[1], range.upper[2], 0.0);
+        let mut range_mask = Mat::default();
+        core::in_range(&hsv_frame, &lower, &upper, &mut range_mask)?;
+        let mut combined = Mat::default();
+        core::bitwise_or(&mask, &range_mask, &mut combined, &core::no_array())?;
+        mask = combined;
+    }
+
+    // 小さなノイズを消してから、領域内の穴を埋める
+    for (op, size) in [
+        (imgproc::MORPH_OPEN, params.open_size),
+        (imgproc::MORPH_CLOSE, params.close_size),
+    ] {
+        if size <= 0 {
+            continue;
+        }
+        let kernel = KernelShape::Ellipse.structuring_element(size)?;
+        let mut cleaned = Mat::default();
+        imgproc::morphology_ex_def(&mask, &mut cleaned, op, &kernel)?;
+        mask = cleaned;
+    }
+    Ok(mask)
+}
+
+// 面積が大きい順にmax_blobs個の領域
+pub fn find_blobs(mask: &Mat, params: &ColorTrackerParams) -> Result<Vec<Blob>, opencv::Error> {
+    let mut contours = Vector::<Vector<Point>>::new();
+    imgproc::find_contours(
+        mask,
+        &mut contours,
+        imgproc::RETR_EXTERNAL,
+        imgproc::CHAIN_APPROX_SIMPLE,
+        Point::new(0, 0),
+    )?;
+
+    let mut blobs = vec![];
+    for contour in contours.iter() {
+        let area = imgproc::contour_area(&contour, false)?;
+        if area < params.min_area {
+            continue;
+        }
+        let moments = imgproc::moments(&contour, false)?;
+        if moments.m00 == 0.0 {
+            continue;
+        }
+        blobs.push(Blob {
+            centroid: [moments.m10 / moments.m00, moments.m01 / moments.m00],
+            area,
+            bbox: Detection::from(imgproc::bounding_rect(&contour)?),
+        });
+    }
+    blobs.sort_by(|a, b| b.area.total_cmp(&a.area));
+    blobs.truncate(params.max_blobs);
+    Ok(blobs)
+}
+
+pub fn draw_blobs(
+    frame: &Mat,
+    blobs: &[Blob],
+    state: &TrackerState,
+    params: &ColorTrackerParams,
+) -> Result<Mat, opencv::Error> {
+    let mut result = frame.clone();
+    let color = Scalar::new(params.color[0], params.color[1], params.color[2], 0.0);
+    for blob in blobs.iter() {
+        let center = Point::new(blob.centroid[0] as i32, blob.centroid[1] as i32);
+        imgproc::rectangle(&mut result, blob.bbox.into(), color, 2, imgproc::LINE_8, 0)?;
+        imgproc::circle(&mut result, center, 4, color, -1, imgproc::LINE_AA, 0)?;
+    }
+    if state.trail.len() > 1 {
+        let trail: Vector<Point> = state.trail.iter().copied().collect();
+        let mut trails = Vector::<Vector<Point>>::new();
+        trails.push(trail);
+        imgproc::polylines(&mut result, &trails, false, color, 2, imgproc::LINE_AA, 0)?;
+    }
+    Ok(result)
+}
+
+/*
+* クリックした位置の色と、その色を追跡するためのHSVの範囲
+* 引数のx, yは画像に対する相対位置(0.0〜1.0)、結果のx, yは画素の位置
+* {"x": 320, "y": 240, "bgr": [40, 30, 200], "hsv": [178, 217, 200],
+*  "ranges": [{"lower": [168, 157, 140], "upper": [180, 255, 255]}, {"lower": [0, 157, 140], "upper": [8, 255, 255]}]}
+*/
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct ColorSample {
+    pub x: i32,
+    pub y: i32,
+    pub bgr: [u8; 3],
+    pub hsv: [u8; 3],
+    pub ranges: Vec<HsvRange>,
+}
+
+pub fn sample_color(frame: &Mat, x: f64, y: f64) -> Result<ColorSample, opencv::Error> {
+    let col = ((x.clamp(0.0, 1.0) * frame.cols() as f64) as i32).min(frame.cols() - 1);
+    let row = ((y.clamp(0.0, 1.0) * frame.rows() as f64) as i32).min(frame.rows() - 1);
+    let area = Rect::new(
+        col - SAMPLE_SIZE / 2,
+        row - SAMPLE_SIZE / 2,
+        SAMPLE_SIZE,
+        SAMPLE_SIZE,
+    ) & Rect::new(0, 0, frame.cols(), frame.rows());
+    let mean = core::mean(&Mat::roi(frame, area)?, &core::no_array())?;
+
+    let pixel = Mat::new_rows_cols_with_default(1, 1, CV_8UC3, mean)?;
+    let mut hsv_pixel = Mat::default();
+    imgproc::cvt_color(&pixel, &mut hsv_pixel, imgproc::COLOR_BGR2HSV, 0)?;
+    let bgr = *pixel.at_2d::<Vec3b>(0, 0)?;
+    let hsv = *hsv_pixel.at_2d::<Vec3b>(0, 0)?;
+    Ok(ColorSample {
+        x: col,
+        y: row,
+        bgr: bgr.0,
+        hsv: hsv.0,
+        ranges: suggest_ranges(hsv.0),
+    })
+}
+
+// 色相が0と180をまたぐ場合は範囲を2つに分ける
+fn suggest_ranges(hsv: [u8; 3]) -> Vec<HsvRange> {
+    let [hue, saturation, value] = hsv.map(|channel| channel as f64);
+    let lower_s = (saturation - SATURATION_TOLERANCE).max(0.0);
+    let lower_v = (value - VALUE_TOLERANCE).max(0.0);
+    let range = |lower_h: f64, upper_h: f64| HsvRange {
+        lower: [lower_h, lower_s, lower_v],
+        upper: [upper_h, 255.0, 255.0],
+    };
+
+    let (lower_h, upper_h) = (hue - HUE_TOLERANCE, hue + HUE_TOLERANCE);
+    if lower_h < 0.0 {
+        vec![range(0.0, upper_h), range(HUE_MAX + lower_h, HUE_MAX)]
+    } else if upper_h > HUE_MAX {
+        vec![range(lower_h, HUE_MAX), range(0.0, upper_h - HUE_MAX)]
+    } else {
+        vec![range(lower_h, upper_h)]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hue_ranges(hsv: [u8; 3]) -> Vec<(f64, f64)> {
+        suggest_ranges(hsv)
+            .iter()
+            .map(|range| (range.lower[0], range.upper[0]))
+            .collect()
+    }
+
+    #[test]
+    fn hue_inside_the_circle_gives_one_range() {
+        assert_eq!(hue_ranges([60, 200, 200]), [(50.0, 70.0)]);
+    }
+
+    #[test]
+    fn hue_near_zero_wraps_to_the_top() {
+        assert_eq!(hue_ranges([3, 200, 200]), [(0.0, 13.0), (173.0, 180.0)]);
+    }
+
+    #[test]
+    fn hue_near_the_top_wraps_to_zero() {
+        assert_eq!(hue_ranges([178, 200, 200]), [(168.0, 180.0), (0.0, 8.0)]);
+    }
+
+    #[test]
+    fn saturation_and_value_lower_bounds_are_clamped() {
+        let ranges = suggest_ranges([60, 30, 200]);
+        assert_eq!(ranges[0].lower, [50.0, 0.0, 140.0]);
+        assert_eq!(ranges[0].upper, [70.0, 255.0, 255.0]);
+    }
+
+    #[test]
+    fn sampled_pixel_is_converted_to_hsv() {
+        let frame =
+            Mat::new_rows_cols_with_default(10, 10, CV_8UC3, Scalar::new(0.0, 0.0, 255.0, 0.0))
+                .unwrap();
+        let sample = sample_color(&frame, 1.0, 1.0).unwrap();
+        assert_eq!((sample.x, sample.y), (9, 9));
+        assert_eq!(sample.bgr, [0, 0, 255]);
+        assert_eq!(sample.hsv, [0, 255, 255]);
+        assert_eq!(sample.ranges.len(), 2);
+    }
+
+    #[test]
+    fn trail_drops_old_points_when_the_blob_is_lost() {
+        let blob = Blob {
+            centroid: [1.0, 2.0],
+            area: 10.0,
+            bbox: Detection::from(Rect::new(0, 0, 2, 4)),
+        };
+        let mut state = TrackerState::default();
+        for _ in 0..5 {
+            state.update(std::slice::from_ref(&blob), 3);
+        }
+        assert_eq!(state.trail.len(), 3);
+        state.update(&[], 3);
+        assert_eq!(state.trail.len(), 2);
+    }
+}
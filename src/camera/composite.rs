@@ -0,0 +1,83 @@
+use crate::camera::pixel_format::{self, PixelFormat};
+use opencv::core::{Mat, Point, Rect, Scalar, Size, CV_8UC3};
+use opencv::{imgproc, prelude::*};
+
+const TILE_WIDTH: i32 = 320;
+const TILE_HEIGHT: i32 = 240;
+
+// 任意の形式のframeを合成用のBGR8に揃える
+pub fn to_bgr(frame: &Mat) -> Result<Mat, opencv::Error> {
+    let format = PixelFormat::of(frame).unwrap_or(PixelFormat::Bgr8);
+    pixel_format::convert(frame, format, PixelFormat::Bgr8)
+}
+
+/*
+* 各ステージの出力をラベル付きのタイルにして格子状に並べる
+* 4枚 -> 2x2, 5枚 -> 3x2
+*/
+pub fn mosaic(labeled_frames: &[(String, Mat)]) -> Result<Mat, opencv::Error> {
+    let count = labeled_frames.len().max(1) as i32;
+    let cols = (count as f64).sqrt().ceil() as i32;
+    let rows = (count + cols - 1) / cols;
+    let mut mosaic_frame = Mat::new_rows_cols_with_default(
+        TILE_HEIGHT * rows,
+        TILE_WIDTH * cols,
+        CV_8UC3,
+        Scalar::all(0.0),
+    )?;
+
+    for (i, (label, frame)) in labeled_frames.iter().enumerate() {
+        let i = i as i32;
+        let rect = Rect::new(
+            (i % cols) * TILE_WIDTH,
+            (i / cols) * TILE_HEIGHT,
+            TILE_WIDTH,
+            TILE_HEIGHT,
+        );
+        let tile = create_tile(frame, label, Size::new(TILE_WIDTH, TILE_HEIGHT))?;
+        let mut roi = Mat::roi_mut(&mut mosaic_frame, rect)?;
+        tile.copy_to(&mut roi)?;
+    }
+    Ok(mosaic_frame)
+}
+
+// baseの右下にtileを1/4サイズで重ねる
+pub fn picture_in_picture(base: &Mat, tile: &Mat, label: &str) -> Result<Mat, opencv::Error> {
+    const MARGIN: i32 = 10;
+    let mut result = to_bgr(base)?;
+    let size = Size::new(result.cols() / 4, result.rows() / 4);
+    let rect = Rect::new(
+        result.cols() - size.width - MARGIN,
+        result.rows() - size.height - MARGIN,
+        size.width,
+        size.height,
+    );
+    let tile = create_tile(tile, label, size)?;
+    let mut roi = Mat::roi_mut(&mut result, rect)?;
+    tile.copy_to(&mut roi)?;
+    Ok(result)
+}
+
+fn create_tile(frame: &Mat, label: &str, size: Size) -> Result<Mat, opencv::Error> {
+    let mut tile = Mat::default();
+    imgproc::resize(
+        &to_bgr(frame)?,
+        &mut tile,
+        size,
+        0.0,
+        0.0,
+        imgproc::INTER_AREA,
+    )?;
+    imgproc::put_text(
+        &mut tile,
+        label,
+        Point::new(5, 15),
+        imgproc::FONT_HERSHEY_SIMPLEX,
+        0.5,
+        Scalar::new(0.0, 255.0, 255.0, 0.0), // 黄色
+        1,
+        imgproc::LINE_AA,
+        false,
+    )?;
+    Ok(tile)
+}
@@ -0,0 +1,390 @@
+use crate::camera::cascade::Detection;
+use crate::camera::composite;
+use crate::camera::edges::{self, CannyParams};
+use crate::camera::threshold::{self, ThresholdParams};
+use crate::camera::utils;
+use opencv::core::{Mat, Point, Point2f, RotatedRect, Scalar, Vec4i, Vector};
+use opencv::{imgproc, prelude::*};
+use std::f64::consts::PI;
+
+// 4πA/P^2 がこれ以上の場合は円とみなす(真円で1.0)
+const CIRCULARITY_THRESHOLD: f64 = 0.8;
+// 縦横比がこの範囲なら正方形とみなす
+const SQUARE_TOLERANCE: f64 = 0.1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ContourSource {
+    Canny,     // cannyのエッジから輪郭を求める
+    Threshold, // 二値化した画像から輪郭を求める
+    Mask,      // 入力画像の0以外の画素をそのまま使う(binaryなどの後段につなぐ場合)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RetrievalMode {
+    External, // 最外輪郭のみ
+    List,     // 全ての輪郭(階層なし)
+    Ccomp,    // 外側と穴の2階層
+    Tree,     // 全ての階層
+}
+
+impl RetrievalMode {
+    fn value(&self) -> i32 {
+        match self {
+            RetrievalMode::External => imgproc::RETR_EXTERNAL,
+            RetrievalMode::List => imgproc::RETR_LIST,
+            RetrievalMode::Ccomp => imgproc::RETR_CCOMP,
+            RetrievalMode::Tree => imgproc::RETR_TREE,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Overlay {
+    Contour,         // 輪郭
+    Approx,          // 多角形近似
+    Hull,            // 凸包
+    BoundingRect,    // 外接矩形
+    RotatedRect,     // 回転を考慮した最小外接矩形
+    EnclosingCircle, // 最小外接円
+    Label,           // 形状名
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Shape {
+    Triangle,
+    Rectangle,
+    Square,
+    Pentagon,
+    Hexagon,
+    Circle,
+    Polygon,
+}
+
+impl Shape {
+    fn name(&self) -> &'static str {
+        match self {
+            Shape::Triangle => "triangle",
+            Shape::Rectangle => "rectangle",
+            Shape::Square => "square",
+            Shape::Pentagon => "pentagon",
+            Shape::Hexagon => "hexagon",
+            Shape::Circle => "circle",
+            Shape::Polygon => "polygon",
+        }
+    }
+
+    // 形状ごとの描画色(BGR)
+    fn color(&self) -> Scalar {
+        match self {
+            Shape::Triangle => Scalar::new(0.0, 255.0, 255.0, 0.0),
+            Shape::Rectangle => Scalar::new(255.0, 0.0, 0.0, 0.0),
+            Shape::Square => Scalar::new(255.0, 255.0, 0.0, 0.0),
+            Shape::Pentagon => Scalar::new(255.0, 0.0, 255.0, 0.0),
+            Shape::Hexagon => Scalar::new(0.0, 128.0, 255.0, 0.0),
+            Shape::Circle => Scalar::new(0.0, 0.0, 255.0, 0.0),
+            Shape::Polygon => Scalar::new(0.0, 255.0, 0.0, 0.0),
+        }
+    }
+}
+
+/*
+* {"source": "canny", "mode": "tree", "min_area": 500, "max_area": 0, "epsilon": 0.02,
+*  "overlays": ["contour", "hull", "rotated_rect", "label"], "max_contours": 50}
+* max_area, max_perimeterが0の場合は上限なし
+* epsilon -> 多角形近似の許容誤差(周囲長に対する割合)
+* canny, thresholdはsourceがcanny, thresholdの場合のパラメータ
+*/
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct ContourParams {
+    pub source: ContourSource,
+    pub canny: CannyParams,
+    pub threshold: ThresholdParams,
+    pub mode: RetrievalMode,
+    pub min_area: f64,
+    pub max_area: f64,
+    pub min_perimeter: f64,
+    pub max_perimeter: f64,
+    pub epsilon: f64,
+    pub overlays: Vec<Overlay>,
+    pub max_contours: usize,
+}
+
+impl Default for ContourParams {
+    fn default() -> Self {
+        Self {
+            source: ContourSource::Canny,
+            canny: CannyParams::default(),
+            threshold: ThresholdParams::default(),
+            mode: RetrievalMode::External,
+            min_area: 100.0,
+            max_area: 0.0,
+            min_perimeter: 0.0,
+            max_perimeter: 0.0,
+            epsilon: 0.02,
+            overlays: vec![Overlay::Contour, Overlay::Label],
+            max_contours: 50,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, serde::Serialize)]
+pub struct RotatedBox {
+    pub center: [f32; 2],
+    pub size: [f32; 2],
+    pub angle: f32,
+}
+
+#[derive(Debug, Clone, Copy, serde::Serialize)]
+pub struct Circle {
+    pub center: [f32; 2],
+    pub radius: f32,
+}
+
+/*
+* 輪郭ごとの統計
+* index, parent -> 検出された輪郭の番号と親輪郭の番号(-1は親なし)。面積などで除外された輪郭があっても番号は変わらない
+* circularity -> 4πA/P^2, solidity -> 面積 / 凸包の面積, aspect_ratio -> 外接矩形の幅 / 高さ
+*/
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct ContourStats {
+    pub index: usize,
+    pub parent: i32,
+    pub shape: Shape,
+    pub vertices: usize,
+    pub area: f64,
+    pub perimeter: f64,
+    pub centroid: [f64; 2],
+    pub circularity: f64,
+    pub solidity: f64,
+    pub aspect_ratio: f64,
+    pub bbox: Detection,
+    pub rotated_rect: RotatedBox,
+    pub enclosing_circle: Circle,
+}
+
+pub struct Contour {
+    pub points: Vector<Point>,
+    pub approx: Vector<Point>,
+    pub hull: Vector<Point>,
+    pub rotated: RotatedRect,
+    pub stats: ContourStats,
+}
+
+// 輪郭を求めるための二値画像
+fn binarize(frame: &Mat, params: &ContourParams) -> Result<Mat, opencv::Error> {
+    match params.source {
+        ContourSource::Canny => Ok(edges::canny(frame, &params.canny)?.0),
+        ContourSource::Threshold => Ok(threshold::threshold(frame, &params.threshold)?.0),
+        ContourSource::Mask => utils::to_gray(frame),
+    }
+}
+
+// 面積が大きい順にmax_contours個の輪郭
+pub fn find_contours(frame: &Mat, params: &ContourParams) -> Result<Vec<Contour>, opencv::Error> {
+    let binary_frame = binarize(frame, params)?;
+    let mut contours = Vector::<Vector<Point>>::new();
+    let mut hierarchy = Vector::<Vec4i>::new();
+    imgproc::find_contours_with_hierarchy(
+        &binary_frame,
+        &mut contours,
+        &mut hierarchy,
+        params.mode.value(),
+        imgproc::CHAIN_APPROX_SIMPLE,
+        Point::new(0, 0),
+    )?;
+
+    let mut results = vec![];
+    for (index, points) in contours.into_iter().enumerate() {
+        let area = imgproc::contour_area(&points, false)?;
+        let perimeter = imgproc::arc_length(&points, true)?;
+        let in_range =
+            |value: f64, min: f64, max: f64| value >= min && (max <= 0.0 || value <= max);
+        if !in_range(area, params.min_area, params.max_area)
+            || !in_range(perimeter, params.min_perimeter, params.max_perimeter)
+        {
+            continue;
+        }
+        let parent = hierarchy.get(index)?[3];
+        results.push(analyze(
+            index,
+            parent,
+            points,
+            area,
+            perimeter,
+            params.epsilon,
+        )?);
+    }
+    results.sort_by(|a, b| b.stats.area.total_cmp(&a.stats.area));
+    results.truncate(params.max_contours);
+    Ok(results)
+}
+
+fn analyze(
+    index: usize,
+    parent: i32,
+    points: Vector<Point>,
+    area: f64,
+    perimeter: f64,
+    epsilon: f64,
+) -> Result<Contour, opencv::Error> {
+    let mut approx = Vector::<Point>::new();
+    imgproc::approx_poly_dp(&points, &mut approx, epsilon * perimeter, true)?;
+    let mut hull = Vector::<Point>::new();
+    imgproc::convex_hull(&points, &mut hull, false, true)?;
+    let hull_area = imgproc::contour_area(&hull, false)?;
+
+    let moments = imgproc::moments(&points, false)?;
+    let bbox = imgproc::bounding_rect(&points)?;
+    let rotated = imgproc::min_area_rect(&points)?;
+    let mut center = Point2f::default();
+    let mut radius = 0.0;
+    imgproc::min_enclosing_circle(&points, &mut center, &mut radius)?;
+
+    let circularity = match perimeter > 0.0 {
+        true => 4.0 * PI * area / (perimeter * perimeter),
+        false => 0.0,
+    };
+    let centroid = match moments.m00 != 0.0 {
+        true => [moments.m10 / moments.m00, moments.m01 / moments.m00],
+        false => [
+            (bbox.x + bbox.width / 2) as f64,
+            (bbox.y + bbox.height / 2) as f64,
+        ],
+    };
+    let stats = ContourStats {
+        index,
+        parent,
+        shape: classify(
+            &approx,
+            circularity,
+            rotated.size.width,
+            rotated.size.height,
+        ),
+        vertices: approx.len(),
+        area,
+        perimeter,
+        centroid,
+        circularity,
+        solidity: if hull_area > 0.0 {
+            area / hull_area
+        } else {
+            0.0
+        },
+        aspect_ratio: bbox.width as f64 / bbox.height.max(1) as f64,
+        bbox: Detection::from(bbox),
+        rotated_rect: RotatedBox {
+            center: [rotated.center.x, rotated.center.y],
+            size: [rotated.size.width, rotated.size.height],
+            angle: rotated.angle,
+        },
+        enclosing_circle: Circle {
+            center: [center.x, center.y],
+            radius,
+        },
+    };
+    Ok(Contour {
+        points,
+        approx,
+        hull,
+        rotated,
+        stats,
+    })
+}
+
+// 近似した多角形の頂点数と円形度から形状を判定する
+fn classify(approx: &Vector<Point>, circularity: f64, width: f32, height: f32) -> Shape {
+    match approx.len() {
+        3 => Shape::Triangle,
+        4 => {
+            // 回転した正方形も正方形とみなすため、最小外接矩形の縦横比を使う
+            let ratio = width as f64 / (height as f64).max(1.0);
+            match (ratio - 1.0).abs() <= SQUARE_TOLERANCE {
+                true => Shape::Square,
+                false => Shape::Rectangle,
+            }
+        }
+        5 => Shape::Pentagon,
+        6 => Shape::Hexagon,
+        _ if circularity >= CIRCULARITY_THRESHOLD => Shape::Circle,
+        _ => Shape::Polygon,
+    }
+}
+
+pub fn draw_contours(
+    frame: &Mat,
+    contours: &[Contour],
+    overlays: &[Overlay],
+) -> Result<Mat, opencv::Error> {
+    let mut result = composite::to_bgr(frame)?;
+    for contour in contours.iter() {
+        let stats = &contour.stats;
+        let color = stats.shape.color();
+        for overlay in overlays.iter() {
+            match overlay {
+                Overlay::Contour => draw_polygon(&mut result, &contour.points, color)?,
+                Overlay::Approx => draw_polygon(&mut result, &contour.approx, color)?,
+                Overlay::Hull => draw_polygon(&mut result, &contour.hull, color)?,
+                Overlay::BoundingRect => imgproc::rectangle(
+                    &mut result,
+                    stats.bbox.into(),
+                    color,
+                    1,
+                    imgproc::LINE_8,
+                    0,
+                )?,
+                Overlay::RotatedRect => {
+                    let box_points = rotated_rect_points(contour.rotated)?;
+                    draw_polygon(&mut result, &box_points, color)?;
+                }
+                Overlay::EnclosingCircle => imgproc::circle(
+                    &mut result,
+                    Point::new(
+                        stats.enclosing_circle.center[0] as i32,
+                        stats.enclosing_circle.center[1] as i32,
+                    ),
+                    stats.enclosing_circle.radius as i32,
+                    color,
+                    1,
+                    imgproc::LINE_AA,
+                    0,
+                )?,
+                Overlay::Label => imgproc::put_text(
+                    &mut result,
+                    stats.shape.name(),
+                    Point::new(stats.centroid[0] as i32, stats.centroid[1] as i32),
+                    imgproc::FONT_HERSHEY_SIMPLEX,
+                    0.5,
+                    color,
+                    1,
+                    imgproc::LINE_AA,
+                    false,
+                )?,
+            }
+        }
+    }
+    Ok(result)
+}
+
+fn draw_polygon(
+    frame: &mut Mat,
+    points: &Vector<Point>,
+    color: Scalar,
+) -> Result<(), opencv::Error> {
+    let mut polygons = Vector::<Vector<Point>>::new();
+    polygons.push(points.clone());
+    imgproc::polylines(frame, &polygons, true, color, 2, imgproc::LINE_AA, 0)
+}
+
+fn rotated_rect_points(rotated: RotatedRect) -> Result<Vector<Point>, opencv::Error> {
+    let mut corners = [Point2f::default(); 4];
+    rotated.points(&mut corners)?;
+    Ok(corners
+        .iter()
+        .map(|corner| Point::new(corner.x.round() as i32, corner.y.round() as i32))
+        .collect())
+}
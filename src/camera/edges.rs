@@ -0,0 +1,270 @@
+use crate::camera::filters::{self, BlurMethod, BlurParams};
+use crate::camera::utils;
+use opencv::core::{self, Mat, StsBadArg, Vector, BORDER_DEFAULT, CV_32F, CV_8U, NORM_MINMAX};
+use opencv::{imgproc, prelude::*};
+
+// 自動閾値の中央値からの幅の既定値
+const AUTO_SIGMA: f64 = 0.33;
+
+// blurが0より大きい場合はガウシアンでぼかしてからグレースケールにする
+fn prepare(frame: &Mat, blur: i32) -> Result<Mat, opencv::Error> {
+    let gray_frame = utils::to_gray(frame)?;
+    if blur <= 0 {
+        return Ok(gray_frame);
+    }
+    let params = BlurParams {
+        method: BlurMethod::Gaussian,
+        size: blur,
+        sigma: 0.0,
+    };
+    filters::blur(&gray_frame, &params)
+}
+
+/*
+* {"auto": true, "sigma": 0.33, "blur": 5, "l2_gradient": true}
+* auto: true の場合は輝度の中央値mから閾値を (1 - sigma) * m, (1 + sigma) * m とする
+* auto: false の場合は threshold1 <= エッジとして判定 <= threshold2
+* aperture_size -> ソーベル演算子のサイズ(3, 5, 7)
+*/
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct CannyParams {
+    pub auto: bool,
+    pub sigma: f64,
+    pub threshold1: f64,
+    pub threshold2: f64,
+    pub blur: i32,
+    pub aperture_size: i32,
+    pub l2_gradient: bool, // true -> L2ノルム, false -> L1ノルム
+}
+
+impl Default for CannyParams {
+    fn default() -> Self {
+        Self {
+            auto: false,
+            sigma: AUTO_SIGMA,
+            threshold1: 100.0,
+            threshold2: 200.0,
+            blur: 0,
+            aperture_size: 3,
+            l2_gradient: false,
+        }
+    }
+}
+
+// エッジ画像と、実際に使われた閾値
+pub fn canny(frame: &Mat, params: &CannyParams) -> Result<(Mat, [f64; 2]), opencv::Error> {
+    let gray_frame = prepare(frame, params.blur)?;
+    let thresholds = match params.auto {
+        true => {
+            let median = median_intensity(&gray_frame)?;
+            [
+                ((1.0 - params.sigma) * median).max(0.0),
+                ((1.0 + params.sigma) * median).min(255.0),
+            ]
+        }
+        false => [params.threshold1, params.threshold2],
+    };
+
+    let mut canny_frame = Mat::default();
+    imgproc::canny(
+        &gray_frame,
+        &mut canny_frame,
+        thresholds[0],
+        thresholds[1],
+        params.aperture_size,
+        params.l2_gradient,
+    )?;
+    Ok((canny_frame, thresholds))
+}
+
+// ヒストグラムから輝度の中央値を求める
+fn median_intensity(gray_frame: &Mat) -> Result<f64, opencv::Error> {
+    let mut histogram = [0usize; 256];
+    for value in gray_frame.data_bytes()? {
+        histogram[*value as usize] += 1;
+    }
+    let half = gray_frame.total() / 2;
+    let mut count = 0;
+    for (value, frequency) in histogram.iter().enumerate() {
+        count += frequency;
+        if count > half {
+            return Ok(value as f64);
+        }
+    }
+    Ok(0.0)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum GradientOperator {
+    Sobel,
+    Scharr,    // 3x3のみ。Sobelより回転に対して正確
+    Laplacian, // 2次微分のため方向はない
+}
+
+/*
+* {"ksize": 3, "blur": 0}
+* ksize -> Sobel, Laplacianのカーネルサイズ(1, 3, 5, 7)。Scharrは3x3固定のため使わない
+*/
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct GradientParams {
+    pub ksize: i32,
+    pub blur: i32,
+}
+
+impl Default for GradientParams {
+    fn default() -> Self {
+        Self { ksize: 3, blur: 0 }
+    }
+}
+
+// x方向, y方向の1次微分(CV_32F)
+fn derivatives(
+    gray_frame: &Mat,
+    operator: GradientOperator,
+    ksize: i32,
+) -> Result<(Mat, Mat), opencv::Error> {
+    let mut gradient_x = Mat::default();
+    let mut gradient_y = Mat::default();
+    match operator {
+        GradientOperator::Sobel => {
+            imgproc::sobel(
+                gray_frame,
+                &mut gradient_x,
+                CV_32F,
+                1,
+                0,
+                ksize,
+                1.0,
+                0.0,
+                BORDER_DEFAULT,
+            )?;
+            imgproc::sobel(
+                gray_frame,
+                &mut gradient_y,
+                CV_32F,
+                0,
+                1,
+                ksize,
+                1.0,
+                0.0,
+                BORDER_DEFAULT,
+            )?;
+        }
+        GradientOperator::Scharr => {
+            imgproc::scharr(
+                gray_frame,
+                &mut gradient_x,
+                CV_32F,
+                1,
+                0,
+                1.0,
+                0.0,
+                BORDER_DEFAULT,
+            )?;
+            imgproc::scharr(
+                gray_frame,
+                &mut gradient_y,
+                CV_32F,
+                0,
+                1,
+                1.0,
+                0.0,
+                BORDER_DEFAULT,
+            )?;
+        }
+        GradientOperator::Laplacian => {
+            return Err(opencv::Error::new(
+                StsBadArg,
+                "laplacian has no gradient direction",
+            ))
+        }
+    }
+    Ok((gradient_x, gradient_y))
+}
+
+// 勾配の強さ(PixelFormat::Float)
+pub fn magnitude(
+    frame: &Mat,
+    operator: GradientOperator,
+    params: &GradientParams,
+) -> Result<Mat, opencv::Error> {
+    let gray_frame = prepare(frame, params.blur)?;
+    if operator == GradientOperator::Laplacian {
+        let mut laplacian = Mat::default();
+        imgproc::laplacian(
+            &gray_frame,
+            &mut laplacian,
+            CV_32F,
+            params.ksize,
+            1.0,
+            0.0,
+            BORDER_DEFAULT,
+        )?;
+        return core::abs(&laplacian)?.to_mat();
+    }
+    let (gradient_x, gradient_y) = derivatives(&gray_frame, operator, params.ksize)?;
+    let mut magnitude = Mat::default();
+    core::magnitude(&gradient_x, &gradient_y, &mut magnitude)?;
+    Ok(magnitude)
+}
+
+/*
+* 勾配の方向を色相、強さを明度で表す
+* {"operator": "scharr", "ksize": 3, "blur": 5}
+* 右向き(明るくなる方向が+x) -> 赤, 下向き -> 黄緑, 左向き -> 水色, 上向き -> 紫
+*/
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct OrientationParams {
+    pub operator: GradientOperator,
+    pub ksize: i32,
+    pub blur: i32,
+}
+
+impl Default for OrientationParams {
+    fn default() -> Self {
+        Self {
+            operator: GradientOperator::Sobel,
+            ksize: 3,
+            blur: 0,
+        }
+    }
+}
+
+pub fn orientation(frame: &Mat, params: &OrientationParams) -> Result<Mat, opencv::Error> {
+    let gray_frame = prepare(frame, params.blur)?;
+    let (gradient_x, gradient_y) = derivatives(&gray_frame, params.operator, params.ksize)?;
+    let mut magnitude = Mat::default();
+    let mut angle = Mat::default();
+    core::cart_to_polar(&gradient_x, &gradient_y, &mut magnitude, &mut angle, true)?;
+
+    // 8bitのHSVの色相は0〜180のため、角度(0〜360)を半分にする
+    let mut hue = Mat::default();
+    angle.convert_to(&mut hue, CV_8U, 0.5, 0.0)?;
+    let mut value = Mat::default();
+    core::normalize(
+        &magnitude,
+        &mut value,
+        0.0,
+        255.0,
+        NORM_MINMAX,
+        CV_8U,
+        &core::no_array(),
+    )?;
+    let saturation = Mat::new_size_with_default(hue.size()?, CV_8U, core::Scalar::all(255.0))?;
+
+    let channels = Vector::<Mat>::from_iter([hue, saturation, value]);
+    let mut hsv_frame = Mat::default();
+    core::merge(&channels, &mut hsv_frame)?;
+    let mut orientation_frame = Mat::default();
+    imgproc::cvt_color(
+        &hsv_frame,
+        &mut orientation_frame,
+        imgproc::COLOR_HSV2BGR,
+        0,
+    )?;
+    Ok(orientation_frame)
+}
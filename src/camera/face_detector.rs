@@ -0,0 +1,318 @@
+use crate::camera::cascade::{self, CascadeCache, CascadeParams, Detection};
+use crate::camera::utils;
+use opencv::core::{Mat, Rect};
+use opencv::prelude::*;
+use std::collections::{BTreeMap, HashMap};
+
+/*
+* 顔の中から探す部位(目・口など)の設定
+* region は顔の矩形に対する相対位置 [x, y, width, height]
+* 最小・最大サイズは顔の幅に対する比率で指定する
+* {"name": "smile", "model": "haarcascade_smile.xml", "region": [0.0, 0.5, 1.0, 0.5],
+*  "min_size_ratio": 0.25, "max_size_ratio": 0.8, "max_count": 1, "color": [0, 0, 255]}
+* 省略した項目は name に対応する部位の既定値になる。既定値のない部位は model が必須
+*/
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+#[serde(try_from = "PartSpec")]
+pub struct PartParams {
+    pub name: String,
+    pub model: String,
+    pub region: [f64; 4],
+    pub scale_factor: f64,
+    pub min_neighbors: i32,
+    pub min_size_ratio: f64,
+    pub max_size_ratio: f64,
+    pub max_count: usize, // 0の場合は上限なし
+    pub color: [f64; 3],  // BGR
+}
+
+impl Default for PartParams {
+    // 顔の上半分から目を最大2つ探す
+    fn default() -> Self {
+        Self {
+            name: "eye".to_string(),
+            model: "haarcascade_eye.xml".to_string(),
+            region: [0.0, 0.0, 1.0, 0.5],
+            scale_factor: 1.1,
+            min_neighbors: 3,
+            min_size_ratio: 0.15,
+            max_size_ratio: 0.5,
+            max_count: 2,
+            color: [255.0, 0.0, 0.0],
+        }
+    }
+}
+
+// クライアントから受け取る部位の設定。省略された項目は既定値で補う
+#[derive(serde::Deserialize)]
+struct PartSpec {
+    name: Option<String>,
+    model: Option<String>,
+    region: Option<[f64; 4]>,
+    scale_factor: Option<f64>,
+    min_neighbors: Option<i32>,
+    min_size_ratio: Option<f64>,
+    max_size_ratio: Option<f64>,
+    max_count: Option<usize>,
+    color: Option<[f64; 3]>,
+}
+
+impl TryFrom<PartSpec> for PartParams {
+    type Error = String;
+
+    // 既定値のない部位はmodelを必須とし、顔全体から探す
+    fn try_from(spec: PartSpec) -> Result<Self, Self::Error> {
+        let name = spec.name.unwrap_or_else(|| PartParams::default().name);
+        let defaults = match (PartParams::preset(&name), &spec.model) {
+            (Some(preset), _) => preset,
+            (None, Some(_)) => Self {
+                name: name.clone(),
+                region: [0.0, 0.0, 1.0, 1.0],
+                max_count: 0,
+                ..Self::default()
+            },
+            (None, None) => return Err(format!("model is required for part: {}", name)),
+        };
+        Ok(Self {
+            model: spec.model.unwrap_or(defaults.model),
+            region: spec.region.unwrap_or(defaults.region),
+            scale_factor: spec.scale_factor.unwrap_or(defaults.scale_factor),
+            min_neighbors: spec.min_neighbors.unwrap_or(defaults.min_neighbors),
+            min_size_ratio: spec.min_size_ratio.unwrap_or(defaults.min_size_ratio),
+            max_size_ratio: spec.max_size_ratio.unwrap_or(defaults.max_size_ratio),
+            max_count: spec.max_count.unwrap_or(defaults.max_count),
+            color: spec.color.unwrap_or(defaults.color),
+            name,
+        })
+    }
+}
+
+impl PartParams {
+    // 名前に対応する部位の既定値。目以外は最大1つ探す
+    fn preset(name: &str) -> Option<Self> {
+        let eye = Self::default();
+        let preset = match name {
+            "eye" => eye,
+            "left_eye" => Self {
+                model: "haarcascade_lefteye_2splits.xml".to_string(),
+                region: [0.5, 0.0, 0.5, 0.5],
+                max_count: 1,
+                ..eye
+            },
+            "right_eye" => Self {
+                model: "haarcascade_righteye_2splits.xml".to_string(),
+                region: [0.0, 0.0, 0.5, 0.5],
+                max_count: 1,
+                ..eye
+            },
+            "nose" => Self {
+                model: "haarcascade_mcs_nose.xml".to_string(),
+                region: [0.25, 0.25, 0.5, 0.5],
+                max_count: 1,
+                color: [0.0, 255.0, 255.0],
+                ..eye
+            },
+            "mouth" => Self {
+                model: "haarcascade_mcs_mouth.xml".to_string(),
+                region: [0.0, 0.5, 1.0, 0.5],
+                min_size_ratio: 0.25,
+                max_size_ratio: 0.8,
+                max_count: 1,
+                color: [0.0, 0.0, 255.0],
+                ..eye
+            },
+            "smile" => Self {
+                model: "haarcascade_smile.xml".to_string(),
+                region: [0.0, 0.5, 1.0, 0.5],
+                min_size_ratio: 0.25,
+                max_size_ratio: 0.8,
+                max_count: 1,
+                color: [0.0, 0.0, 255.0],
+                ..eye
+            },
+            _ => return None,
+        };
+        Some(Self {
+            name: name.to_string(),
+            ..preset
+        })
+    }
+
+    // 顔の矩形から探索範囲を求める(画像の外にはみ出さないようにする)
+    fn search_region(&self, face: Rect, frame_rect: Rect) -> Rect {
+        let [x, y, width, height] = self.region;
+        let region = Rect::new(
+            face.x + (face.width as f64 * x) as i32,
+            face.y + (face.height as f64 * y) as i32,
+            (face.width as f64 * width) as i32,
+            (face.height as f64 * height) as i32,
+        );
+        region & frame_rect
+    }
+
+    fn cascade_params(&self, face: Rect) -> CascadeParams {
+        let min_size = (face.width as f64 * self.min_size_ratio) as i32;
+        let max_size = (face.width as f64 * self.max_size_ratio) as i32;
+        CascadeParams {
+            model: self.model.clone(),
+            scale_factor: self.scale_factor,
+            min_neighbors: self.min_neighbors,
+            flags: vec![],
+            min_size: [min_size, min_size],
+            max_size: [max_size, max_size],
+            color: self.color,
+        }
+    }
+}
+
+/*
+* {"face": {"min_neighbors": 5}, "parts": [{"name": "eye"}, {"name": "smile", ...}]}
+* partsを指定した場合はデフォルトの目の設定を置き換える
+*/
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct FaceDetectorParams {
+    pub face: CascadeParams,
+    pub parts: Vec<PartParams>,
+}
+
+impl Default for FaceDetectorParams {
+    fn default() -> Self {
+        Self {
+            face: CascadeParams {
+                min_size: [80, 80],
+                ..Default::default()
+            },
+            parts: vec![PartParams::default()],
+        }
+    }
+}
+
+// 顔ごとの検出結果。部位の座標は画像全体の座標
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct FaceResult {
+    pub face: Detection,
+    pub parts: BTreeMap<String, Vec<Detection>>,
+}
+
+// 顔と各部位の分類器をノードの状態として保持する
+#[derive(Default)]
+pub struct FaceDetectorCache {
+    face: CascadeCache,
+    parts: HashMap<String, CascadeCache>,
+}
+
+pub fn detect_faces(
+    frame: &Mat,
+    cache: &mut FaceDetectorCache,
+    params: &FaceDetectorParams,
+) -> Result<Vec<FaceResult>, opencv::Error> {
+    let gray_frame = utils::to_gray(frame)?;
+    let frame_rect = Rect::new(0, 0, gray_frame.cols(), gray_frame.rows());
+    let faces = cascade::detect(
+        &gray_frame,
+        cache.face.get(&params.face.model)?,
+        &params.face,
+    )?;
+
+    let mut results = vec![];
+    for face in faces {
+        let mut parts = BTreeMap::new();
+        for part in params.parts.iter() {
+            let region = part.search_region(face, frame_rect);
+            if region.empty() {
+                continue;
+            }
+            let roi = Mat::roi(&gray_frame, region)?.try_clone()?;
+            let classifier = cache
+                .parts
+                .entry(part.name.clone())
+                .or_default()
+                .get(&part.model)?;
+            let mut rects = cascade::detect(&roi, classifier, &part.cascade_params(face))?;
+            // 大きいものから順にmax_count個まで残す
+            rects.sort_by_key(|rect| -rect.area());
+            if part.max_count > 0 {
+                rects.truncate(part.max_count);
+            }
+            let detections = rects
+                .iter()
+                .map(|rect| Detection::from(*rect + region.tl()))
+                .collect();
+            parts.insert(part.name.clone(), detections);
+        }
+        results.push(FaceResult {
+            face: Detection::from(face),
+            parts,
+        });
+    }
+    Ok(results)
+}
+
+pub fn draw_faces(
+    frame: &Mat,
+    results: &[FaceResult],
+    params: &FaceDetectorParams,
+) -> Result<Mat, opencv::Error> {
+    let faces: Vec<Rect> = results.iter().map(|result| result.face.into()).collect();
+    let mut detected_frame = cascade::draw_detections(frame, &faces, params.face.color)?;
+    for part in params.parts.iter() {
+        let rects: Vec<Rect> = results
+            .iter()
+            .filter_map(|result| result.parts.get(&part.name))
+            .flatten()
+            .map(|detection| (*detection).into())
+            .collect();
+        detected_frame = cascade::draw_detections(&detected_frame, &rects, part.color)?;
+    }
+    Ok(detected_frame)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn part(spec: serde_json::Value) -> Result<PartParams, serde_json::Error> {
+        serde_json::from_value(spec)
+    }
+
+    #[test]
+    fn known_parts_use_their_own_model_and_region() {
+        let smile = part(json!({"name": "smile"})).unwrap();
+        assert_eq!(smile.model, "haarcascade_smile.xml");
+        assert_eq!(smile.region, [0.0, 0.5, 1.0, 0.5]);
+        assert_eq!(smile.max_count, 1);
+
+        let nose = part(json!({"name": "nose", "max_count": 2})).unwrap();
+        assert_eq!(nose.model, "haarcascade_mcs_nose.xml");
+        assert_eq!(nose.max_count, 2);
+    }
+
+    #[test]
+    fn omitted_name_is_an_eye() {
+        let eye = part(json!({})).unwrap();
+        assert_eq!(eye.name, "eye");
+        assert_eq!(eye.model, "haarcascade_eye.xml");
+    }
+
+    #[test]
+    fn unknown_parts_require_a_model() {
+        let error = part(json!({"name": "ear"})).unwrap_err();
+        assert!(error
+            .to_string()
+            .contains("model is required for part: ear"));
+
+        let ear = part(json!({"name": "ear", "model": "ear.xml"})).unwrap();
+        assert_eq!(ear.region, [0.0, 0.0, 1.0, 1.0]);
+        assert_eq!(ear.max_count, 0);
+    }
+
+    #[test]
+    fn serialized_parts_are_read_back_unchanged() {
+        let smile = part(json!({"name": "smile", "min_neighbors": 20})).unwrap();
+        let restored = part(serde_json::to_value(&smile).unwrap()).unwrap();
+        assert_eq!(restored.model, smile.model);
+        assert_eq!(restored.min_neighbors, 20);
+    }
+}
@@ -0,0 +1,331 @@
+use opencv::core::{self, Mat, Point, Size, StsBadArg, BORDER_DEFAULT};
+use opencv::{imgproc, prelude::*};
+
+// ガウシアン・メディアンのカーネルは奇数のみ。偶数が指定された場合は+1する
+fn odd_size(size: i32) -> i32 {
+    size.max(1) | 1
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BlurMethod {
+    Gaussian,
+    Median,
+    Box,           // 正規化しない(カーネル内の合計。明るくなる)
+    BoxNormalized, // カーネル内の平均
+}
+
+/*
+* {"method": "gaussian", "size": 5, "sigma": 0.0}
+* sigmaが0の場合はsizeから求める(gaussianのみ)
+*/
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct BlurParams {
+    pub method: BlurMethod,
+    pub size: i32,
+    pub sigma: f64,
+}
+
+impl Default for BlurParams {
+    fn default() -> Self {
+        Self {
+            method: BlurMethod::Gaussian,
+            size: 5,
+            sigma: 0.0,
+        }
+    }
+}
+
+pub fn blur(frame: &Mat, params: &BlurParams) -> Result<Mat, opencv::Error> {
+    let mut blurred = Mat::default();
+    let size = params.size.max(1);
+    let anchor = Point::new(-1, -1);
+    match params.method {
+        BlurMethod::Gaussian => {
+            let size = odd_size(size);
+            imgproc::gaussian_blur(
+                frame,
+                &mut blurred,
+                Size::new(size, size),
+                params.sigma,
+                params.sigma,
+                BORDER_DEFAULT,
+            )?;
+        }
+        BlurMethod::Median => imgproc::median_blur(frame, &mut blurred, odd_size(size))?,
+        BlurMethod::Box => imgproc::box_filter(
+            frame,
+            &mut blurred,
+            -1,
+            Size::new(size, size),
+            anchor,
+            false,
+            BORDER_DEFAULT,
+        )?,
+        BlurMethod::BoxNormalized => imgproc::blur(
+            frame,
+            &mut blurred,
+            Size::new(size, size),
+            anchor,
+            BORDER_DEFAULT,
+        )?,
+    }
+    Ok(blurred)
+}
+
+/*
+* アンシャープマスク: frame + amount * (frame - ぼかした画像)
+* {"size": 5, "sigma": 1.0, "amount": 1.5}
+*/
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct SharpenParams {
+    pub size: i32,
+    pub sigma: f64,
+    pub amount: f64,
+}
+
+impl Default for SharpenParams {
+    fn default() -> Self {
+        Self {
+            size: 5,
+            sigma: 1.0,
+            amount: 1.0,
+        }
+    }
+}
+
+pub fn sharpen(frame: &Mat, params: &SharpenParams) -> Result<Mat, opencv::Error> {
+    let blur_params = BlurParams {
+        method: BlurMethod::Gaussian,
+        size: params.size,
+        sigma: params.sigma,
+    };
+    let blurred = blur(frame, &blur_params)?;
+    let mut sharpened = Mat::default();
+    core::add_weighted(
+        frame,
+        1.0 + params.amount,
+        &blurred,
+        -params.amount,
+        0.0,
+        &mut sharpened,
+        -1,
+    )?;
+    Ok(sharpened)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum KernelShape {
+    Rect,
+    Cross,
+    Ellipse,
+}
+
+impl KernelShape {
+    pub fn structuring_element(&self, size: i32) -> Result<Mat, opencv::Error> {
+        let shape = match self {
+            KernelShape::Rect => imgproc::MORPH_RECT,
+            KernelShape::Cross => imgproc::MORPH_CROSS,
+            KernelShape::Ellipse => imgproc::MORPH_ELLIPSE,
+        };
+        let size = size.max(1);
+        imgproc::get_structuring_element(shape, Size::new(size, size), Point::new(-1, -1))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MorphOperation {
+    Erode,
+    Dilate,
+    Open,
+    Close,
+    Gradient,
+    TopHat,
+    BlackHat,
+}
+
+impl MorphOperation {
+    fn value(&self) -> i32 {
+        match self {
+            MorphOperation::Erode => imgproc::MORPH_ERODE,
+            MorphOperation::Dilate => imgproc::MORPH_DILATE,
+            MorphOperation::Open => imgproc::MORPH_OPEN,
+            MorphOperation::Close => imgproc::MORPH_CLOSE,
+            MorphOperation::Gradient => imgproc::MORPH_GRADIENT,
+            MorphOperation::TopHat => imgproc::MORPH_TOPHAT,
+            MorphOperation::BlackHat => imgproc::MORPH_BLACKHAT,
+        }
+    }
+}
+
+/*
+* {"operation": "open", "shape": "ellipse", "size": 5, "iterations": 1}
+*/
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct MorphologyParams {
+    pub operation: MorphOperation,
+    pub shape: KernelShape,
+    pub size: i32,
+    pub iterations: i32,
+}
+
+impl Default for MorphologyParams {
+    fn default() -> Self {
+        Self {
+            operation: MorphOperation::Open,
+            shape: KernelShape::Rect,
+            size: 5,
+            iterations: 1,
+        }
+    }
+}
+
+pub fn morphology(frame: &Mat, params: &MorphologyParams) -> Result<Mat, opencv::Error> {
+    let kernel = params.shape.structuring_element(params.size)?;
+    let mut result = Mat::default();
+    imgproc::morphology_ex(
+        frame,
+        &mut result,
+        params.operation.value(),
+        &kernel,
+        Point::new(-1, -1),
+        params.iterations.max(1),
+        core::BORDER_CONSTANT,
+        imgproc::morphology_default_border_value()?,
+    )?;
+    Ok(result)
+}
+
+/*
+* ユーザ定義のカーネルによる畳み込み
+* {"kernel": [[0, -1, 0], [-1, 5, -1], [0, -1, 0]], "normalize": false, "delta": 0.0}
+* normalize: true の場合はカーネルの合計で割る(合計が0の場合はそのまま)
+*/
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct ConvolutionParams {
+    pub kernel: Vec<Vec<f64>>,
+    pub normalize: bool,
+    pub delta: f64,
+}
+
+impl Default for ConvolutionParams {
+    // シャープ化
+    fn default() -> Self {
+        Self {
+            kernel: vec![
+                vec![0.0, -1.0, 0.0],
+                vec![-1.0, 5.0, -1.0],
+                vec![0.0, -1.0, 0.0],
+            ],
+            normalize: false,
+            delta: 0.0,
+        }
+    }
+}
+
+pub fn convolve(frame: &Mat, params: &ConvolutionParams) -> Result<Mat, opencv::Error> {
+    let rows = params.kernel.len();
+    let cols = params.kernel.first().map_or(0, |row| row.len());
+    if rows == 0 || cols == 0 || params.kernel.iter().any(|row| row.len() != cols) {
+        return Err(opencv::Error::new(
+            StsBadArg,
+            "kernel must be a non-empty rectangular matrix",
+        ));
+    }
+    let mut values: Vec<f64> = params.kernel.concat();
+    let sum: f64 = values.iter().sum();
+    if params.normalize && sum != 0.0 {
+        values.iter_mut().for_each(|value| *value /= sum);
+    }
+
+    let kernel = Mat::new_rows_cols_with_data(rows as i32, cols as i32, &values)?;
+    let mut result = Mat::default();
+    imgproc::filter_2d(
+        frame,
+        &mut result,
+        -1,
+        &kernel,
+        Point::new(-1, -1),
+        params.delta,
+        BORDER_DEFAULT,
+    )?;
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use opencv::core::{Scalar, CV_8UC1};
+
+    fn uniform(value: f64) -> Mat {
+        Mat::new_rows_cols_with_default(5, 5, CV_8UC1, Scalar::all(value)).unwrap()
+    }
+
+    fn kernel_params(kernel: Vec<Vec<f64>>) -> ConvolutionParams {
+        ConvolutionParams {
+            kernel,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn even_sizes_are_rounded_up_to_odd() {
+        assert_eq!(odd_size(4), 5);
+        assert_eq!(odd_size(5), 5);
+        assert_eq!(odd_size(0), 1);
+        assert_eq!(odd_size(-3), 1);
+    }
+
+    #[test]
+    fn empty_or_ragged_kernels_are_rejected() {
+        for kernel in [
+            vec![],
+            vec![vec![]],
+            vec![vec![1.0, 2.0], vec![3.0]],
+            vec![vec![1.0], vec![2.0, 3.0]],
+        ] {
+            assert!(convolve(&uniform(10.0), &kernel_params(kernel)).is_err());
+        }
+    }
+
+    #[test]
+    fn identity_kernel_keeps_the_frame() {
+        let frame = Mat::from_slice_2d(&[[1u8, 2, 3], [4, 5, 6], [7, 8, 9]]).unwrap();
+        let params = kernel_params(vec![vec![0.0, 0.0, 0.0], vec![0.0, 1.0, 0.0], vec![0.0; 3]]);
+        let result = convolve(&frame, &params).unwrap();
+        assert_eq!(result.data_bytes().unwrap(), frame.data_bytes().unwrap());
+    }
+
+    #[test]
+    fn non_square_kernels_are_accepted() {
+        let params = kernel_params(vec![vec![1.0, 1.0, 1.0]]);
+        let result = convolve(&uniform(10.0), &params).unwrap();
+        assert_eq!(*result.at_2d::<u8>(2, 2).unwrap(), 30);
+    }
+
+    #[test]
+    fn normalize_divides_by_the_kernel_sum() {
+        let params = ConvolutionParams {
+            kernel: vec![vec![1.0; 3]; 3],
+            normalize: true,
+            delta: 5.0,
+        };
+        let result = convolve(&uniform(10.0), &params).unwrap();
+        assert_eq!(*result.at_2d::<u8>(2, 2).unwrap(), 15);
+
+        // 合計が0のカーネルはそのまま使う
+        let params = ConvolutionParams {
+            kernel: vec![vec![-1.0, 0.0, 1.0]],
+            normalize: true,
+            delta: 0.0,
+        };
+        let result = convolve(&uniform(10.0), &params).unwrap();
+        assert_eq!(*result.at_2d::<u8>(2, 2).unwrap(), 0);
+    }
+}
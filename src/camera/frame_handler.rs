@@ -1,85 +1,202 @@
-use crate::camera::{haar_like, text, utils};
-use opencv::core::{flip, Mat, Point, Rect, Scalar, Vector, BORDER_DEFAULT};
-use opencv::{dnn_superres, imgproc, prelude::*, ximgproc, xphoto};
+use crate::camera::aruco::{self, ArucoCache, ArucoParams};
+use crate::camera::calibration::{self, UndistortCache, UndistortParams};
+use crate::camera::cascade::{self, CascadeCache, CascadeFlag, CascadeParams, Detection};
+use crate::camera::codes::{self, CodeReaderParams, CodeReaderState};
+use crate::camera::color_space::{self, Channel, ChannelParams, ColorSpaceParams};
+use crate::camera::color_tracker::{self, ColorTrackerParams, TrackerOutput, TrackerState};
+use crate::camera::contours::{self, ContourParams, ContourStats};
+use crate::camera::edges::{
+    self, CannyParams, GradientOperator, GradientParams, OrientationParams,
+};
+use crate::camera::face_detector::{self, FaceDetectorCache, FaceDetectorParams};
+use crate::camera::filters::{
+    self, BlurParams, ConvolutionParams, MorphologyParams, SharpenParams,
+};
+use crate::camera::geometry::{
+    self, AffineParams, CropParams, FlipParams, LetterboxParams, PerspectiveParams, ResizeParams,
+    RotateParams,
+};
+use crate::camera::haar_like::{HaarOutput, HaarParams};
+use crate::camera::handler_context::HandlerContext;
+use crate::camera::hough::{self, HoughCircleParams, HoughLineParams};
+use crate::camera::pixel_format::PixelFormat;
+use crate::camera::superpixel::{self, SuperpixelCache, SuperpixelParams};
+use crate::camera::superres::{self, SuperResAlgorithm, SuperResCache, SuperResParams};
+use crate::camera::threshold::{self, ThresholdParams};
+use crate::camera::tone::{
+    self, BrightnessParams, ContrastParams, EqualizeParams, GammaParams, ToneCurveCache,
+    ToneCurveParams,
+};
+use crate::camera::{composite, haar_like, text, utils};
+use opencv::core::{flip, Mat, Point, Scalar, BORDER_DEFAULT};
+use opencv::{imgproc, prelude::*, ximgproc, xphoto};
 use std::collections::HashMap;
 
-pub type FrameHandler = fn(frame: &Mat) -> Result<Mat, opencv::Error>;
+pub type FrameHandler = fn(frame: &Mat, context: &mut HandlerContext) -> Result<Mat, opencv::Error>;
 
-pub fn search_frame_handler(mode: &str) -> Option<FrameHandler> {
+// 出力形式。SameAsInputは入力された形式をそのまま出力する
+#[derive(Debug, Clone, Copy)]
+pub enum Produces {
+    SameAsInput,
+    Format(PixelFormat),
+}
+
+#[derive(Clone, Copy)]
+pub struct FrameHandlerSpec {
+    pub handler: FrameHandler,
+    pub accepts: &'static [PixelFormat], // 先頭が変換時の優先形式
+    pub produces: Produces,
+}
+
+const ANY: &[PixelFormat] = &[
+    PixelFormat::Bgr8,
+    PixelFormat::Gray8,
+    PixelFormat::Bgra,
+    PixelFormat::Float,
+];
+const BGR: &[PixelFormat] = &[PixelFormat::Bgr8];
+const GRAY: &[PixelFormat] = &[PixelFormat::Gray8];
+const BGR_GRAY: &[PixelFormat] = &[PixelFormat::Bgr8, PixelFormat::Gray8];
+
+pub fn search_frame_handler(mode: &str) -> Option<FrameHandlerSpec> {
     let frame_handler = create_frame_handler_map();
     frame_handler.get(mode).copied()
 }
 
-fn create_frame_handler_map() -> HashMap<&'static str, FrameHandler> {
-    let mut frame_handler_map: HashMap<&str, FrameHandler> = HashMap::new();
-    frame_handler_map.insert("color", convert_to_color);
-    frame_handler_map.insert("gray", convert_to_gray);
-    frame_handler_map.insert("canny", convert_to_canny);
-    frame_handler_map.insert("white_balance", convert_to_white_balance);
-    frame_handler_map.insert("filter", convert_to_bilateral_filter);
-    frame_handler_map.insert("superpixel", convert_to_superpixel);
-    frame_handler_map.insert("countours", convert_to_countours);
-    frame_handler_map.insert("fsrcnn", convert_to_fsrcnn);
-    frame_handler_map.insert("espcn", convert_to_espcn);
-    frame_handler_map.insert("binary", convert_to_binary);
-    frame_handler_map.insert("haar_like", convert_to_haar_like);
-    frame_handler_map.insert("removed_red", convert_to_removed_red);
-    frame_handler_map.insert("removed_blue", convert_to_removed_blue);
-    frame_handler_map.insert("removed_green", convert_to_removed_green);
-    frame_handler_map.insert("text", convert_to_text_frame);
-    frame_handler_map.insert("face", convert_to_detect_faces);
-    frame_handler_map.insert("eye", convert_to_detect_eye);
-    frame_handler_map.insert("reverse", convert_to_reverse);
-    frame_handler_map
+fn create_frame_handler_map() -> HashMap<&'static str, FrameHandlerSpec> {
+    use PixelFormat::{Bgr8, Float, Gray8};
+    use Produces::{Format, SameAsInput};
+    // (モード名, ハンドラ, 受け付ける形式, 出力形式)
+    let specs: Vec<(&str, FrameHandler, &[PixelFormat], Produces)> = vec![
+        ("color", convert_to_color, ANY, SameAsInput),
+        ("gray", convert_to_gray, BGR_GRAY, Format(Gray8)),
+        ("canny", convert_to_canny, GRAY, Format(Gray8)),
+        ("sobel", convert_to_sobel, BGR_GRAY, Format(Float)),
+        ("scharr", convert_to_scharr, BGR_GRAY, Format(Float)),
+        ("laplacian", convert_to_laplacian, BGR_GRAY, Format(Float)),
+        (
+            "gradient_orientation",
+            convert_to_gradient_orientation,
+            BGR_GRAY,
+            Format(Bgr8),
+        ),
+        ("white_balance", convert_to_white_balance, BGR, Format(Bgr8)),
+        ("equalize", convert_to_equalize, BGR_GRAY, SameAsInput),
+        ("gamma", convert_to_gamma, BGR_GRAY, SameAsInput),
+        ("brightness", convert_to_brightness, BGR_GRAY, SameAsInput),
+        ("contrast", convert_to_contrast, BGR_GRAY, SameAsInput),
+        ("tone_curve", convert_to_tone_curve, BGR_GRAY, SameAsInput),
+        ("filter", convert_to_bilateral_filter, BGR_GRAY, SameAsInput),
+        ("blur", convert_to_blur, BGR_GRAY, SameAsInput),
+        ("sharpen", convert_to_sharpen, BGR_GRAY, SameAsInput),
+        ("morphology", convert_to_morphology, BGR_GRAY, SameAsInput),
+        ("convolve", convert_to_convolve, BGR_GRAY, SameAsInput),
+        ("superpixel", convert_to_superpixel, BGR, Format(Bgr8)),
+        ("countours", convert_to_countours, BGR_GRAY, Format(Bgr8)),
+        ("contours", convert_to_countours, BGR_GRAY, Format(Bgr8)),
+        (
+            "hough_lines",
+            convert_to_hough_lines,
+            BGR_GRAY,
+            Format(Bgr8),
+        ),
+        (
+            "hough_circles",
+            convert_to_hough_circles,
+            BGR_GRAY,
+            Format(Bgr8),
+        ),
+        ("code_reader", convert_to_code_reader, BGR_GRAY, SameAsInput),
+        ("aruco", convert_to_aruco, BGR_GRAY, Format(Bgr8)),
+        ("undistort", convert_to_undistort, BGR_GRAY, SameAsInput),
+        ("crop", convert_to_crop, ANY, SameAsInput),
+        ("resize", convert_to_resize, ANY, SameAsInput),
+        ("rotate", convert_to_rotate, ANY, SameAsInput),
+        ("flip", convert_to_flip, ANY, SameAsInput),
+        ("affine", convert_to_affine, ANY, SameAsInput),
+        ("perspective", convert_to_perspective, ANY, SameAsInput),
+        ("letterbox", convert_to_letterbox, ANY, SameAsInput),
+        ("fsrcnn", convert_to_fsrcnn, BGR, Format(Bgr8)),
+        ("espcn", convert_to_espcn, BGR, Format(Bgr8)),
+        ("superres", convert_to_superres, BGR, Format(Bgr8)),
+        ("binary", convert_to_binary, GRAY, Format(Gray8)),
+        ("haar_like", convert_to_haar_like, BGR_GRAY, Format(Bgr8)),
+        ("removed_red", convert_to_removed_red, BGR, Format(Bgr8)),
+        ("removed_blue", convert_to_removed_blue, BGR, Format(Bgr8)),
+        ("removed_green", convert_to_removed_green, BGR, Format(Bgr8)),
+        ("color_space", convert_to_color_space, BGR, Format(Bgr8)),
+        ("channels", convert_to_channels, BGR, Format(Bgr8)),
+        ("color_tracker", convert_to_color_tracker, BGR, Format(Bgr8)),
+        ("text", convert_to_text_frame, BGR_GRAY, SameAsInput),
+        ("face", convert_to_detect_faces, BGR_GRAY, SameAsInput),
+        ("eye", convert_to_detect_eye, BGR_GRAY, SameAsInput),
+        ("cascade", convert_to_cascade, BGR_GRAY, SameAsInput),
+        ("face_parts", convert_to_face_parts, BGR_GRAY, SameAsInput),
+        ("reverse", convert_to_reverse, ANY, SameAsInput),
+    ];
+    specs
+        .into_iter()
+        .map(|(mode, handler, accepts, produces)| {
+            let spec = FrameHandlerSpec {
+                handler,
+                accepts,
+                produces,
+            };
+            (mode, spec)
+        })
+        .collect()
 }
 
 // グレースケール
-pub fn convert_to_gray(frame: &Mat) -> Result<Mat, opencv::Error> {
-    if utils::is_grayscale(frame)? {
-        return Ok(frame.clone());
-    }
-    let mut gray_frame = Mat::default();
-    imgproc::cvt_color(frame, &mut gray_frame, imgproc::COLOR_BGR2GRAY, 0)?;
-    Ok(gray_frame)
-}
-
-// cannyエッジ検出
-pub fn convert_to_canny(frame: &Mat) -> Result<Mat, opencv::Error> {
-    // THRESHOLD1 <= エッジとして判定 <= THRESHOLD2
-    const THRESHOLD1: f64 = 100.0;
-    const THRESHOLD2: f64 = 200.0;
-    // エッジ検出時のソーベル演算子のサイズ(3, 5, 7)
-    const APERTURE_SIZE: i32 = 3;
-    // TRUE -> L2ノルム, FALSE -> L1ノルム
-    const L2_GRADIENT: bool = false;
-
-    let mut canny_frame = match utils::is_grayscale(frame)? {
-        true => frame.clone(),
-        false => convert_to_gray(frame)?,
-    };
+pub fn convert_to_gray(frame: &Mat, _context: &mut HandlerContext) -> Result<Mat, opencv::Error> {
+    utils::to_gray(frame)
+}
 
-    imgproc::canny(
-        &frame,
-        &mut canny_frame,
-        THRESHOLD1,
-        THRESHOLD2,
-        APERTURE_SIZE,
-        L2_GRADIENT,
-    )?;
+// cannyエッジ検出(閾値は固定、または輝度の中央値から自動で決める)
+pub fn convert_to_canny(frame: &Mat, context: &mut HandlerContext) -> Result<Mat, opencv::Error> {
+    let params: CannyParams = context.params()?;
+    let (canny_frame, [threshold1, threshold2]) = edges::canny(frame, &params)?;
+    context.set_metadata(serde_json::json!({ "threshold1": threshold1, "threshold2": threshold2 }));
     Ok(canny_frame)
 }
 
+// 勾配の強さ(Sobel)
+fn convert_to_sobel(frame: &Mat, context: &mut HandlerContext) -> Result<Mat, opencv::Error> {
+    let params: GradientParams = context.params()?;
+    edges::magnitude(frame, GradientOperator::Sobel, &params)
+}
+
+// 勾配の強さ(Scharr)
+fn convert_to_scharr(frame: &Mat, context: &mut HandlerContext) -> Result<Mat, opencv::Error> {
+    let params: GradientParams = context.params()?;
+    edges::magnitude(frame, GradientOperator::Scharr, &params)
+}
+
+// 2次微分の絶対値(Laplacian)
+fn convert_to_laplacian(frame: &Mat, context: &mut HandlerContext) -> Result<Mat, opencv::Error> {
+    let params: GradientParams = context.params()?;
+    edges::magnitude(frame, GradientOperator::Laplacian, &params)
+}
+
+// 勾配の方向を色で表示する
+fn convert_to_gradient_orientation(
+    frame: &Mat,
+    context: &mut HandlerContext,
+) -> Result<Mat, opencv::Error> {
+    let params: OrientationParams = context.params()?;
+    edges::orientation(frame, &params)
+}
+
 // そのまま
-pub fn convert_to_color(frame: &Mat) -> Result<Mat, opencv::Error> {
+pub fn convert_to_color(frame: &Mat, _context: &mut HandlerContext) -> Result<Mat, opencv::Error> {
     Ok(frame.clone())
 }
 
 // 色調補正(白をより現実の色に変える)
-pub fn convert_to_white_balance(frame: &Mat) -> Result<Mat, opencv::Error> {
-    if utils::is_grayscale(frame)? {
-        return Ok(frame.clone());
-    }
-
+pub fn convert_to_white_balance(
+    frame: &Mat,
+    _context: &mut HandlerContext,
+) -> Result<Mat, opencv::Error> {
     let mut white_balance_frame = Mat::default();
     let mut grayworld_wb = xphoto::create_grayworld_wb()?;
     grayworld_wb.balance_white(&frame, &mut white_balance_frame)?;
@@ -87,11 +204,10 @@ pub fn convert_to_white_balance(frame: &Mat) -> Result<Mat, opencv::Error> {
 }
 
 // ぼかし(ノイズ除去。エッジ検出と併用可能)
-pub fn convert_to_bilateral_filter(frame: &Mat) -> Result<Mat, opencv::Error> {
-    if utils::is_grayscale(frame)? {
-        return Ok(frame.clone());
-    }
-
+pub fn convert_to_bilateral_filter(
+    frame: &Mat,
+    _context: &mut HandlerContext,
+) -> Result<Mat, opencv::Error> {
     let mut filtered_frame = Mat::default();
     imgproc::bilateral_filter(
         &frame,
@@ -104,135 +220,280 @@ pub fn convert_to_bilateral_filter(frame: &Mat) -> Result<Mat, opencv::Error> {
     Ok(filtered_frame)
 }
 
+// ぼかし(gaussian, median, box, box_normalized)
+fn convert_to_blur(frame: &Mat, context: &mut HandlerContext) -> Result<Mat, opencv::Error> {
+    let params: BlurParams = context.params()?;
+    filters::blur(frame, &params)
+}
+
+// アンシャープマスクによるシャープ化
+fn convert_to_sharpen(frame: &Mat, context: &mut HandlerContext) -> Result<Mat, opencv::Error> {
+    let params: SharpenParams = context.params()?;
+    filters::sharpen(frame, &params)
+}
+
+// モルフォロジー変換(収縮, 膨張, オープニング, クロージングなど)
+fn convert_to_morphology(frame: &Mat, context: &mut HandlerContext) -> Result<Mat, opencv::Error> {
+    let params: MorphologyParams = context.params()?;
+    filters::morphology(frame, &params)
+}
+
+// パラメータで指定したカーネルによる畳み込み
+fn convert_to_convolve(frame: &Mat, context: &mut HandlerContext) -> Result<Mat, opencv::Error> {
+    let params: ConvolutionParams = context.params()?;
+    filters::convolve(frame, &params)
+}
+
 // スーパーピクセル
-pub fn convert_to_superpixel(frame: &Mat) -> Result<Mat, opencv::Error> {
-    let mut superpixeld_frame = Mat::default();
-    let mut slic = ximgproc::create_superpixel_slic(frame, ximgproc::SLIC, 25, 100.0)?;
-    slic.iterate(5)?;
-    slic.get_labels(&mut superpixeld_frame)?;
-    slic.get_label_contour_mask(&mut superpixeld_frame, true)?;
-    Ok(superpixeld_frame)
-}
-
-// 輪郭
-pub fn convert_to_countours(frame: &Mat) -> Result<Mat, opencv::Error> {
-    if utils::is_grayscale(frame)? {
-        return Ok(frame.clone());
+pub fn convert_to_superpixel(
+    frame: &Mat,
+    context: &mut HandlerContext,
+) -> Result<Mat, opencv::Error> {
+    let params: SuperpixelParams = context.params()?;
+    let segmentation = superpixel::segment(frame, context.state::<SuperpixelCache>(), &params)?;
+    let mut metadata = serde_json::json!({ "count": segmentation.count });
+    if params.label_map {
+        metadata["label_map"] = superpixel::label_map(&segmentation.labels, params.label_map_step)?;
     }
+    context.set_metadata(metadata);
+    superpixel::render(frame, &segmentation, &params)
+}
 
-    let mut result = frame.clone();
-    let mut contours = Vector::<Vector<Point>>::new();
-    let edges = convert_to_canny(frame)?;
-    imgproc::find_contours(
-        &edges,                       // 入力画像（エッジ検出後の画像）
-        &mut contours,                // 検出された輪郭が格納されるベクター
-        imgproc::RETR_EXTERNAL,       // 輪郭の検出モード（最外輪郭のみ）
-        imgproc::CHAIN_APPROX_SIMPLE, // 輪郭の近似方法（簡略化された近似）
-        Point::new(0, 0),             // 検出のオフセット（画像全体）
-    )?;
+// 輪郭の検出と形状の判定
+pub fn convert_to_countours(
+    frame: &Mat,
+    context: &mut HandlerContext,
+) -> Result<Mat, opencv::Error> {
+    let params: ContourParams = context.params()?;
+    let found = contours::find_contours(frame, &params)?;
+    let stats: Vec<&ContourStats> = found.iter().map(|contour| &contour.stats).collect();
+    context.set_metadata(serde_json::json!({ "contours": stats }));
+    contours::draw_contours(frame, &found, &params.overlays)
+}
 
-    imgproc::draw_contours(
-        &mut result,
-        &contours,
-        -1,                                // 全ての輪郭を描画
-        Scalar::new(0.0, 255.0, 0.0, 0.0), // 青色で描画
-        2,                                 // 線の太さ
-        imgproc::LINE_8,                   // 線のタイプ
-        &frame,                            // 階層情報
-        0,                                 // 階層レベル 2
-        Point::new(0, 0),                  // オフセット
-    )?;
-    Ok(result)
+// ハフ変換による直線・線分の検出
+fn convert_to_hough_lines(frame: &Mat, context: &mut HandlerContext) -> Result<Mat, opencv::Error> {
+    let params: HoughLineParams = context.params()?;
+    let lines = hough::detect_lines(frame, &params)?;
+    context.set_metadata(serde_json::json!({ "lines": lines }));
+    hough::draw_lines(frame, &lines, &params)
 }
 
-// 超解像処理(FSRCNN)
-pub fn convert_to_fsrcnn(frame: &Mat) -> Result<Mat, opencv::Error> {
-    if utils::is_grayscale(frame)? {
-        return Ok(frame.clone());
+// ハフ変換による円の検出
+fn convert_to_hough_circles(
+    frame: &Mat,
+    context: &mut HandlerContext,
+) -> Result<Mat, opencv::Error> {
+    let params: HoughCircleParams = context.params()?;
+    let circles = hough::detect_circles(frame, &params)?;
+    context.set_metadata(serde_json::json!({ "circles": circles }));
+    hough::draw_circles(frame, &circles, &params)
+}
+
+// QRコード・バーコードの読み取り。新しく読み取ったコードはイベントとして送る
+fn convert_to_code_reader(frame: &Mat, context: &mut HandlerContext) -> Result<Mat, opencv::Error> {
+    let params: CodeReaderParams = context.params()?;
+    let state = context.state::<CodeReaderState>();
+    let decoded = codes::read_codes(frame, state, &params)?;
+    let window = params.dedup_window()?;
+    for code in state.filter_new(&decoded, window) {
+        context.push_event(codes::code_event(code));
     }
+    context.set_metadata(serde_json::json!({ "codes": decoded }));
+    codes::draw_codes(frame, &decoded, &params)
+}
 
-    let mut result = Mat::default();
-    let mut sr = dnn_superres::DnnSuperResImpl::create()?;
-    sr.read_model("model/fsrcnn.pb")?;
-    sr.set_model("fsrcnn", 2)?;
+// ArUcoマーカー(とChArUcoボード)の検出。内部パラメータがあれば姿勢も推定する
+fn convert_to_aruco(frame: &Mat, context: &mut HandlerContext) -> Result<Mat, opencv::Error> {
+    let params: ArucoParams = context.params()?;
+    let result = aruco::detect(frame, context.state::<ArucoCache>(), &params)?;
+    context.set_metadata(serde_json::json!({ "markers": result.markers, "board": result.board }));
+    aruco::draw(frame, &result, &params)
+}
 
-    sr.upsample(&frame, &mut result)?;
-    Ok(result)
+// 保存した内部パラメータでレンズの歪みを補正する
+fn convert_to_undistort(frame: &Mat, context: &mut HandlerContext) -> Result<Mat, opencv::Error> {
+    let params: UndistortParams = context.params()?;
+    calibration::undistort(frame, context.state::<UndistortCache>(), &params)
 }
 
-// 超解像処理(ESPCN)
-pub fn convert_to_espcn(frame: &Mat) -> Result<Mat, opencv::Error> {
-    if utils::is_grayscale(frame)? {
-        return Ok(frame.clone());
-    }
+// 範囲を切り取る。切り取った範囲(画素)をメタデータとする
+fn convert_to_crop(frame: &Mat, context: &mut HandlerContext) -> Result<Mat, opencv::Error> {
+    let params: CropParams = context.params()?;
+    let rect = geometry::crop_rect(frame.size()?, &params)?;
+    context.set_metadata(Detection::from(rect));
+    geometry::crop(frame, rect)
+}
+
+fn convert_to_resize(frame: &Mat, context: &mut HandlerContext) -> Result<Mat, opencv::Error> {
+    let params: ResizeParams = context.params()?;
+    geometry::resize(frame, &params)
+}
+
+fn convert_to_rotate(frame: &Mat, context: &mut HandlerContext) -> Result<Mat, opencv::Error> {
+    let params: RotateParams = context.params()?;
+    geometry::rotate(frame, &params)
+}
 
-    let mut result = Mat::default();
-    let mut sr = dnn_superres::DnnSuperResImpl::create()?;
-    sr.read_model("model/espcn.pb")?;
-    sr.set_model("espcn", 2)?;
+// 上下・上下左右の反転(左右のみの場合はreverse)
+fn convert_to_flip(frame: &Mat, context: &mut HandlerContext) -> Result<Mat, opencv::Error> {
+    let params: FlipParams = context.params()?;
+    geometry::flip(frame, params.axis)
+}
+
+fn convert_to_affine(frame: &Mat, context: &mut HandlerContext) -> Result<Mat, opencv::Error> {
+    let params: AffineParams = context.params()?;
+    geometry::affine(frame, &params)
+}
 
-    sr.upsample(&frame, &mut result)?;
+// 四隅を指定した四角形を長方形に引き伸ばす(書類や画面の正面化など)
+fn convert_to_perspective(frame: &Mat, context: &mut HandlerContext) -> Result<Mat, opencv::Error> {
+    let params: PerspectiveParams = context.params()?;
+    geometry::perspective(frame, &params)
+}
+
+// 縦横比を保って固定の大きさに収める。元の座標に戻すための倍率と余白をメタデータとする
+fn convert_to_letterbox(frame: &Mat, context: &mut HandlerContext) -> Result<Mat, opencv::Error> {
+    let params: LetterboxParams = context.params()?;
+    let (padded, letterbox) = geometry::letterbox(frame, &params)?;
+    context.set_metadata(letterbox);
+    Ok(padded)
+}
+
+// ヒストグラム平坦化(カラー画像は輝度のみ)
+fn convert_to_equalize(frame: &Mat, context: &mut HandlerContext) -> Result<Mat, opencv::Error> {
+    let params: EqualizeParams = context.params()?;
+    tone::equalize(frame, &params)
+}
+
+fn convert_to_gamma(frame: &Mat, context: &mut HandlerContext) -> Result<Mat, opencv::Error> {
+    let params: GammaParams = context.params()?;
+    tone::apply_lut(frame, &tone::gamma_lut(&params)?)
+}
+
+fn convert_to_brightness(frame: &Mat, context: &mut HandlerContext) -> Result<Mat, opencv::Error> {
+    let params: BrightnessParams = context.params()?;
+    tone::apply_lut(frame, &tone::brightness_lut(&params)?)
+}
+
+fn convert_to_contrast(frame: &Mat, context: &mut HandlerContext) -> Result<Mat, opencv::Error> {
+    let params: ContrastParams = context.params()?;
+    tone::apply_lut(frame, &tone::contrast_lut(&params)?)
+}
+
+// 制御点を通るトーンカーブ(ルックアップテーブルに変換して適用する)
+fn convert_to_tone_curve(frame: &Mat, context: &mut HandlerContext) -> Result<Mat, opencv::Error> {
+    let params: ToneCurveParams = context.params()?;
+    let lut = context.state::<ToneCurveCache>().get(&params)?;
+    tone::apply_lut(frame, lut)
+}
+
+// 超解像処理(アルゴリズムと倍率をパラメータで指定する)
+fn convert_to_superres(frame: &Mat, context: &mut HandlerContext) -> Result<Mat, opencv::Error> {
+    let params: SuperResParams = context.params()?;
+    upsample_by_superres(frame, context, params)
+}
+
+// 超解像処理(FSRCNN)
+pub fn convert_to_fsrcnn(frame: &Mat, context: &mut HandlerContext) -> Result<Mat, opencv::Error> {
+    let defaults = SuperResParams {
+        algorithm: SuperResAlgorithm::Fsrcnn,
+        ..Default::default()
+    };
+    let params = context.params_or(defaults)?;
+    upsample_by_superres(frame, context, params)
+}
+
+// 超解像処理(ESPCN)
+pub fn convert_to_espcn(frame: &Mat, context: &mut HandlerContext) -> Result<Mat, opencv::Error> {
+    let defaults = SuperResParams {
+        algorithm: SuperResAlgorithm::Espcn,
+        ..Default::default()
+    };
+    let params = context.params_or(defaults)?;
+    upsample_by_superres(frame, context, params)
+}
+
+fn upsample_by_superres(
+    frame: &Mat,
+    context: &mut HandlerContext,
+    params: SuperResParams,
+) -> Result<Mat, opencv::Error> {
+    let (result, info) = superres::upsample(frame, context.state::<SuperResCache>(), &params)?;
+    context.set_metadata(info);
     Ok(result)
 }
 
 // 白黒の二値化
-pub fn convert_to_binary(frame: &Mat) -> Result<Mat, opencv::Error> {
-    const THRESHOLD: f64 = 200.0;
-    const MAX_VALUE: f64 = 255.0;
-    let mut binary_frame = Mat::default();
-
-    imgproc::threshold(
-        &convert_to_gray(&frame)?,
-        &mut binary_frame,
-        THRESHOLD,
-        MAX_VALUE,
-        imgproc::THRESH_BINARY,
-    )?;
+pub fn convert_to_binary(frame: &Mat, context: &mut HandlerContext) -> Result<Mat, opencv::Error> {
+    let params: ThresholdParams = context.params()?;
+    let (binary_frame, computed) = threshold::threshold(frame, &params)?;
+    context.set_metadata(serde_json::json!({ "method": params.method, "threshold": computed }));
     Ok(binary_frame)
 }
 
-pub fn convert_to_haar_like(frame: &Mat) -> Result<Mat, opencv::Error> {
-    // 取得する特徴の数, frameを横に区切る数
-    const DIVISIONS: i32 = 40;
-    const RECT_HEIGHT: i32 = 15;
-    const BLACK: Scalar = Scalar::new(0.0, 0.0, 0.0, 0.0);
-    let mut haar_like_frame = frame.clone();
-    let haar_like_vec = haar_like::calc_haar_like_vec(&frame, DIVISIONS, RECT_HEIGHT)?;
-    let width = frame.cols();
-    let height = frame.rows();
-    let width_step = width / DIVISIONS as i32;
-
-    for i in 0..DIVISIONS {
-        let x = width_step * i;
-        let y = (haar_like_vec[i as usize] * height as f64) as i32;
-        let rect = Rect::new(x, y, width_step, 1);
-        let _ = imgproc::rectangle(&mut haar_like_frame, rect, BLACK, 1, imgproc::LINE_8, 0);
+// Haar-like特徴の応答をヒートマップ、または応答が強い窓として表示する
+pub fn convert_to_haar_like(
+    frame: &Mat,
+    context: &mut HandlerContext,
+) -> Result<Mat, opencv::Error> {
+    let params: HaarParams = context.params()?;
+    let feature_maps = haar_like::compute_features(frame, &params)?;
+    let features: Vec<serde_json::Value> = feature_maps
+        .iter()
+        .map(|feature_map| feature_map.to_json(params.responses))
+        .collect();
+    context.set_metadata(serde_json::json!({ "features": features }));
+    match params.output {
+        HaarOutput::Heatmap => haar_like::draw_heatmap(frame, &feature_maps, params.alpha),
+        HaarOutput::Strongest => haar_like::draw_strongest(frame, &feature_maps, params.top),
     }
-    Ok(haar_like_frame)
 }
 
-fn convert_to_removed_red(frame: &Mat) -> Result<Mat, opencv::Error> {
-    if utils::is_grayscale(frame)? {
-        return Ok(frame.clone());
-    }
-    utils::remove_color_channel(frame, 2)
+// 色空間を変換し、チャンネルを並べて(または1チャンネルを疑似カラーで)表示する
+fn convert_to_color_space(frame: &Mat, context: &mut HandlerContext) -> Result<Mat, opencv::Error> {
+    let params: ColorSpaceParams = context.params()?;
+    color_space::view_color_space(frame, &params)
 }
 
-fn convert_to_removed_blue(frame: &Mat) -> Result<Mat, opencv::Error> {
-    if utils::is_grayscale(frame)? {
-        return Ok(frame.clone());
-    }
-    utils::remove_color_channel(frame, 0)
+// チャンネルの入れ替え、ゲイン・オフセット、除去
+fn convert_to_channels(frame: &Mat, context: &mut HandlerContext) -> Result<Mat, opencv::Error> {
+    let params: ChannelParams = context.params()?;
+    color_space::apply_channel_operations(frame, &params)
 }
 
-fn convert_to_removed_green(frame: &Mat) -> Result<Mat, opencv::Error> {
-    if utils::is_grayscale(frame)? {
-        return Ok(frame.clone());
-    }
-    utils::remove_color_channel(frame, 1)
+fn convert_to_removed_red(frame: &Mat, context: &mut HandlerContext) -> Result<Mat, opencv::Error> {
+    remove_channel(frame, context, Channel::Red)
+}
+
+fn convert_to_removed_blue(
+    frame: &Mat,
+    context: &mut HandlerContext,
+) -> Result<Mat, opencv::Error> {
+    remove_channel(frame, context, Channel::Blue)
+}
+
+fn convert_to_removed_green(
+    frame: &Mat,
+    context: &mut HandlerContext,
+) -> Result<Mat, opencv::Error> {
+    remove_channel(frame, context, Channel::Green)
 }
 
-fn convert_to_text_frame(frame: &Mat) -> Result<Mat, opencv::Error> {
+fn remove_channel(
+    frame: &Mat,
+    context: &mut HandlerContext,
+    channel: Channel,
+) -> Result<Mat, opencv::Error> {
+    let defaults = ChannelParams {
+        remove: vec![channel],
+        ..Default::default()
+    };
+    let params = context.params_or(defaults)?;
+    color_space::apply_channel_operations(frame, &params)
+}
+
+fn convert_to_text_frame(frame: &Mat, _context: &mut HandlerContext) -> Result<Mat, opencv::Error> {
     let text: String = text::extract_text(frame)?;
     let mut result: Mat = frame.clone();
 
@@ -261,19 +522,84 @@ fn convert_to_text_frame(frame: &Mat) -> Result<Mat, opencv::Error> {
     Ok(result)
 }
 
-fn convert_to_detect_faces(frame: &Mat) -> Result<Mat, opencv::Error> {
-    const FACE_MODEL_PATH: &str = "model/haarcascade_frontalface_default.xml";
-    let rect_green = Scalar::new(0.0, 255.0, 0.0, 0.0);
-    utils::detect_object(&frame, FACE_MODEL_PATH, rect_green)
+// カスケード分類器による物体検出(モデル・検出パラメータをノードのパラメータで指定)
+fn convert_to_cascade(frame: &Mat, context: &mut HandlerContext) -> Result<Mat, opencv::Error> {
+    let params: CascadeParams = context.params()?;
+    detect_by_cascade(frame, context, params)
+}
+
+fn convert_to_detect_faces(
+    frame: &Mat,
+    context: &mut HandlerContext,
+) -> Result<Mat, opencv::Error> {
+    let defaults = CascadeParams {
+        model: "haarcascade_frontalface_default.xml".to_string(),
+        ..legacy_cascade_params()
+    };
+    let params = context.params_or(defaults)?;
+    detect_by_cascade(frame, context, params)
 }
 
-fn convert_to_detect_eye(frame: &Mat) -> Result<Mat, opencv::Error> {
-    const EYE_MODEL_PATH: &str = "model/haarcascade_eye.xml";
-    let rect_red = Scalar::new(255.0, 0.0, 0.0, 0.0);
-    utils::detect_object(&frame, EYE_MODEL_PATH, rect_red)
+fn convert_to_detect_eye(frame: &Mat, context: &mut HandlerContext) -> Result<Mat, opencv::Error> {
+    let defaults = CascadeParams {
+        model: "haarcascade_eye.xml".to_string(),
+        color: [255.0, 0.0, 0.0],
+        ..legacy_cascade_params()
+    };
+    let params = context.params_or(defaults)?;
+    detect_by_cascade(frame, context, params)
+}
+
+// face, eyeのデフォルト値(最も大きい物体を1つだけ検出する)
+fn legacy_cascade_params() -> CascadeParams {
+    CascadeParams {
+        min_neighbors: 2,
+        flags: vec![CascadeFlag::FindBiggestObject],
+        min_size: [100, 100],
+        ..Default::default()
+    }
+}
+
+fn detect_by_cascade(
+    frame: &Mat,
+    context: &mut HandlerContext,
+    params: CascadeParams,
+) -> Result<Mat, opencv::Error> {
+    let classifier = context.state::<CascadeCache>().get(&params.model)?;
+    let rects = cascade::detect(frame, classifier, &params)?;
+    let detections: Vec<Detection> = rects.iter().map(|rect| Detection::from(*rect)).collect();
+    context.set_metadata(serde_json::json!({ "model": params.model, "detections": detections }));
+    cascade::draw_detections(frame, &rects, params.color)
+}
+
+// 顔を検出し、その中から目などの部位を探す
+fn convert_to_face_parts(frame: &Mat, context: &mut HandlerContext) -> Result<Mat, opencv::Error> {
+    let params: FaceDetectorParams = context.params()?;
+    let results =
+        face_detector::detect_faces(frame, context.state::<FaceDetectorCache>(), &params)?;
+    context.set_metadata(serde_json::json!({ "faces": results }));
+    face_detector::draw_faces(frame, &results, &params)
+}
+
+// HSVの範囲で色を抽出し、大きい領域の位置と軌跡を表示する
+fn convert_to_color_tracker(
+    frame: &Mat,
+    context: &mut HandlerContext,
+) -> Result<Mat, opencv::Error> {
+    let params: ColorTrackerParams = context.params()?;
+    let mask = color_tracker::create_mask(frame, &params)?;
+    let blobs = color_tracker::find_blobs(&mask, &params)?;
+    let state = context.state::<TrackerState>();
+    state.update(&blobs, params.trail);
+    let result = match params.output {
+        TrackerOutput::Overlay => color_tracker::draw_blobs(frame, &blobs, state, &params)?,
+        TrackerOutput::Mask => composite::to_bgr(&mask)?,
+    };
+    context.set_metadata(serde_json::json!({ "blobs": blobs }));
+    Ok(result)
 }
 
-fn convert_to_reverse(frame: &Mat) -> Result<Mat, opencv::Error> {
+fn convert_to_reverse(frame: &Mat, _context: &mut HandlerContext) -> Result<Mat, opencv::Error> {
     let mut reversed_frame = Mat::default();
     let _ = flip(&frame, &mut reversed_frame, 1);
     Ok(reversed_frame)
@@ -0,0 +1,554 @@
+use crate::camera::utils;
+use opencv::core::{self, Mat, Point2f, Rect, Scalar, Size, StsBadArg, Vector};
+use opencv::{imgproc, prelude::*};
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Interpolation {
+    Nearest,
+    #[default]
+    Linear,
+    Cubic,
+    Area, // 縮小向き
+    Lanczos,
+}
+
+impl Interpolation {
+    pub fn flag(&self) -> i32 {
+        match self {
+            Interpolation::Nearest => imgproc::INTER_NEAREST,
+            Interpolation::Linear => imgproc::INTER_LINEAR,
+            Interpolation::Cubic => imgproc::INTER_CUBIC,
+            Interpolation::Area => imgproc::INTER_AREA,
+            Interpolation::Lanczos => imgproc::INTER_LANCZOS4,
+        }
+    }
+}
+
+fn border_color(color: &[f64; 3]) -> Scalar {
+    Scalar::new(color[0], color[1], color[2], 0.0)
+}
+
+/*
+* {"x": 0.25, "y": 0.25, "width": 0.5, "height": 0.5, "relative": true}
+* relative -> trueの場合は画像の大きさに対する比率(0.0〜1.0)、falseの場合は画素
+* 画像からはみ出した部分は切り詰める
+*/
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct CropParams {
+    pub x: f64,
+    pub y: f64,
+    pub width: f64,
+    pub height: f64,
+    pub relative: bool,
+}
+
+impl Default for CropParams {
+    fn default() -> Self {
+        Self {
+            x: 0.0,
+            y: 0.0,
+            width: 1.0,
+            height: 1.0,
+            relative: true,
+        }
+    }
+}
+
+// 切り取った範囲(画素)を返す
+pub fn crop_rect(size: Size, params: &CropParams) -> Result<Rect, opencv::Error> {
+    let (scale_x, scale_y) = match params.relative {
+        true => (size.width as f64, size.height as f64),
+        false => (1.0, 1.0),
+    };
+    let clamp_x = |value: f64| ((value * scale_x).round() as i32).clamp(0, size.width);
+    let clamp_y = |value: f64| ((value * scale_y).round() as i32).clamp(0, size.height);
+    let (left, right) = (clamp_x(params.x), clamp_x(params.x + params.width));
+    let (top, bottom) = (clamp_y(params.y), clamp_y(params.y + params.height));
+    if right <= left || bottom <= top {
+        return Err(opencv::Error::new(StsBadArg, "crop area is empty"));
+    }
+    Ok(Rect::new(left, top, right - left, bottom - top))
+}
+
+pub fn crop(frame: &Mat, rect: Rect) -> Result<Mat, opencv::Error> {
+    Mat::roi(frame, rect)?.try_clone()
+}
+
+/*
+* {"width": 640, "height": 0, "interpolation": "area"}
+* width, heightの一方が0の場合は縦横比を保って他方から求める。両方0の場合はscaleで拡大・縮小する
+* 出力の幅・高さは4096以下
+*/
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct ResizeParams {
+    pub width: i32,
+    pub height: i32,
+    pub scale: f64,
+    pub interpolation: Interpolation,
+}
+
+impl Default for ResizeParams {
+    fn default() -> Self {
+        Self {
+            width: 0,
+            height: 0,
+            scale: 0.5,
+            interpolation: Interpolation::Linear,
+        }
+    }
+}
+
+pub fn resize(frame: &Mat, params: &ResizeParams) -> Result<Mat, opencv::Error> {
+    let size = frame.size()?;
+    let (width, height) = (size.width as f64, size.height as f64);
+    let target = match (params.width, params.height) {
+        (0, 0) => Size::new(
+            (width * params.scale).round() as i32,
+            (height * params.scale).round() as i32,
+        ),
+        (target_width, 0) => Size::new(
+            target_width,
+            (height * target_width as f64 / width).round() as i32,
+        ),
+        (0, target_height) => Size::new(
+            (width * target_height as f64 / height).round() as i32,
+            target_height,
+        ),
+        (target_width, target_height) => Size::new(target_width, target_height),
+    };
+    utils::check_image_size("width", target.width)?;
+    utils::check_image_size("height", target.height)?;
+    let mut resized = Mat::default();
+    imgproc::resize(
+        frame,
+        &mut resized,
+        target,
+        0.0,
+        0.0,
+        params.interpolation.flag(),
+    )?;
+    Ok(resized)
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FlipAxis {
+    Horizontal, // 左右(reverseと同じ)
+    #[default]
+    Vertical, // 上下
+    Both,       // 上下左右(180度回転)
+}
+
+// {"axis": "vertical"}
+#[derive(Debug, Clone, Default, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct FlipParams {
+    pub axis: FlipAxis,
+}
+
+pub fn flip(frame: &Mat, axis: FlipAxis) -> Result<Mat, opencv::Error> {
+    let flip_code = match axis {
+        FlipAxis::Horizontal => 1,
+        FlipAxis::Vertical => 0,
+        FlipAxis::Both => -1,
+    };
+    let mut flipped = Mat::default();
+    core::flip(frame, &mut flipped, flip_code)?;
+    Ok(flipped)
+}
+
+/*
+* {"angle": 30.0, "expand": true, "color": [0, 0, 0], "interpolation": "linear"}
+* angle -> 反時計回りの角度(度)。90度の倍数の場合は補間せずに回転する
+* expand -> trueの場合は回転した画像全体が収まるよう出力を大きくする(falseの場合は入力と同じ大きさで切り取る)
+* color -> 画像の外側を埋める色(BGR)
+*/
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct RotateParams {
+    pub angle: f64,
+    pub expand: bool,
+    pub color: [f64; 3], // BGR
+    pub interpolation: Interpolation,
+}
+
+impl Default for RotateParams {
+    fn default() -> Self {
+        Self {
+            angle: 90.0,
+            expand: true,
+            color: [0.0, 0.0, 0.0],
+            interpolation: Interpolation::Linear,
+        }
+    }
+}
+
+pub fn rotate(frame: &Mat, params: &RotateParams) -> Result<Mat, opencv::Error> {
+    // 90度単位の回転は画素の並べ替えのみで済む(expandしない場合は縦横が変わらない180度のみ)
+    let quarter_turns = (params.angle / 90.0).round();
+    if (params.angle / 90.0 - quarter_turns).abs() < 1e-9 {
+        let steps = (quarter_turns as i64).rem_euclid(4);
+        if params.expand || steps % 2 == 0 {
+            let rotate_code = match steps {
+                0 => return Ok(frame.clone()),
+                1 => core::ROTATE_90_COUNTERCLOCKWISE,
+                2 => core::ROTATE_180,
+                _ => core::ROTATE_90_CLOCKWISE,
+            };
+            let mut rotated = Mat::default();
+            core::rotate(frame, &mut rotated, rotate_code)?;
+            return Ok(rotated);
+        }
+    }
+
+    let size = frame.size()?;
+    let center = Point2f::new(size.width as f32 / 2.0, size.height as f32 / 2.0);
+    let mut matrix = imgproc::get_rotation_matrix_2d(center, params.angle, 1.0)?;
+    let output_size = match params.expand {
+        true => {
+            let radians = params.angle.to_radians();
+            let (cos, sin) = (radians.cos().abs(), radians.sin().abs());
+            let (width, height) = (size.width as f64, size.height as f64);
+            let expanded = Size::new(
+                (width * cos + height * sin).ceil() as i32,
+                (width * sin + height * cos).ceil() as i32,
+            );
+            // 回転の中心が出力の中心に来るよう平行移動する
+            *matrix.at_2d_mut::<f64>(0, 2)? += expanded.width as f64 / 2.0 - center.x as f64;
+            *matrix.at_2d_mut::<f64>(1, 2)? += expanded.height as f64 / 2.0 - center.y as f64;
+            expanded
+        }
+        false => size,
+    };
+    let mut rotated = Mat::default();
+    imgproc::warp_affine(
+        frame,
+        &mut rotated,
+        &matrix,
+        output_size,
+        params.interpolation.flag(),
+        core::BORDER_CONSTANT,
+        border_color(&params.color),
+    )?;
+    Ok(rotated)
+}
+
+/*
+* {"from": [[0, 0], [1, 0], [0, 1]], "to": [[0.1, 0], [0.9, 0.1], [0, 1]]}
+* from, to -> 入力・出力の画像上の3点(画像の大きさに対する比率)。fromの3点がtoの位置に移るよう変形する
+* matrix -> 指定した場合はfrom, toの代わりに2x3の変換行列(画素)をそのまま使う
+*/
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct AffineParams {
+    pub from: [[f32; 2]; 3],
+    pub to: [[f32; 2]; 3],
+    pub matrix: Option<[[f64; 3]; 2]>,
+    pub color: [f64; 3], // BGR
+    pub interpolation: Interpolation,
+}
+
+impl Default for AffineParams {
+    fn default() -> Self {
+        let triangle = [[0.0, 0.0], [1.0, 0.0], [0.0, 1.0]];
+        Self {
+            from: triangle,
+            to: triangle,
+            matrix: None,
+            color: [0.0, 0.0, 0.0],
+            interpolation: Interpolation::Linear,
+        }
+    }
+}
+
+// 比率で指定された点を画素の座標にする
+fn to_pixels(points: &[[f32; 2]], size: Size) -> Vector<Point2f> {
+    points
+        .iter()
+        .map(|[x, y]| Point2f::new(x * size.width as f32, y * size.height as f32))
+        .collect()
+}
+
+pub fn affine(frame: &Mat, params: &AffineParams) -> Result<Mat, opencv::Error> {
+    let size = frame.size()?;
+    let matrix = match &params.matrix {
+        Some(matrix) => Mat::from_slice_2d(matrix)?,
+        None => imgproc::get_affine_transform(
+            &to_pixels(&params.from, size),
+            &to_pixels(&params.to, size),
+        )?,
+    };
+    let mut warped = Mat::default();
+    imgproc::warp_affine(
+        frame,
+        &mut warped,
+        &matrix,
+        size,
+        params.interpolation.flag(),
+        core::BORDER_CONSTANT,
+        border_color(&params.color),
+    )?;
+    Ok(warped)
+}
+
+/*
+* {"corners": [[0.1, 0.1], [0.9, 0.1], [0.9, 0.9], [0.1, 0.9]], "width": 0, "height": 0}
+* corners -> 入力画像上の四隅(左上, 右上, 右下, 左下の順, 画像の大きさに対する比率)。この四角形を出力全体に引き伸ばす
+* width, height -> 出力の大きさ(0の場合は入力と同じ, 4096以下)
+*                  片方だけを指定した場合は、もう片方を四角形の縦横比から求める
+*/
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct PerspectiveParams {
+    pub corners: [[f32; 2]; 4],
+    pub width: i32,
+    pub height: i32,
+    pub color: [f64; 3], // BGR
+    pub interpolation: Interpolation,
+}
+
+impl Default for PerspectiveParams {
+    fn default() -> Self {
+        Self {
+            corners: [[0.0, 0.0], [1.0, 0.0], [1.0, 1.0], [0.0, 1.0]],
+            width: 0,
+            height: 0,
+            color: [0.0, 0.0, 0.0],
+            interpolation: Interpolation::Linear,
+        }
+    }
+}
+
+pub fn perspective(frame: &Mat, params: &PerspectiveParams) -> Result<Mat, opencv::Error> {
+    let size = frame.size()?;
+    let output_size = perspective_size(params, size);
+    utils::check_image_size("width", output_size.width)?;
+    utils::check_image_size("height", output_size.height)?;
+    let (width, height) = (output_size.width as f32, output_size.height as f32);
+    let destination = Vector::<Point2f>::from_iter([
+        Point2f::new(0.0, 0.0),
+        Point2f::new(width, 0.0),
+        Point2f::new(width, height),
+        Point2f::new(0.0, height),
+    ]);
+    let matrix =
+        imgproc::get_perspective_transform_def(&to_pixels(&params.corners, size), &destination)?;
+    let mut warped = Mat::default();
+    imgproc::warp_perspective(
+        frame,
+        &mut warped,
+        &matrix,
+        output_size,
+        params.interpolation.flag(),
+        core::BORDER_CONSTANT,
+        border_color(&params.color),
+    )?;
+    Ok(warped)
+}
+
+// 四角形の幅・高さは向かい合う辺の長さの平均とする
+fn perspective_size(params: &PerspectiveParams, size: Size) -> Size {
+    let corners = to_pixels(&params.corners, size).to_vec();
+    let distance = |a: Point2f, b: Point2f| (a.x - b.x).hypot(a.y - b.y) as f64;
+    let quad_width = (distance(corners[0], corners[1]) + distance(corners[3], corners[2])) / 2.0;
+    let quad_height = (distance(corners[0], corners[3]) + distance(corners[1], corners[2])) / 2.0;
+    match (params.width > 0, params.height > 0) {
+        (true, true) => Size::new(params.width, params.height),
+        (true, false) => {
+            let height = params.width as f64 * quad_height / quad_width;
+            Size::new(params.width, height.round() as i32)
+        }
+        (false, true) => {
+            let width = params.height as f64 * quad_width / quad_height;
+            Size::new(width.round() as i32, params.height)
+        }
+        (false, false) => size,
+    }
+}
+
+/*
+* {"width": 640, "height": 640, "color": [114, 114, 114], "interpolation": "linear"}
+* 縦横比を保って出力の大きさ(4096以下)に収まるよう拡大・縮小し、余白をcolorで埋める
+*/
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct LetterboxParams {
+    pub width: i32,
+    pub height: i32,
+    pub color: [f64; 3], // BGR
+    pub interpolation: Interpolation,
+}
+
+impl Default for LetterboxParams {
+    fn default() -> Self {
+        Self {
+            width: 640,
+            height: 640,
+            color: [0.0, 0.0, 0.0],
+            interpolation: Interpolation::Linear,
+        }
+    }
+}
+
+// 出力上の座標 = 入力上の座標 * scale + offset
+#[derive(Debug, Clone, Copy, serde::Serialize)]
+pub struct Letterbox {
+    pub scale: f64,
+    pub offset: [i32; 2],
+}
+
+pub fn letterbox(frame: &Mat, params: &LetterboxParams) -> Result<(Mat, Letterbox), opencv::Error> {
+    utils::check_image_size("width", params.width)?;
+    utils::check_image_size("height", params.height)?;
+    let size = frame.size()?;
+    let scale =
+        (params.width as f64 / size.width as f64).min(params.height as f64 / size.height as f64);
+    let scaled_size = Size::new(
+        ((size.width as f64 * scale).round() as i32).clamp(1, params.width),
+        ((size.height as f64 * scale).round() as i32).clamp(1, params.height),
+    );
+    let mut scaled = Mat::default();
+    imgproc::resize(
+        frame,
+        &mut scaled,
+        scaled_size,
+        0.0,
+        0.0,
+        params.interpolation.flag(),
+    )?;
+    let left = (params.width - scaled_size.width) / 2;
+    let top = (params.height - scaled_size.height) / 2;
+    let mut padded = Mat::default();
+    core::copy_make_border(
+        &scaled,
+        &mut padded,
+        top,
+        params.height - scaled_size.height - top,
+        left,
+        params.width - scaled_size.width - left,
+        core::BORDER_CONSTANT,
+        border_color(&params.color),
+    )?;
+    let letterbox = Letterbox {
+        scale,
+        offset: [left, top],
+    };
+    Ok((padded, letterbox))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use opencv::core::CV_8UC3;
+
+    fn crop_params(x: f64, y: f64, width: f64, height: f64, relative: bool) -> CropParams {
+        CropParams {
+            x,
+            y,
+            width,
+            height,
+            relative,
+        }
+    }
+
+    fn rotated_size(angle: f64, expand: bool) -> Size {
+        let frame = Mat::new_rows_cols_with_default(100, 200, CV_8UC3, Scalar::all(0.0)).unwrap();
+        let params = RotateParams {
+            angle,
+            expand,
+            ..Default::default()
+        };
+        rotate(&frame, &params).unwrap().size().unwrap()
+    }
+
+    #[test]
+    fn relative_crop_is_scaled_to_pixels() {
+        let rect = crop_rect(
+            Size::new(640, 480),
+            &crop_params(0.25, 0.25, 0.5, 0.5, true),
+        );
+        assert_eq!(rect.unwrap(), Rect::new(160, 120, 320, 240));
+    }
+
+    #[test]
+    fn crop_outside_the_image_is_clipped() {
+        let size = Size::new(640, 480);
+        let rect = crop_rect(size, &crop_params(600.0, -20.0, 100.0, 100.0, false));
+        assert_eq!(rect.unwrap(), Rect::new(600, 0, 40, 80));
+        assert!(crop_rect(size, &crop_params(1.5, 0.0, 0.5, 0.5, true)).is_err());
+        assert!(crop_rect(size, &crop_params(0.0, 0.0, 0.0, 1.0, true)).is_err());
+    }
+
+    #[test]
+    fn quarter_turns_swap_width_and_height_when_expanded() {
+        assert_eq!(rotated_size(90.0, true), Size::new(100, 200));
+        assert_eq!(rotated_size(-90.0, true), Size::new(100, 200));
+        assert_eq!(rotated_size(180.0, true), Size::new(200, 100));
+        assert_eq!(rotated_size(90.0, false), Size::new(200, 100));
+    }
+
+    #[test]
+    fn expanded_size_contains_the_rotated_image() {
+        assert_eq!(rotated_size(45.0, true), Size::new(213, 213));
+        assert_eq!(rotated_size(30.0, true), Size::new(224, 187));
+        assert_eq!(rotated_size(30.0, false), Size::new(200, 100));
+    }
+
+    #[test]
+    fn resize_beyond_the_size_limit_is_rejected() {
+        let frame = Mat::new_rows_cols_with_default(100, 200, CV_8UC3, Scalar::all(0.0)).unwrap();
+        let params = ResizeParams {
+            width: 5000,
+            ..Default::default()
+        };
+        assert!(resize(&frame, &params).is_err());
+        let params = ResizeParams {
+            scale: 100.0,
+            ..Default::default()
+        };
+        assert!(resize(&frame, &params).is_err());
+    }
+
+    fn perspective_params(corners: [[f32; 2]; 4], width: i32, height: i32) -> PerspectiveParams {
+        PerspectiveParams {
+            corners,
+            width,
+            height,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn perspective_derives_the_missing_dimension_from_the_quad() {
+        let size = Size::new(200, 100);
+        let quad = [[0.1, 0.1], [0.9, 0.1], [0.9, 0.9], [0.1, 0.9]];
+        let cases = [
+            (0, 0, Size::new(200, 100)),
+            (80, 0, Size::new(80, 40)),
+            (0, 60, Size::new(120, 60)),
+            (50, 70, Size::new(50, 70)),
+        ];
+        for (width, height, expected) in cases {
+            let params = perspective_params(quad, width, height);
+            assert_eq!(perspective_size(&params, size), expected);
+        }
+    }
+
+    #[test]
+    fn perspective_output_uses_the_given_width() {
+        let frame = Mat::new_rows_cols_with_default(100, 200, CV_8UC3, Scalar::all(0.0)).unwrap();
+        let params = perspective_params(PerspectiveParams::default().corners, 100, 0);
+        let warped = perspective(&frame, &params).unwrap();
+        assert_eq!(warped.size().unwrap(), Size::new(100, 50));
+    }
+
+    #[test]
+    fn perspective_with_a_degenerate_quad_is_rejected() {
+        let frame = Mat::new_rows_cols_with_default(100, 200, CV_8UC3, Scalar::all(0.0)).unwrap();
+        let line = [[0.0, 0.5], [1.0, 0.5], [1.0, 0.5], [0.0, 0.5]];
+        assert!(perspective(&frame, &perspective_params(line, 100, 0)).is_err());
+    }
+}
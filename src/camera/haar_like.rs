@@ -1,81 +1,419 @@
-use crate::camera::{frame_handler, utils};
-use ndarray::{prelude::*, Array2, ArrayView2};
-use opencv::{core::Mat, prelude::*};
+use crate::camera::{composite, utils};
+use ndarray::Array2;
+use opencv::core::{Mat, Rect, Scalar, Size, StsError, CV_64F, CV_8U, NORM_MINMAX};
+use opencv::{core, imgproc, prelude::*};
 use rayon::prelude::*;
-use std::cmp::Ordering;
 
-pub fn calc_haar_like_vec(
-    frame: &Mat,
-    divisions: i32,
-    rect_height: i32,
-) -> Result<Vec<f64>, opencv::Error> {
-    let gray_frame = match utils::is_grayscale(frame)? {
-        true => frame.clone(),
-        false => frame_handler::convert_to_gray(&frame)?,
-    };
-    let width = gray_frame.cols() as usize;
-    let height = gray_frame.rows() as usize;
-    let width_step = width / divisions as usize;
-
-    let array = Array2::<u8>::from_shape_vec(
-        (height, width),
-        gray_frame
-            .data_bytes()
-            .unwrap()
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum HaarKernel {
+    TwoHorizontal,   // 左右の明暗差(縦方向のエッジ)
+    TwoVertical,     // 上下の明暗差(横方向のエッジ)
+    ThreeHorizontal, // 左右に挟まれた中央との差(縦線)
+    ThreeVertical,   // 上下に挟まれた中央との差(横線)
+    Four,            // 対角の明暗差
+}
+
+impl HaarKernel {
+    pub const ALL: [HaarKernel; 5] = [
+        HaarKernel::TwoHorizontal,
+        HaarKernel::TwoVertical,
+        HaarKernel::ThreeHorizontal,
+        HaarKernel::ThreeVertical,
+        HaarKernel::Four,
+    ];
+
+    /*
+     * 窓を分割した矩形と重み(窓の左上からの相対位置)
+     * 応答 = Σ 重み * 矩形内の平均輝度。白い矩形と黒い矩形の重みの合計はそれぞれ+1, -1
+     * 窓の大きさが割り切れない場合、余りの画素は最後の矩形に含める
+     */
+    fn rects(&self, width: i32, height: i32) -> Vec<(Rect, f64)> {
+        let (half_width, half_height) = (width / 2, height / 2);
+        let (third_width, third_height) = (width / 3, height / 3);
+        let (rest_width, rest_height) = (width - half_width, height - half_height);
+        match self {
+            HaarKernel::TwoHorizontal => vec![
+                (Rect::new(0, 0, half_width, height), 1.0),
+                (Rect::new(half_width, 0, rest_width, height), -1.0),
+            ],
+            HaarKernel::TwoVertical => vec![
+                (Rect::new(0, 0, width, half_height), 1.0),
+                (Rect::new(0, half_height, width, rest_height), -1.0),
+            ],
+            HaarKernel::ThreeHorizontal => vec![
+                (Rect::new(0, 0, third_width, height), 0.5),
+                (Rect::new(third_width, 0, third_width, height), -1.0),
+                (
+                    Rect::new(third_width * 2, 0, width - third_width * 2, height),
+                    0.5,
+                ),
+            ],
+            HaarKernel::ThreeVertical => vec![
+                (Rect::new(0, 0, width, third_height), 0.5),
+                (Rect::new(0, third_height, width, third_height), -1.0),
+                (
+                    Rect::new(0, third_height * 2, width, height - third_height * 2),
+                    0.5,
+                ),
+            ],
+            HaarKernel::Four => vec![
+                (Rect::new(0, 0, half_width, half_height), 0.5),
+                (Rect::new(half_width, 0, rest_width, half_height), -0.5),
+                (Rect::new(0, half_height, half_width, rest_height), -0.5),
+                (
+                    Rect::new(half_width, half_height, rest_width, rest_height),
+                    0.5,
+                ),
+            ],
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum HaarOutput {
+    Heatmap,   // 全特徴の応答の絶対値の最大をカラーマップで重ねる
+    Strongest, // 応答が強い窓を枠で囲む
+}
+
+/*
+* {"kernels": ["two_horizontal", "four"], "window": [24, 24], "scales": [1.0, 2.0], "step": 4,
+*  "output": "heatmap", "alpha": 0.5, "top": 10, "responses": false}
+* window -> 倍率1.0の窓の大きさ [width, height]
+* step   -> 窓の中心をずらす間隔(画素)
+* responses: true の場合、各特徴の応答の配列をメタデータに含める
+*/
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct HaarParams {
+    pub kernels: Vec<HaarKernel>,
+    pub window: [i32; 2],
+    pub scales: Vec<f64>,
+    pub step: i32,
+    pub output: HaarOutput,
+    pub alpha: f64,
+    pub top: usize,
+    pub responses: bool,
+}
+
+impl Default for HaarParams {
+    fn default() -> Self {
+        Self {
+            kernels: HaarKernel::ALL.to_vec(),
+            window: [24, 24],
+            scales: vec![1.0, 2.0],
+            step: 4,
+            output: HaarOutput::Heatmap,
+            alpha: 0.5,
+            top: 10,
+            responses: false,
+        }
+    }
+}
+
+// 積分画像。任意の矩形の画素値の合計を4回の参照で求める
+pub struct IntegralImage {
+    sum: Array2<f64>,
+}
+
+impl IntegralImage {
+    pub fn new(frame: &Mat) -> Result<Self, opencv::Error> {
+        let gray_frame = utils::to_gray(frame)?;
+        let mut sum = Mat::default();
+        imgproc::integral(&gray_frame, &mut sum, CV_64F)?;
+        let shape = (sum.rows() as usize, sum.cols() as usize);
+        let sum = Array2::from_shape_vec(shape, sum.data_typed::<f64>()?.to_vec())
+            .map_err(|err| opencv::Error::new(StsError, err.to_string()))?;
+        Ok(Self { sum })
+    }
+
+    pub fn width(&self) -> i32 {
+        self.sum.ncols() as i32 - 1
+    }
+
+    pub fn height(&self) -> i32 {
+        self.sum.nrows() as i32 - 1
+    }
+
+    // 矩形は画像の内側にあること
+    pub fn rect_sum(&self, rect: Rect) -> f64 {
+        let (x0, y0) = (rect.x as usize, rect.y as usize);
+        let (x1, y1) = (
+            (rect.x + rect.width) as usize,
+            (rect.y + rect.height) as usize,
+        );
+        self.sum[[y1, x1]] - self.sum[[y0, x1]] - self.sum[[y1, x0]] + self.sum[[y0, x0]]
+    }
+
+    pub fn rect_mean(&self, rect: Rect) -> f64 {
+        self.rect_sum(rect) / rect.area().max(1) as f64
+    }
+
+    // 左上が(x, y)の窓に対する特徴の応答(-255.0〜255.0)
+    pub fn response(&self, kernel: HaarKernel, x: i32, y: i32, width: i32, height: i32) -> f64 {
+        kernel
+            .rects(width, height)
             .iter()
-            .map(|&x| x)
-            .collect(),
-    )
-    .unwrap();
+            .map(|(rect, weight)| {
+                weight * self.rect_mean(Rect::new(x + rect.x, y + rect.y, rect.width, rect.height))
+            })
+            .sum()
+    }
+}
+
+/*
+* 1つの特徴(カーネルと倍率)の応答
+* responses[[j, i]] は中心が(i * step + step / 2, j * step + step / 2)の窓の応答
+* 窓が画像からはみ出す位置は0.0
+*/
+#[derive(Debug, Clone)]
+pub struct FeatureMap {
+    pub kernel: HaarKernel,
+    pub scale: f64,
+    pub window: [i32; 2],
+    pub step: i32,
+    pub responses: Array2<f64>,
+}
+
+impl FeatureMap {
+    fn window_rect(&self, i: usize, j: usize) -> Rect {
+        let [width, height] = self.window;
+        let center_x = i as i32 * self.step + self.step / 2;
+        let center_y = j as i32 * self.step + self.step / 2;
+        Rect::new(center_x - width / 2, center_y - height / 2, width, height)
+    }
+
+    // 応答の絶対値が大きい順にtop個の窓
+    pub fn strongest(&self, top: usize) -> Vec<(Rect, f64)> {
+        let mut windows: Vec<(Rect, f64)> = self
+            .responses
+            .indexed_iter()
+            .filter(|(_, response)| **response != 0.0)
+            .map(|((j, i), response)| (self.window_rect(i, j), *response))
+            .collect();
+        windows.sort_by(|a, b| b.1.abs().total_cmp(&a.1.abs()));
+        windows.truncate(top);
+        windows
+    }
+
+    pub fn to_json(&self, with_responses: bool) -> serde_json::Value {
+        let strongest = self.strongest(1).first().map(|(rect, response)| {
+            serde_json::json!({ "x": rect.x, "y": rect.y, "response": response })
+        });
+        let mut value = serde_json::json!({
+            "kernel": self.kernel,
+            "scale": self.scale,
+            "window": self.window,
+            "step": self.step,
+            "strongest": strongest,
+        });
+        if with_responses {
+            let rows: Vec<Vec<f64>> = self
+                .responses
+                .rows()
+                .into_iter()
+                .map(|row| row.to_vec())
+                .collect();
+            value["responses"] = serde_json::json!(rows);
+        }
+        value
+    }
+}
 
-    let result: Vec<f64> = (0..divisions)
+pub fn compute_feature_map(
+    integral: &IntegralImage,
+    kernel: HaarKernel,
+    scale: f64,
+    params: &HaarParams,
+) -> FeatureMap {
+    let step = params.step.max(1);
+    let width = ((params.window[0] as f64 * scale) as i32).max(2);
+    let height = ((params.window[1] as f64 * scale) as i32).max(2);
+    let cols = (integral.width() / step).max(0) as usize;
+    let rows = (integral.height() / step).max(0) as usize;
+
+    let mut feature_map = FeatureMap {
+        kernel,
+        scale,
+        window: [width, height],
+        step,
+        responses: Array2::zeros((rows, cols)),
+    };
+    let values: Vec<f64> = (0..rows * cols)
         .into_par_iter()
-        .map(|i| {
-            let x = i as usize * width_step;
-            let cropped_array = array.slice(s![.., x..x + width_step]);
-            calc_haar_like_ndarray(&cropped_array, rect_height)
+        .map(|index| {
+            let rect = feature_map.window_rect(index % cols, index / cols);
+            let inside = rect.x >= 0
+                && rect.y >= 0
+                && rect.x + rect.width <= integral.width()
+                && rect.y + rect.height <= integral.height();
+            if !inside {
+                return 0.0;
+            }
+            integral.response(kernel, rect.x, rect.y, rect.width, rect.height)
         })
         .collect();
-    Ok(result)
+    feature_map.responses = Array2::from_shape_vec((rows, cols), values).unwrap();
+    feature_map
 }
 
-fn calc_haar_like_ndarray(frame: &ArrayView2<u8>, rect_height: i32) -> f64 {
-    let (rows, _) = frame.dim();
+// 指定された全てのカーネルと倍率の組み合わせについて応答を求める
+pub fn compute_features(
+    frame: &Mat,
+    params: &HaarParams,
+) -> Result<Vec<FeatureMap>, opencv::Error> {
+    let integral = IntegralImage::new(frame)?;
+    let mut feature_maps = vec![];
+    for kernel in params.kernels.iter() {
+        for scale in params.scales.iter() {
+            feature_maps.push(compute_feature_map(&integral, *kernel, *scale, params));
+        }
+    }
+    Ok(feature_maps)
+}
 
-    let mut array_1d = vec![0.0; rows];
-    for r in 0..rows {
-        array_1d[r] = frame.row(r).iter().map(|&x| x as f64).sum();
+pub fn draw_heatmap(
+    frame: &Mat,
+    feature_maps: &[FeatureMap],
+    alpha: f64,
+) -> Result<Mat, opencv::Error> {
+    let base = composite::to_bgr(frame)?;
+    let Some(first) = feature_maps.first() else {
+        return Ok(base);
+    };
+    let mut magnitude: Array2<f64> = Array2::zeros(first.responses.dim());
+    for feature_map in feature_maps.iter() {
+        magnitude.zip_mut_with(&feature_map.responses, |max, response| {
+            *max = max.max(response.abs())
+        });
+    }
+    let (rows, cols) = magnitude.dim();
+    if rows == 0 || cols == 0 {
+        return Ok(base);
     }
 
-    let kernel = vec![1.0 / rect_height as f64; rect_height as usize];
-    let convolved_array = convolve(&array_1d, &kernel);
+    let values: Vec<f64> = magnitude.iter().copied().collect();
+    let magnitude = Mat::new_rows_cols_with_data(rows as i32, cols as i32, &values)?;
+    let mut normalized = Mat::default();
+    core::normalize(
+        &magnitude,
+        &mut normalized,
+        0.0,
+        255.0,
+        NORM_MINMAX,
+        CV_8U,
+        &core::no_array(),
+    )?;
+    // 応答は窓の中心ごとに求めているため、step倍に拡大すると元の画像と位置が揃う
+    let size = Size::new(cols as i32 * first.step, rows as i32 * first.step);
+    let mut resized = Mat::default();
+    imgproc::resize(
+        &normalized,
+        &mut resized,
+        size,
+        0.0,
+        0.0,
+        imgproc::INTER_LINEAR,
+    )?;
+    let mut heatmap = Mat::new_size_with_default(base.size()?, base.typ(), Scalar::all(0.0))?;
+    let mut colored = Mat::default();
+    imgproc::apply_color_map(&resized, &mut colored, imgproc::COLORMAP_JET)?;
+    let mut roi = Mat::roi_mut(&mut heatmap, Rect::new(0, 0, size.width, size.height))?;
+    colored.copy_to(&mut roi)?;
 
-    let diff_array: Vec<f64> = convolved_array.windows(2).map(|w| w[1] - w[0]).collect();
+    let mut result = Mat::default();
+    core::add_weighted(&base, 1.0 - alpha, &heatmap, alpha, 0.0, &mut result, -1)?;
+    Ok(result)
+}
 
-    let max_idx = diff_array
+pub fn draw_strongest(
+    frame: &Mat,
+    feature_maps: &[FeatureMap],
+    top: usize,
+) -> Result<Mat, opencv::Error> {
+    let mut result = composite::to_bgr(frame)?;
+    let mut windows: Vec<(Rect, f64)> = feature_maps
         .iter()
-        .enumerate()
-        .max_by(|a, b| a.1.partial_cmp(b.1).unwrap_or(Ordering::Equal))
-        .map(|(idx, _)| idx)
-        .unwrap_or(0);
-
-    max_idx as f64 / diff_array.len() as f64
+        .flat_map(|feature_map| feature_map.strongest(top))
+        .collect();
+    windows.sort_by(|a, b| b.1.abs().total_cmp(&a.1.abs()));
+    windows.truncate(top);
+    for (rect, response) in windows {
+        // 正の応答は赤、負の応答は青
+        let color = if response > 0.0 {
+            Scalar::new(0.0, 0.0, 255.0, 0.0)
+        } else {
+            Scalar::new(255.0, 0.0, 0.0, 0.0)
+        };
+        imgproc::rectangle(&mut result, rect, color, 2, imgproc::LINE_8, 0)?;
+    }
+    Ok(result)
 }
 
-fn convolve(array: &[f64], kernel: &[f64]) -> Vec<f64> {
-    let kernel_size = kernel.len();
-    let array_len = array.len();
-    let mut result = Vec::with_capacity(array_len - kernel_size + 1);
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /*
+     * 1 2 3
+     * 4 5 6
+     * 7 8 9
+     */
+    fn integral_3x3() -> IntegralImage {
+        let frame = Mat::from_slice_2d(&[[1u8, 2, 3], [4, 5, 6], [7, 8, 9]]).unwrap();
+        IntegralImage::new(&frame).unwrap()
+    }
+
+    #[test]
+    fn rect_sum_matches_hand_computed_sums() {
+        let integral = integral_3x3();
+        assert_eq!((integral.width(), integral.height()), (3, 3));
+        assert_eq!(integral.rect_sum(Rect::new(0, 0, 3, 3)), 45.0);
+        assert_eq!(integral.rect_sum(Rect::new(1, 1, 2, 2)), 28.0);
+        assert_eq!(integral.rect_sum(Rect::new(0, 2, 3, 1)), 24.0);
+        assert_eq!(integral.rect_sum(Rect::new(2, 0, 1, 3)), 18.0);
+        assert_eq!(integral.rect_sum(Rect::new(1, 1, 0, 0)), 0.0);
+        assert_eq!(integral.rect_mean(Rect::new(1, 1, 2, 2)), 7.0);
+    }
+
+    #[test]
+    fn white_and_black_weights_sum_to_one() {
+        for kernel in HaarKernel::ALL {
+            let rects = kernel.rects(24, 24);
+            let white: f64 = rects.iter().map(|(_, w)| w.max(0.0)).sum();
+            let black: f64 = rects.iter().map(|(_, w)| w.min(0.0)).sum();
+            assert_eq!((white, black), (1.0, -1.0), "{:?}", kernel);
+        }
+    }
 
-    let mut sum: f64 = 0.0;
-    for i in 0..kernel_size {
-        sum += array[i] * kernel[kernel_size - 1 - i];
+    #[test]
+    fn rects_cover_odd_sized_windows() {
+        for kernel in HaarKernel::ALL {
+            for (width, height) in [(25, 25), (7, 11), (24, 24)] {
+                let rects = kernel.rects(width, height);
+                let area: i32 = rects.iter().map(|(rect, _)| rect.area()).sum();
+                assert_eq!(area, width * height, "{:?} {}x{}", kernel, width, height);
+                let window = Rect::new(0, 0, width, height);
+                assert!(rects.iter().all(|(rect, _)| (*rect & window) == *rect));
+            }
+        }
     }
-    result.push(sum);
 
-    for i in kernel_size..array_len {
-        sum += array[i] * kernel[0] - array[i - kernel_size] * kernel[kernel_size - 1];
-        result.push(sum);
+    #[test]
+    fn responses_follow_the_kernel_weights() {
+        let integral = integral_3x3();
+        // 左1列(1, 4, 7)の平均4 - 右2列の平均5.5
+        let two_horizontal = integral.response(HaarKernel::TwoHorizontal, 0, 0, 3, 3);
+        assert_eq!(two_horizontal, -1.5);
+        // 上1行の平均2 - 下2行の平均6.5
+        let two_vertical = integral.response(HaarKernel::TwoVertical, 0, 0, 3, 3);
+        assert_eq!(two_vertical, -4.5);
+        // (左列4 + 右列6) / 2 - 中央列5
+        let three_horizontal = integral.response(HaarKernel::ThreeHorizontal, 0, 0, 3, 3);
+        assert_eq!(three_horizontal, 0.0);
+        // (左上1 + 右下7) / 2 - (右上2.5 + 左下5.5) / 2
+        let four = integral.response(HaarKernel::Four, 0, 0, 3, 3);
+        assert_eq!(four, 0.0);
     }
-    result
 }
@@ -0,0 +1,155 @@
+use opencv::core::StsBadArg;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_json::{Map, Value};
+use std::any::Any;
+
+/*
+* ノードごとに保持される情報
+* params   -> クライアントから指定されたパラメータ
+* metadata -> フレームごとの処理結果(検出した矩形など)。クライアントにイベントとして送る
+* events   -> 読み取ったコードなど、フレームが破棄されてもクライアントに必ず送るイベント
+* state    -> フレームをまたいで保持する状態(読み込んだモデルなど)
+*/
+#[derive(Default)]
+pub struct HandlerContext {
+    params: Map<String, Value>,
+    metadata: Option<Value>,
+    events: Vec<Value>,
+    state: Option<Box<dyn Any + Send>>,
+}
+
+impl HandlerContext {
+    pub fn new(params: Map<String, Value>) -> Self {
+        Self {
+            params,
+            ..Default::default()
+        }
+    }
+
+    /*
+     * defaultsに指定されたパラメータを上書きした値を返す
+     * defaults = {"scale_factor": 1.1, "min_neighbors": 3}, params = {"min_neighbors": 5}
+     *   -> {"scale_factor": 1.1, "min_neighbors": 5}
+     * オブジェクトの値は再帰的に上書きする
+     * defaults = {"face": {"min_neighbors": 3, "min_size": [80, 80]}}, params = {"face": {"min_neighbors": 5}}
+     *   -> {"face": {"min_neighbors": 5, "min_size": [80, 80]}}
+     */
+    pub fn params_or<T: Serialize + DeserializeOwned>(
+        &self,
+        defaults: T,
+    ) -> Result<T, opencv::Error> {
+        let mut merged = match serde_json::to_value(defaults) {
+            Ok(Value::Object(map)) => map,
+            _ => Map::new(),
+        };
+        merge_params(&mut merged, &self.params);
+        serde_json::from_value(Value::Object(merged))
+            .map_err(|err| opencv::Error::new(StsBadArg, format!("invalid params: {}", err)))
+    }
+
+    pub fn has_params(&self, params: &Map<String, Value>) -> bool {
+        self.params == *params
+    }
+
+    pub fn params<T: Serialize + DeserializeOwned + Default>(&self) -> Result<T, opencv::Error> {
+        self.params_or(T::default())
+    }
+
+    pub fn set_metadata(&mut self, metadata: impl Serialize) {
+        self.metadata = serde_json::to_value(metadata).ok();
+    }
+
+    pub fn take_metadata(&mut self) -> Option<Value> {
+        self.metadata.take()
+    }
+
+    pub fn push_event(&mut self, event: impl Serialize) {
+        if let Ok(event) = serde_json::to_value(event) {
+            self.events.push(event);
+        }
+    }
+
+    pub fn take_events(&mut self) -> Vec<Value> {
+        std::mem::take(&mut self.events)
+    }
+
+    // 初回の呼び出し時(または型が変わった場合)にDefaultで初期化される
+    pub fn state<T: Default + Send + 'static>(&mut self) -> &mut T {
+        if !self.state.as_ref().is_some_and(|state| state.is::<T>()) {
+            self.state = Some(Box::<T>::default());
+        }
+        self.state.as_mut().unwrap().downcast_mut::<T>().unwrap()
+    }
+}
+
+fn merge_params(target: &mut Map<String, Value>, overrides: &Map<String, Value>) {
+    for (key, value) in overrides.iter() {
+        match (target.get_mut(key), value) {
+            (Some(Value::Object(target)), Value::Object(overrides)) => {
+                merge_params(target, overrides)
+            }
+            _ => {
+                target.insert(key.clone(), value.clone());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[derive(Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+    struct Inner {
+        min_neighbors: i32,
+        min_size: [i32; 2],
+    }
+
+    #[derive(Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+    struct Outer {
+        scale_factor: f64,
+        face: Inner,
+        parts: Vec<String>,
+    }
+
+    fn defaults() -> Outer {
+        Outer {
+            scale_factor: 1.1,
+            face: Inner {
+                min_neighbors: 3,
+                min_size: [80, 80],
+            },
+            parts: vec!["eye".to_string(), "smile".to_string()],
+        }
+    }
+
+    fn context(params: Value) -> HandlerContext {
+        HandlerContext::new(params.as_object().cloned().unwrap())
+    }
+
+    #[test]
+    fn nested_objects_are_merged_field_by_field() {
+        let params: Outer = context(json!({"face": {"min_neighbors": 5}}))
+            .params_or(defaults())
+            .unwrap();
+        assert_eq!(params.face.min_neighbors, 5);
+        assert_eq!(params.face.min_size, [80, 80]);
+        assert_eq!(params.scale_factor, 1.1);
+    }
+
+    #[test]
+    fn arrays_are_replaced() {
+        let params: Outer = context(json!({"parts": ["nose"]}))
+            .params_or(defaults())
+            .unwrap();
+        assert_eq!(params.parts, ["nose"]);
+    }
+
+    #[test]
+    fn invalid_params_are_an_error() {
+        let result = context(json!({"face": {"min_neighbors": "many"}})).params_or(defaults());
+        assert!(result.is_err());
+    }
+}
@@ -0,0 +1,326 @@
+use crate::camera::composite;
+use crate::camera::contours::Circle;
+use crate::camera::edges::{self, CannyParams};
+use crate::camera::filters::{self, BlurMethod, BlurParams};
+use crate::camera::utils;
+use opencv::core::{Mat, Point, Scalar, StsBadArg, Vec2f, Vec3f, Vec4i, Vector};
+use opencv::{imgproc, prelude::*};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EdgeSource {
+    Canny, // cannyでエッジを求めてから検出する
+    Input, // 入力をエッジ画像としてそのまま使う(canny, sobel, binaryなどの後段につなぐ場合)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LineMethod {
+    Standard,      // 直線(rho, theta)。画像の端から端まで描く
+    Probabilistic, // 線分
+}
+
+/*
+* {"method": "probabilistic", "source": "canny", "canny": {"auto": true},
+*  "threshold": 80, "min_line_length": 30, "max_line_gap": 10}
+* rho, theta -> 投票する距離(画素)と角度(度)の分解能(0より大きい値)
+* min_angle, max_angle -> 検出する直線の法線の角度の範囲(度, standardのみ)
+*/
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct HoughLineParams {
+    pub method: LineMethod,
+    pub source: EdgeSource,
+    pub canny: CannyParams,
+    pub rho: f64,
+    pub theta: f64,
+    pub threshold: i32,
+    pub min_line_length: f64,
+    pub max_line_gap: f64,
+    pub min_angle: f64,
+    pub max_angle: f64,
+    pub max_lines: usize,
+    pub color: [f64; 3], // BGR
+    pub thickness: i32,
+}
+
+impl Default for HoughLineParams {
+    fn default() -> Self {
+        Self {
+            method: LineMethod::Probabilistic,
+            source: EdgeSource::Canny,
+            canny: CannyParams::default(),
+            rho: 1.0,
+            theta: 1.0,
+            threshold: 80,
+            min_line_length: 30.0,
+            max_line_gap: 10.0,
+            min_angle: 0.0,
+            max_angle: 180.0,
+            max_lines: 100,
+            color: [0.0, 0.0, 255.0],
+            thickness: 2,
+        }
+    }
+}
+
+/*
+* 線分の始点・終点と長さ、角度(度, x軸からの時計回り)
+* standardの場合は直線を画像の範囲で切り取った線分と、rho, theta(度)
+*/
+#[derive(Debug, Clone, Copy, serde::Serialize)]
+pub struct LineSegment {
+    pub start: [i32; 2],
+    pub end: [i32; 2],
+    pub length: f64,
+    pub angle: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rho: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub theta: Option<f32>,
+}
+
+impl LineSegment {
+    fn new(start: Point, end: Point) -> Self {
+        let (dx, dy) = ((end.x - start.x) as f64, (end.y - start.y) as f64);
+        Self {
+            start: [start.x, start.y],
+            end: [end.x, end.y],
+            length: dx.hypot(dy),
+            angle: dy.atan2(dx).to_degrees(),
+            rho: None,
+            theta: None,
+        }
+    }
+}
+
+fn edge_frame(frame: &Mat, source: EdgeSource, canny: &CannyParams) -> Result<Mat, opencv::Error> {
+    match source {
+        EdgeSource::Canny => Ok(edges::canny(frame, canny)?.0),
+        EdgeSource::Input => utils::to_gray(frame),
+    }
+}
+
+pub fn detect_lines(
+    frame: &Mat,
+    params: &HoughLineParams,
+) -> Result<Vec<LineSegment>, opencv::Error> {
+    if params.rho <= 0.0 || params.theta <= 0.0 {
+        return Err(opencv::Error::new(
+            StsBadArg,
+            "rho and theta must be greater than 0",
+        ));
+    }
+    let edge_frame = edge_frame(frame, params.source, &params.canny)?;
+    let theta = params.theta.to_radians();
+    let mut segments = match params.method {
+        LineMethod::Probabilistic => {
+            let mut lines = Vector::<Vec4i>::new();
+            imgproc::hough_lines_p(
+                &edge_frame,
+                &mut lines,
+                params.rho,
+                theta,
+                params.threshold,
+                params.min_line_length,
+                params.max_line_gap,
+            )?;
+            lines
+                .iter()
+                .map(|line| {
+                    LineSegment::new(Point::new(line[0], line[1]), Point::new(line[2], line[3]))
+                })
+                .collect::<Vec<_>>()
+        }
+        LineMethod::Standard => {
+            let mut lines = Vector::<Vec2f>::new();
+            imgproc::hough_lines(
+                &edge_frame,
+                &mut lines,
+                params.rho,
+                theta,
+                params.threshold,
+                0.0,
+                0.0,
+                params.min_angle.to_radians(),
+                params.max_angle.to_radians(),
+            )?;
+            let mut segments = vec![];
+            for line in lines.iter() {
+                if let Some(segment) = clip_polar_line(line[0], line[1], edge_frame.size()?)? {
+                    segments.push(segment);
+                }
+            }
+            segments
+        }
+    };
+    // 投票数の多い順に返されるため、先頭からmax_lines本
+    segments.truncate(params.max_lines);
+    Ok(segments)
+}
+
+// 直線 x*cosθ + y*sinθ = rho を画像の範囲で切り取る
+fn clip_polar_line(
+    rho: f32,
+    theta: f32,
+    size: opencv::core::Size,
+) -> Result<Option<LineSegment>, opencv::Error> {
+    let (rho, theta_f64) = (rho as f64, theta as f64);
+    let (cos, sin) = (theta_f64.cos(), theta_f64.sin());
+    let (x0, y0) = (rho * cos, rho * sin);
+    // 画像の対角線より長く伸ばしてから切り取る
+    let reach = 2.0 * (size.width as f64).hypot(size.height as f64);
+    let mut start = Point::new((x0 - reach * sin) as i32, (y0 + reach * cos) as i32);
+    let mut end = Point::new((x0 + reach * sin) as i32, (y0 - reach * cos) as i32);
+    if !imgproc::clip_line_size(size, &mut start, &mut end)? {
+        return Ok(None);
+    }
+    let mut segment = LineSegment::new(start, end);
+    segment.rho = Some(rho as f32);
+    segment.theta = Some(theta.to_degrees());
+    Ok(Some(segment))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CircleMethod {
+    Gradient,
+    GradientAlt, // param2は円らしさ(0〜1, 1に近いほど厳しい)
+}
+
+/*
+* {"method": "gradient", "blur": 5, "dp": 1.0, "min_dist": 20, "param1": 100, "param2": 30,
+*  "min_radius": 10, "max_radius": 100}
+* 円はグレースケールの画像から検出する(param1は内部で使うcannyの上側の閾値)
+* blur -> 検出前のメディアンフィルタのサイズ(0の場合は行わない)
+* dp -> 投票空間の解像度の逆数(2の場合は画像の半分の解像度で投票する)
+* min_dist -> 円の中心同士の最小距離
+* param2 -> gradientの場合は中心の投票数の閾値(小さいほど誤検出が増える)
+*           省略した場合はmethodに合わせる(gradient: 30, gradient_alt: 0.9)
+* max_radiusが0の場合は上限なし
+*/
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct HoughCircleParams {
+    pub method: CircleMethod,
+    pub blur: i32,
+    pub dp: f64,
+    pub min_dist: f64,
+    pub param1: f64,
+    pub param2: Option<f64>,
+    pub min_radius: i32,
+    pub max_radius: i32,
+    pub max_circles: usize,
+    pub color: [f64; 3], // BGR
+    pub thickness: i32,
+}
+
+impl Default for HoughCircleParams {
+    fn default() -> Self {
+        Self {
+            method: CircleMethod::Gradient,
+            blur: 5,
+            dp: 1.0,
+            min_dist: 20.0,
+            param1: 100.0,
+            param2: None,
+            min_radius: 0,
+            max_radius: 0,
+            max_circles: 20,
+            color: [0.0, 255.0, 0.0],
+            thickness: 2,
+        }
+    }
+}
+
+impl HoughCircleParams {
+    fn param2(&self) -> f64 {
+        self.param2.unwrap_or(match self.method {
+            CircleMethod::Gradient => 30.0,
+            CircleMethod::GradientAlt => 0.9,
+        })
+    }
+}
+
+pub fn detect_circles(
+    frame: &Mat,
+    params: &HoughCircleParams,
+) -> Result<Vec<Circle>, opencv::Error> {
+    let mut gray_frame = utils::to_gray(frame)?;
+    if params.blur > 0 {
+        let blur_params = BlurParams {
+            method: BlurMethod::Median,
+            size: params.blur,
+            sigma: 0.0,
+        };
+        gray_frame = filters::blur(&gray_frame, &blur_params)?;
+    }
+    let method = match params.method {
+        CircleMethod::Gradient => imgproc::HOUGH_GRADIENT,
+        CircleMethod::GradientAlt => imgproc::HOUGH_GRADIENT_ALT,
+    };
+    let mut circles = Vector::<Vec3f>::new();
+    imgproc::hough_circles(
+        &gray_frame,
+        &mut circles,
+        method,
+        params.dp,
+        params.min_dist,
+        params.param1,
+        params.param2(),
+        params.min_radius,
+        params.max_radius,
+    )?;
+    Ok(circles
+        .iter()
+        .take(params.max_circles)
+        .map(|circle| Circle {
+            center: [circle[0], circle[1]],
+            radius: circle[2],
+        })
+        .collect())
+}
+
+pub fn draw_lines(
+    frame: &Mat,
+    segments: &[LineSegment],
+    params: &HoughLineParams,
+) -> Result<Mat, opencv::Error> {
+    let mut result = composite::to_bgr(frame)?;
+    let color = Scalar::new(params.color[0], params.color[1], params.color[2], 0.0);
+    for segment in segments.iter() {
+        imgproc::line(
+            &mut result,
+            Point::new(segment.start[0], segment.start[1]),
+            Point::new(segment.end[0], segment.end[1]),
+            color,
+            params.thickness,
+            imgproc::LINE_AA,
+            0,
+        )?;
+    }
+    Ok(result)
+}
+
+pub fn draw_circles(
+    frame: &Mat,
+    circles: &[Circle],
+    params: &HoughCircleParams,
+) -> Result<Mat, opencv::Error> {
+    let mut result = composite::to_bgr(frame)?;
+    let color = Scalar::new(params.color[0], params.color[1], params.color[2], 0.0);
+    for circle in circles.iter() {
+        let center = Point::new(circle.center[0] as i32, circle.center[1] as i32);
+        imgproc::circle(
+            &mut result,
+            center,
+            circle.radius as i32,
+            color,
+            params.thickness,
+            imgproc::LINE_AA,
+            0,
+        )?;
+        imgproc::circle(&mut result, center, 2, color, -1, imgproc::LINE_AA, 0)?;
+    }
+    Ok(result)
+}
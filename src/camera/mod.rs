@@ -1,5 +1,28 @@
+pub mod aruco;
+pub mod calibration;
 pub mod camera;
+pub mod cascade;
+pub mod codes;
+pub mod color_space;
+pub mod color_tracker;
+pub mod composite;
+pub mod contours;
+pub mod edges;
+pub mod face_detector;
+pub mod filters;
 pub mod frame_handler;
+pub mod geometry;
 pub mod haar_like;
+pub mod handler_context;
+pub mod hough;
+pub mod pipeline;
+pub mod pixel_format;
+pub mod properties;
+pub mod ptz;
+pub mod source;
+pub mod superpixel;
+pub mod superres;
 pub mod text;
+pub mod threshold;
+pub mod tone;
 pub mod utils;
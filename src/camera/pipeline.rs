@@ -0,0 +1,267 @@
+use crate::camera::frame_handler::{self, FrameHandler, Produces};
+use crate::camera::handler_context::HandlerContext;
+use crate::camera::pixel_format::{self, PixelFormat};
+use opencv::core::Mat;
+use serde_json::{Map, Value};
+use std::collections::HashMap;
+use std::fmt;
+
+// チェーンを構成するノード(処理名と、ノードごとに指定されたパラメータ)
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ProcessNode {
+    pub name: String,
+    pub params: Map<String, Value>,
+}
+
+pub enum Stage {
+    Handler {
+        name: String,
+        handler: FrameHandler,
+        context: HandlerContext,
+    },
+    Convert {
+        from: PixelFormat,
+        to: PixelFormat,
+    },
+}
+
+impl Stage {
+    pub fn name(&self) -> String {
+        match self {
+            Stage::Handler { name, .. } => name.clone(),
+            Stage::Convert { from, to } => format!("{}->{}", from.name(), to.name()),
+        }
+    }
+
+    pub fn apply(&mut self, frame: &Mat) -> Result<Mat, opencv::Error> {
+        match self {
+            Stage::Handler {
+                handler, context, ..
+            } => handler(frame, context),
+            Stage::Convert { from, to } => pixel_format::convert(frame, *from, *to),
+        }
+    }
+
+    // 直前のフレームで処理が出力したメタデータ
+    pub fn take_metadata(&mut self) -> Option<Value> {
+        match self {
+            Stage::Handler { context, .. } => context.take_metadata(),
+            Stage::Convert { .. } => None,
+        }
+    }
+
+    // 直前のフレームまでに処理が出力したイベント
+    pub fn take_events(&mut self) -> Vec<Value> {
+        match self {
+            Stage::Handler { context, .. } => context.take_events(),
+            Stage::Convert { .. } => vec![],
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum PipelineError {
+    UnknownHandler(String),
+    UnknownStage(String),
+}
+
+impl fmt::Display for PipelineError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PipelineError::UnknownHandler(name) => write!(f, "unknown handler: {}", name),
+            PipelineError::UnknownStage(name) => write!(f, "stage not in pipeline: {}", name),
+        }
+    }
+}
+
+/*
+* 同じ名前のステージが複数ある場合は2つ目以降に番号を付ける
+* ["blur", "GRAY8->BGR8", "blur"] -> ["blur", "GRAY8->BGR8", "blur#2"]
+*/
+pub fn unique_stage_names(stages: &[Stage]) -> Vec<String> {
+    let mut counts: HashMap<String, usize> = HashMap::new();
+    stages
+        .iter()
+        .map(|stage| {
+            let name = stage.name();
+            let count = counts.entry(name.clone()).or_insert(0);
+            *count += 1;
+            match *count {
+                1 => name,
+                n => format!("{}#{}", name, n),
+            }
+        })
+        .collect()
+}
+
+/*
+* build_pipeline(["gray", "white_balance"], Bgr8, [])
+*   -> [Handler("gray"), Convert(GRAY8 -> BGR8), Handler("white_balance")]
+* build_pipeline(["sobel"], Bgr8, []) -> [Handler("sobel"), Convert(FLOAT -> GRAY8)]
+* previousのステージのうち処理名とパラメータが同じものは、読み込んだモデルなどの状態を引き継ぐ
+* エラーの場合はpreviousを変更しない
+*/
+pub fn build_pipeline(
+    process_chain: &[ProcessNode],
+    source_format: PixelFormat,
+    previous: &mut Vec<Stage>,
+) -> Result<Vec<Stage>, PipelineError> {
+    let specs = process_chain
+        .iter()
+        .map(|node| {
+            frame_handler::search_frame_handler(&node.name)
+                .ok_or_else(|| PipelineError::UnknownHandler(node.name.clone()))
+        })
+        .collect::<Result<Vec<_>, PipelineError>>()?;
+
+    let mut stages: Vec<Stage> = vec![];
+    let mut current_format = source_format;
+
+    for (node, spec) in process_chain.iter().zip(specs) {
+        // 入力形式が受け付けられない場合は、優先形式への変換を挟む
+        if !spec.accepts.contains(&current_format) {
+            let to = spec.accepts[0];
+            stages.push(Stage::Convert {
+                from: current_format,
+                to,
+            });
+            current_format = to;
+        }
+        let context = take_context(previous, node)
+            .unwrap_or_else(|| HandlerContext::new(node.params.clone()));
+        stages.push(Stage::Handler {
+            name: node.name.clone(),
+            handler: spec.handler,
+            context,
+        });
+        if let Produces::Format(format) = spec.produces {
+            current_format = format;
+        }
+    }
+    // 浮動小数点のままでは画像としてエンコードできないため、最後に8bitへ変換する
+    if current_format == PixelFormat::Float {
+        stages.push(Stage::Convert {
+            from: current_format,
+            to: PixelFormat::Gray8,
+        });
+    }
+    Ok(stages)
+}
+
+// 同じ処理が複数ある場合は前から順に引き継ぐ
+fn take_context(previous: &mut Vec<Stage>, node: &ProcessNode) -> Option<HandlerContext> {
+    let index = previous.iter().position(|stage| match stage {
+        Stage::Handler { name, context, .. } => {
+            *name == node.name && context.has_params(&node.params)
+        }
+        Stage::Convert { .. } => false,
+    })?;
+    match previous.remove(index) {
+        Stage::Handler { context, .. } => Some(context),
+        Stage::Convert { .. } => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn node(name: &str, params: Value) -> ProcessNode {
+        ProcessNode {
+            name: name.to_string(),
+            params: params.as_object().cloned().unwrap_or_default(),
+        }
+    }
+
+    fn chain(names: &[&str]) -> Vec<ProcessNode> {
+        names.iter().map(|name| node(name, json!({}))).collect()
+    }
+
+    fn build(names: &[&str]) -> Vec<String> {
+        let stages = build_pipeline(&chain(names), PixelFormat::Bgr8, &mut vec![]).unwrap();
+        stages.iter().map(|stage| stage.name()).collect()
+    }
+
+    fn context(stage: &mut Stage) -> &mut HandlerContext {
+        match stage {
+            Stage::Handler { context, .. } => context,
+            Stage::Convert { .. } => panic!("not a handler stage"),
+        }
+    }
+
+    #[test]
+    fn conversion_is_inserted_when_format_is_not_accepted() {
+        assert_eq!(
+            build(&["gray", "white_balance"]),
+            ["gray", "GRAY8->BGR8", "white_balance"]
+        );
+        assert_eq!(build(&["canny"]), ["BGR8->GRAY8", "canny"]);
+        assert_eq!(build(&["gray", "blur"]), ["gray", "blur"]);
+    }
+
+    #[test]
+    fn float_output_is_converted_to_gray8() {
+        assert_eq!(build(&["sobel"]), ["sobel", "FLOAT->GRAY8"]);
+        assert_eq!(
+            build(&["sobel", "reverse"]),
+            ["sobel", "reverse", "FLOAT->GRAY8"]
+        );
+        assert_eq!(build(&["sobel", "blur"]), ["sobel", "FLOAT->BGR8", "blur"]);
+    }
+
+    #[test]
+    fn unknown_handler_leaves_previous_stages_untouched() {
+        let mut previous =
+            build_pipeline(&chain(&["blur"]), PixelFormat::Bgr8, &mut vec![]).unwrap();
+        let result = build_pipeline(
+            &chain(&["blur", "unknown"]),
+            PixelFormat::Bgr8,
+            &mut previous,
+        );
+        assert!(matches!(result, Err(PipelineError::UnknownHandler(name)) if name == "unknown"));
+        assert_eq!(previous.len(), 1);
+    }
+
+    #[test]
+    fn unchanged_stages_keep_their_state() {
+        let nodes = vec![
+            node("blur", json!({"size": 5})),
+            node("blur", json!({"size": 9})),
+        ];
+        let mut previous = build_pipeline(&nodes, PixelFormat::Bgr8, &mut vec![]).unwrap();
+        *context(&mut previous[0]).state::<u32>() = 1;
+        *context(&mut previous[1]).state::<u32>() = 2;
+
+        // 2つ目のblurのパラメータだけを変更し、順序も入れ替える
+        let nodes = vec![
+            node("blur", json!({"size": 3})),
+            node("blur", json!({"size": 5})),
+        ];
+        let mut stages = build_pipeline(&nodes, PixelFormat::Bgr8, &mut previous).unwrap();
+        assert_eq!(*context(&mut stages[0]).state::<u32>(), 0);
+        assert_eq!(*context(&mut stages[1]).state::<u32>(), 1);
+        assert_eq!(previous.len(), 1);
+    }
+
+    #[test]
+    fn duplicate_stage_names_are_numbered() {
+        let stages = build_pipeline(
+            &chain(&["blur", "gray", "blur", "white_balance", "blur"]),
+            PixelFormat::Bgr8,
+            &mut vec![],
+        )
+        .unwrap();
+        assert_eq!(
+            unique_stage_names(&stages),
+            [
+                "blur",
+                "gray",
+                "blur#2",
+                "GRAY8->BGR8",
+                "white_balance",
+                "blur#3"
+            ]
+        );
+    }
+}
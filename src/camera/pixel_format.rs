@@ -0,0 +1,74 @@
+use opencv::core::{no_array, Mat, CV_32F, CV_8U, NORM_MINMAX};
+use opencv::{imgproc, prelude::*};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PixelFormat {
+    Bgr8,  // 8bit 3チャネル(カメラの出力)
+    Gray8, // 8bit 1チャネル(グレースケール、マスク)
+    Bgra,  // 8bit 4チャネル
+    Float, // 32bit浮動小数点 1チャネル(勾配強度など)
+}
+
+impl PixelFormat {
+    pub fn of(frame: &Mat) -> Option<PixelFormat> {
+        match (frame.depth(), frame.channels()) {
+            (CV_8U, 3) => Some(PixelFormat::Bgr8),
+            (CV_8U, 1) => Some(PixelFormat::Gray8),
+            (CV_8U, 4) => Some(PixelFormat::Bgra),
+            (CV_32F, 1) => Some(PixelFormat::Float),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            PixelFormat::Bgr8 => "BGR8",
+            PixelFormat::Gray8 => "GRAY8",
+            PixelFormat::Bgra => "BGRA",
+            PixelFormat::Float => "FLOAT",
+        }
+    }
+}
+
+/*
+* convert(frame, Gray8, Bgr8) -> 3チャネルに複製したframe
+* convert(frame, Float, Bgr8) -> 0-255に正規化した後、3チャネルに複製したframe
+*/
+pub fn convert(frame: &Mat, from: PixelFormat, to: PixelFormat) -> Result<Mat, opencv::Error> {
+    use PixelFormat::*;
+    let code = match (from, to) {
+        (Bgr8, Bgr8) | (Gray8, Gray8) | (Bgra, Bgra) | (Float, Float) => return Ok(frame.clone()),
+        (Float, _) => return convert(&float_to_gray(frame)?, Gray8, to),
+        (_, Float) => return gray_to_float(&convert(frame, from, Gray8)?),
+        (Bgr8, Gray8) => imgproc::COLOR_BGR2GRAY,
+        (Bgr8, Bgra) => imgproc::COLOR_BGR2BGRA,
+        (Gray8, Bgr8) => imgproc::COLOR_GRAY2BGR,
+        (Gray8, Bgra) => imgproc::COLOR_GRAY2BGRA,
+        (Bgra, Bgr8) => imgproc::COLOR_BGRA2BGR,
+        (Bgra, Gray8) => imgproc::COLOR_BGRA2GRAY,
+    };
+    let mut converted_frame = Mat::default();
+    imgproc::cvt_color(frame, &mut converted_frame, code, 0)?;
+    Ok(converted_frame)
+}
+
+// 値の範囲が不定なため、最小値〜最大値を0〜255に引き伸ばす
+fn float_to_gray(frame: &Mat) -> Result<Mat, opencv::Error> {
+    let mut gray_frame = Mat::default();
+    opencv::core::normalize(
+        frame,
+        &mut gray_frame,
+        0.0,
+        255.0,
+        NORM_MINMAX,
+        CV_8U,
+        &no_array(),
+    )?;
+    Ok(gray_frame)
+}
+
+fn gray_to_float(frame: &Mat) -> Result<Mat, opencv::Error> {
+    let mut float_frame = Mat::default();
+    frame.convert_to(&mut float_frame, CV_32F, 1.0 / 255.0, 0.0)?;
+    Ok(float_frame)
+}
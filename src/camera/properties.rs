@@ -0,0 +1,189 @@
+use opencv::prelude::{VideoCaptureTrait, VideoCaptureTraitConst};
+use opencv::videoio::{self, VideoCapture};
+use phf::phf_map;
+use serde_json::{json, Map, Value};
+use std::fmt;
+
+// 値の読み戻しで設定が反映されたとみなす誤差
+const TOLERANCE: f64 = 1e-3;
+
+static CAMERA_PROPERTIES: phf::Map<&'static str, i32> = phf_map! {
+    "auto_exposure" => videoio::CAP_PROP_AUTO_EXPOSURE,
+    "exposure" => videoio::CAP_PROP_EXPOSURE,
+    "gain" => videoio::CAP_PROP_GAIN,
+    "brightness" => videoio::CAP_PROP_BRIGHTNESS,
+    "contrast" => videoio::CAP_PROP_CONTRAST,
+    "saturation" => videoio::CAP_PROP_SATURATION,
+    "autofocus" => videoio::CAP_PROP_AUTOFOCUS,
+    "focus" => videoio::CAP_PROP_FOCUS,
+    "auto_white_balance" => videoio::CAP_PROP_AUTO_WB,
+    "white_balance_temperature" => videoio::CAP_PROP_WB_TEMPERATURE,
+    "fps" => videoio::CAP_PROP_FPS,
+    "fourcc" => videoio::CAP_PROP_FOURCC,
+    "width" => videoio::CAP_PROP_FRAME_WIDTH,
+    "height" => videoio::CAP_PROP_FRAME_HEIGHT,
+};
+
+#[derive(Debug)]
+pub enum PropertyError {
+    UnknownProperty(String),
+    InvalidValue(String),
+    NotConnected,
+}
+
+impl fmt::Display for PropertyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PropertyError::UnknownProperty(name) => write!(f, "unknown camera property: {}", name),
+            PropertyError::InvalidValue(name) => write!(f, "invalid value for: {}", name),
+            PropertyError::NotConnected => write!(f, "source is not connected"),
+        }
+    }
+}
+
+/*
+* {"backend": "V4L2", "properties": {"exposure": {"value": 156.0, "supported": true}, ...}}
+* 対応していないプロパティは-1(バックエンドによっては0)が返る
+*/
+pub fn query_capabilities(capture: &VideoCapture) -> Value {
+    let mut properties = Map::new();
+    for (name, id) in CAMERA_PROPERTIES.entries() {
+        let value = capture.get(*id).unwrap_or(-1.0);
+        properties.insert(
+            name.to_string(),
+            json!({ "value": to_json_value(name, value), "supported": value != -1.0 }),
+        );
+    }
+    json!({
+        "backend": capture.get_backend_name().unwrap_or_default(),
+        "properties": properties,
+    })
+}
+
+/*
+* 設定後に値を読み戻し、ドライバが実際に反映したかを返す
+* {"exposure": {"requested": -6.0, "actual": -6.0, "applied": true}}
+*/
+pub fn set_properties(
+    capture: &mut VideoCapture,
+    requested: &Map<String, Value>,
+) -> Result<Value, PropertyError> {
+    // 一部だけ反映されることを避けるため、先に全ての値を検証する
+    let parsed = parse_properties(requested)?;
+
+    let mut results = Map::new();
+    for (name, id, value) in parsed {
+        let accepted = capture.set(id, value).unwrap_or(false);
+        let actual = capture.get(id).unwrap_or(-1.0);
+        results.insert(
+            name.clone(),
+            json!({
+                "requested": to_json_value(name, value),
+                "actual": to_json_value(name, actual),
+                "applied": accepted && (actual - value).abs() < TOLERANCE,
+            }),
+        );
+    }
+    Ok(Value::Object(results))
+}
+
+// プロパティ名を番号に、値を数値に変換する。1つでも不正な値があればエラーを返す
+fn parse_properties(
+    requested: &Map<String, Value>,
+) -> Result<Vec<(&String, i32, f64)>, PropertyError> {
+    requested
+        .iter()
+        .map(|(name, value)| {
+            let id = *CAMERA_PROPERTIES
+                .get(name.as_str())
+                .ok_or_else(|| PropertyError::UnknownProperty(name.clone()))?;
+            Ok((name, id, from_json_value(name, value)?))
+        })
+        .collect()
+}
+
+// FOURCCは4文字の文字列として扱う("MJPG" <-> 1196444237.0)
+fn to_json_value(name: &str, value: f64) -> Value {
+    if name == "fourcc" {
+        let bytes = (value as u32).to_le_bytes();
+        return json!(String::from_utf8_lossy(&bytes));
+    }
+    json!(value)
+}
+
+fn from_json_value(name: &str, value: &Value) -> Result<f64, PropertyError> {
+    let invalid = || PropertyError::InvalidValue(name.to_string());
+    match value {
+        Value::String(code) if name == "fourcc" => {
+            let bytes: [u8; 4] = code.as_bytes().try_into().map_err(|_| invalid())?;
+            Ok(u32::from_le_bytes(bytes) as f64)
+        }
+        Value::Bool(flag) => Ok(if *flag { 1.0 } else { 0.0 }),
+        _ => value.as_f64().ok_or_else(invalid),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MJPG: f64 = 1196444237.0;
+
+    fn parse(requested: Value) -> Result<Vec<(String, i32, f64)>, PropertyError> {
+        let requested = requested.as_object().cloned().unwrap_or_default();
+        let parsed = parse_properties(&requested)?;
+        Ok(parsed
+            .into_iter()
+            .map(|(name, id, value)| (name.clone(), id, value))
+            .collect())
+    }
+
+    #[test]
+    fn fourcc_is_converted_between_string_and_number() {
+        assert_eq!(from_json_value("fourcc", &json!("MJPG")).unwrap(), MJPG);
+        assert_eq!(to_json_value("fourcc", MJPG), json!("MJPG"));
+        assert_eq!(from_json_value("fourcc", &json!(MJPG)).unwrap(), MJPG);
+    }
+
+    #[test]
+    fn fourcc_must_be_four_bytes() {
+        for code in ["MJP", "MJPEG", ""] {
+            assert!(matches!(
+                from_json_value("fourcc", &json!(code)),
+                Err(PropertyError::InvalidValue(name)) if name == "fourcc"
+            ));
+        }
+    }
+
+    #[test]
+    fn bools_are_mapped_to_one_and_zero() {
+        assert_eq!(from_json_value("autofocus", &json!(true)).unwrap(), 1.0);
+        assert_eq!(from_json_value("autofocus", &json!(false)).unwrap(), 0.0);
+    }
+
+    #[test]
+    fn strings_are_rejected_for_numeric_properties() {
+        assert!(from_json_value("exposure", &json!("-6")).is_err());
+        assert!(from_json_value("exposure", &json!(null)).is_err());
+        assert_eq!(to_json_value("exposure", -6.0), json!(-6.0));
+    }
+
+    #[test]
+    fn all_properties_are_parsed_before_setting() {
+        let parsed = parse(json!({"exposure": -6, "fourcc": "MJPG", "autofocus": false})).unwrap();
+        assert_eq!(parsed.len(), 3);
+        assert!(parsed.contains(&("fourcc".to_string(), videoio::CAP_PROP_FOURCC, MJPG)));
+    }
+
+    #[test]
+    fn one_invalid_property_rejects_the_whole_request() {
+        assert!(matches!(
+            parse(json!({"exposure": -6, "gain": "high"})),
+            Err(PropertyError::InvalidValue(name)) if name == "gain"
+        ));
+        assert!(matches!(
+            parse(json!({"exposure": -6, "zoom": 2})),
+            Err(PropertyError::UnknownProperty(name)) if name == "zoom"
+        ));
+    }
+}
@@ -0,0 +1,334 @@
+use crate::camera::cascade::{self, CascadeCache, CascadeParams};
+use crate::camera::handler_context::HandlerContext;
+use crate::camera::superres::{self, SuperResCache, SuperResParams};
+use opencv::core::{Mat, Rect, Size};
+use opencv::{imgproc, prelude::*};
+use serde_json::{Map, Value};
+use std::time::Instant;
+
+// これ以下の拡大率は等倍として切り出さない
+const ZOOM_EPSILON: f64 = 1e-3;
+
+/*
+* ePTZ(デジタルパン・チルト・ズーム)の設定
+* {"max_zoom": 8.0, "smoothing": 0.3, "superres": {"algorithm": "espcn", "scale": 2},
+*  "auto_frame": true, "face_ratio": 0.3}
+* smoothing -> 目標の表示範囲に近づくまでの時定数(秒)。0の場合はすぐに移動する
+* superres -> 指定した場合は切り出した範囲を超解像で拡大する(省略時は通常の拡大)
+* auto_frame -> 最も大きい顔を追いかける。face_ratioは顔の高さが表示範囲に占める割合
+* face -> 顔の検出に使うカスケードのパラメータ
+*/
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct PtzSettings {
+    pub max_zoom: f64,
+    pub smoothing: f64,
+    pub superres: Option<SuperResParams>,
+    pub auto_frame: bool,
+    pub face_ratio: f64,
+    pub face: CascadeParams,
+}
+
+impl Default for PtzSettings {
+    fn default() -> Self {
+        Self {
+            max_zoom: 8.0,
+            smoothing: 0.3,
+            superres: None,
+            auto_frame: false,
+            face_ratio: 0.3,
+            face: CascadeParams::default(),
+        }
+    }
+}
+
+// pan, tilt -> 表示範囲の中心(画像に対する相対位置), zoom -> 拡大率(1.0で全体)
+#[derive(Debug, Clone, Copy, PartialEq, serde::Serialize)]
+pub struct Viewport {
+    pub pan: f64,
+    pub tilt: f64,
+    pub zoom: f64,
+}
+
+impl Default for Viewport {
+    fn default() -> Self {
+        Self {
+            pan: 0.5,
+            tilt: 0.5,
+            zoom: 1.0,
+        }
+    }
+}
+
+impl Viewport {
+    // 表示範囲が画像からはみ出さないようにする
+    fn clamped(self, max_zoom: f64) -> Self {
+        let zoom = self.zoom.clamp(1.0, max_zoom.max(1.0));
+        let half = 0.5 / zoom;
+        Self {
+            pan: self.pan.clamp(half, 1.0 - half),
+            tilt: self.tilt.clamp(half, 1.0 - half),
+            zoom,
+        }
+    }
+
+    fn interpolate(&self, target: &Viewport, ratio: f64) -> Self {
+        let lerp = |from: f64, to: f64| from + (to - from) * ratio;
+        Self {
+            pan: lerp(self.pan, target.pan),
+            tilt: lerp(self.tilt, target.tilt),
+            zoom: lerp(self.zoom, target.zoom),
+        }
+    }
+
+    fn rect(&self, size: Size) -> Rect {
+        let width = ((size.width as f64 / self.zoom).round() as i32).clamp(1, size.width);
+        let height = ((size.height as f64 / self.zoom).round() as i32).clamp(1, size.height);
+        let x = (self.pan * size.width as f64 - width as f64 / 2.0).round() as i32;
+        let y = (self.tilt * size.height as f64 - height as f64 / 2.0).round() as i32;
+        Rect::new(
+            x.clamp(0, size.width - width),
+            y.clamp(0, size.height - height),
+            width,
+            height,
+        )
+    }
+
+    // 表示中の画像に対する相対位置を、元の画像に対する相対位置にする
+    pub fn to_source(&self, x: f64, y: f64) -> (f64, f64) {
+        (
+            self.pan + (x - 0.5) / self.zoom,
+            self.tilt + (y - 0.5) / self.zoom,
+        )
+    }
+}
+
+// target -> クライアントが指定した表示範囲, current -> 滑らかに移動中の現在の表示範囲
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct PtzStatus {
+    pub target: Viewport,
+    pub current: Viewport,
+    pub auto_frame: bool,
+}
+
+// ソースの画像に対して仮想的な表示範囲を持ち、切り出して元の大きさに拡大する
+#[derive(Default)]
+pub struct Ptz {
+    settings: PtzSettings,
+    target: Viewport,
+    current: Viewport,
+    last_update: Option<Instant>,
+    superres: SuperResCache,
+    face: CascadeCache,
+}
+
+impl Ptz {
+    // 指定された項目のみ現在の設定を上書きする({"auto_frame": true} は他の項目を変えない)
+    pub fn update_settings(&mut self, overrides: Map<String, Value>) -> Result<(), opencv::Error> {
+        self.settings = HandlerContext::new(overrides).params_or(self.settings.clone())?;
+        self.target = self.target.clamped(self.settings.max_zoom);
+        Ok(())
+    }
+
+    // Noneの値は変更しない。手動で動かした場合は自動フレーミングを解除する
+    pub fn set_viewport(&mut self, pan: Option<f64>, tilt: Option<f64>, zoom: Option<f64>) {
+        let viewport = Viewport {
+            pan: pan.unwrap_or(self.target.pan),
+            tilt: tilt.unwrap_or(self.target.tilt),
+            zoom: zoom.unwrap_or(self.target.zoom),
+        };
+        self.move_to(viewport);
+    }
+
+    // 表示中の画像に対する相対的な移動量(画像をつかんで動かす向き)
+    pub fn drag(&mut self, dx: f64, dy: f64) {
+        let viewport = Viewport {
+            pan: self.target.pan - dx / self.target.zoom,
+            tilt: self.target.tilt - dy / self.target.zoom,
+            zoom: self.target.zoom,
+        };
+        self.move_to(viewport);
+    }
+
+    // 表示中の画像上のx, yの位置を固定したまま、factor倍に拡大・縮小する
+    pub fn scroll(&mut self, factor: f64, x: f64, y: f64) {
+        let (source_x, source_y) = self.target.to_source(x, y);
+        let zoom = (self.target.zoom * factor).clamp(1.0, self.settings.max_zoom.max(1.0));
+        let viewport = Viewport {
+            pan: source_x - (x - 0.5) / zoom,
+            tilt: source_y - (y - 0.5) / zoom,
+            zoom,
+        };
+        self.move_to(viewport);
+    }
+
+    pub fn reset(&mut self) {
+        self.move_to(Viewport::default());
+    }
+
+    fn move_to(&mut self, viewport: Viewport) {
+        self.settings.auto_frame = false;
+        self.target = viewport.clamped(self.settings.max_zoom);
+    }
+
+    pub fn status(&self) -> PtzStatus {
+        PtzStatus {
+            target: self.target,
+            current: self.current,
+            auto_frame: self.settings.auto_frame,
+        }
+    }
+
+    pub fn apply(&mut self, frame: &Mat) -> Result<Mat, opencv::Error> {
+        if self.settings.auto_frame {
+            self.follow_largest_face(frame)?;
+        }
+        self.step();
+        if self.current.zoom < 1.0 + ZOOM_EPSILON {
+            return Ok(frame.clone());
+        }
+
+        let size = frame.size()?;
+        let roi = Mat::roi(frame, self.current.rect(size))?.try_clone()?;
+        let upscaled = match &self.settings.superres {
+            Some(params) => {
+                let params = SuperResParams {
+                    crop: None,
+                    ..params.clone()
+                };
+                superres::upsample(&roi, &mut self.superres, &params)?.0
+            }
+            None => roi,
+        };
+        let mut zoomed = Mat::default();
+        imgproc::resize(
+            &upscaled,
+            &mut zoomed,
+            size,
+            0.0,
+            0.0,
+            imgproc::INTER_LINEAR,
+        )?;
+        Ok(zoomed)
+    }
+
+    // 経過時間に応じて現在の表示範囲を目標に近づける
+    fn step(&mut self) {
+        let now = Instant::now();
+        let elapsed = self
+            .last_update
+            .map(|last| now.duration_since(last).as_secs_f64())
+            .unwrap_or_default();
+        self.last_update = Some(now);
+        let ratio = match self.settings.smoothing > 0.0 {
+            true => 1.0 - (-elapsed / self.settings.smoothing).exp(),
+            false => 1.0,
+        };
+        self.current = self.current.interpolate(&self.target, ratio);
+    }
+
+    // 顔を見失った場合は直前の表示範囲に留まる
+    fn follow_largest_face(&mut self, frame: &Mat) -> Result<(), opencv::Error> {
+        let classifier = self.face.get(&self.settings.face.model)?;
+        let faces = cascade::detect(frame, classifier, &self.settings.face)?;
+        let Some(face) = faces.iter().max_by_key(|face| face.width * face.height) else {
+            return Ok(());
+        };
+        let size = frame.size()?;
+        let (width, height) = (size.width as f64, size.height as f64);
+        let viewport = Viewport {
+            pan: (face.x as f64 + face.width as f64 / 2.0) / width,
+            tilt: (face.y as f64 + face.height as f64 / 2.0) / height,
+            zoom: self.settings.face_ratio * height / face.height as f64,
+        };
+        self.target = viewport.clamped(self.settings.max_zoom);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn viewport(pan: f64, tilt: f64, zoom: f64) -> Viewport {
+        Viewport { pan, tilt, zoom }
+    }
+
+    fn overrides(value: Value) -> Map<String, Value> {
+        value.as_object().cloned().unwrap()
+    }
+
+    #[test]
+    fn clamped_keeps_zoom_in_range() {
+        assert_eq!(viewport(0.5, 0.5, 0.5).clamped(8.0), Viewport::default());
+        assert_eq!(viewport(0.5, 0.5, 10.0).clamped(8.0).zoom, 8.0);
+        // max_zoomが1未満の場合は等倍のみ
+        assert_eq!(viewport(0.5, 0.5, 2.0).clamped(0.5).zoom, 1.0);
+    }
+
+    #[test]
+    fn clamped_keeps_viewport_inside_the_image() {
+        assert_eq!(
+            viewport(0.0, 1.0, 2.0).clamped(8.0),
+            viewport(0.25, 0.75, 2.0)
+        );
+        assert_eq!(viewport(0.9, 0.1, 1.0).clamped(8.0), Viewport::default());
+        assert_eq!(
+            viewport(0.3, 0.6, 2.0).clamped(8.0),
+            viewport(0.3, 0.6, 2.0)
+        );
+    }
+
+    #[test]
+    fn to_source_maps_displayed_position_to_source_position() {
+        assert_eq!(Viewport::default().to_source(0.25, 0.75), (0.25, 0.75));
+        let zoomed = viewport(0.25, 0.75, 2.0);
+        assert_eq!(zoomed.to_source(0.5, 0.5), (0.25, 0.75));
+        assert_eq!(zoomed.to_source(0.0, 0.0), (0.0, 0.5));
+        assert_eq!(zoomed.to_source(1.0, 1.0), (0.5, 1.0));
+    }
+
+    #[test]
+    fn rect_covers_the_viewport() {
+        let size = Size::new(640, 480);
+        assert_eq!(Viewport::default().rect(size), Rect::new(0, 0, 640, 480));
+        assert_eq!(
+            viewport(0.25, 0.75, 2.0).rect(size),
+            Rect::new(0, 240, 320, 240)
+        );
+    }
+
+    #[test]
+    fn scroll_keeps_the_pointed_position() {
+        let mut ptz = Ptz::default();
+        ptz.scroll(2.0, 0.2, 0.8);
+        let (x, y) = ptz.target.to_source(0.2, 0.8);
+        assert!((x - 0.2).abs() < 1e-9 && (y - 0.8).abs() < 1e-9);
+        assert_eq!(ptz.target.zoom, 2.0);
+    }
+
+    #[test]
+    fn settings_update_only_the_given_fields() {
+        let mut ptz = Ptz::default();
+        ptz.update_settings(overrides(
+            json!({"max_zoom": 4.0, "face": {"min_neighbors": 7}}),
+        ))
+        .unwrap();
+        ptz.update_settings(overrides(json!({"auto_frame": true})))
+            .unwrap();
+        assert!(ptz.settings.auto_frame);
+        assert_eq!(ptz.settings.max_zoom, 4.0);
+        assert_eq!(ptz.settings.face.min_neighbors, 7);
+        assert_eq!(ptz.settings.smoothing, PtzSettings::default().smoothing);
+    }
+
+    #[test]
+    fn lowering_max_zoom_clamps_the_target() {
+        let mut ptz = Ptz::default();
+        ptz.set_viewport(None, None, Some(8.0));
+        ptz.update_settings(overrides(json!({"max_zoom": 2.0})))
+            .unwrap();
+        assert_eq!(ptz.target.zoom, 2.0);
+    }
+}
@@ -0,0 +1,384 @@
+use crate::camera::utils;
+use opencv::core::{Mat, Point, Scalar, Size, StsError, Vector, CV_8UC3};
+use opencv::imgproc;
+use opencv::prelude::{MatTraitConst, VideoCaptureTrait, VideoCaptureTraitConst};
+use opencv::videoio::{self, VideoCapture};
+use serde_json::{json, Value};
+use std::sync::mpsc::{self, Receiver, TryRecvError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// 起動時に接続を確認するデバイス番号の上限
+const MAX_DEVICE_PROBE: i32 = 10;
+const DEVICE_WIDTH: f64 = 640.0;
+const DEVICE_HEIGHT: f64 = 480.0;
+// ネットワークソースの接続・読み込みのタイムアウトと再接続の間隔
+const NETWORK_TIMEOUT_MSEC: i32 = 5000;
+const RECONNECT_INTERVAL: Duration = Duration::from_secs(3);
+// SOURCESでGStreamerパイプラインを指定する場合の接頭辞
+const GSTREAMER_PREFIX: &str = "gst:";
+
+#[derive(Debug, Clone, serde::Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum FrameSource {
+    Device { index: i32 },
+    File { path: String },
+    Network { url: String },        // RTSP, HTTP(MJPEG)
+    Gstreamer { pipeline: String }, // appsinkで終わるパイプライン
+}
+
+impl FrameSource {
+    /*
+     * parse("rtsp://192.168.0.10/stream")                -> Network
+     * parse("gst:videotestsrc ! videoconvert ! appsink") -> Gstreamer
+     * parse("/data/door.mp4")                            -> File
+     */
+    pub fn parse(spec: &str) -> FrameSource {
+        const NETWORK_SCHEMES: [&str; 3] = ["rtsp://", "http://", "https://"];
+        if let Some(pipeline) = spec.strip_prefix(GSTREAMER_PREFIX) {
+            return FrameSource::Gstreamer {
+                pipeline: pipeline.to_string(),
+            };
+        }
+        if NETWORK_SCHEMES
+            .iter()
+            .any(|scheme| spec.starts_with(scheme))
+        {
+            return FrameSource::Network {
+                url: spec.to_string(),
+            };
+        }
+        FrameSource::File {
+            path: spec.to_string(),
+        }
+    }
+
+    // 接続が切れた場合に再接続を試みるソース
+    fn is_remote(&self) -> bool {
+        matches!(
+            self,
+            FrameSource::Network { .. } | FrameSource::Gstreamer { .. }
+        )
+    }
+
+    fn open_capture(&self) -> Result<VideoCapture, opencv::Error> {
+        let capture = match self {
+            FrameSource::Device { index } => {
+                let mut capture = VideoCapture::new(*index, videoio::CAP_ANY)?;
+                capture.set(videoio::CAP_PROP_FRAME_WIDTH, DEVICE_WIDTH)?;
+                capture.set(videoio::CAP_PROP_FRAME_HEIGHT, DEVICE_HEIGHT)?;
+                capture
+            }
+            FrameSource::File { path } => VideoCapture::from_file(path, videoio::CAP_ANY)?,
+            FrameSource::Network { url } => {
+                let params = Vector::from_slice(&[
+                    videoio::CAP_PROP_OPEN_TIMEOUT_MSEC,
+                    NETWORK_TIMEOUT_MSEC,
+                    videoio::CAP_PROP_READ_TIMEOUT_MSEC,
+                    NETWORK_TIMEOUT_MSEC,
+                ]);
+                VideoCapture::from_file_with_params(url, videoio::CAP_FFMPEG, &params)?
+            }
+            FrameSource::Gstreamer { pipeline } => {
+                VideoCapture::from_file(pipeline, videoio::CAP_GSTREAMER)?
+            }
+        };
+        if !capture.is_opened()? {
+            return Err(opencv::Error::new(StsError, "cannot open source"));
+        }
+        Ok(capture)
+    }
+}
+
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct NamedSource {
+    pub name: String,
+    #[serde(flatten)]
+    pub source: FrameSource,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ConnectionState {
+    Connected,
+    Reconnecting,
+}
+
+/*
+* 開いた状態のソース
+* 動画ファイルは最後まで読んだら先頭に戻し、早送りにならないようファイルのFPSに合わせて読み込む
+* ネットワーク・GStreamerのソースは切断時に再接続を繰り返し、その間は代替の画像を返す
+* 再接続はタイムアウトまで処理が止まるため別スレッドで行い、接続できたら次のフレームから読み込む
+*/
+pub struct SourceReader {
+    pub name: String,
+    source: FrameSource,
+    pub capture: Option<VideoCapture>,
+    // 再接続のスレッドが接続を試みている間は、その結果を受け取る
+    connecting: Option<Receiver<Result<VideoCapture, String>>>,
+    state: ConnectionState,
+    reconnects: u32,
+    last_error: Option<String>,
+    last_attempt: Instant,
+    last_size: Size,
+    status_changed: bool,
+    // 動画ファイルのフレーム間隔と、次のフレームを読み込む時刻
+    frame_interval: Option<Duration>,
+    next_frame: Instant,
+}
+
+impl SourceReader {
+    pub fn open(named_source: &NamedSource) -> Result<Self, opencv::Error> {
+        let source = &named_source.source;
+        let (capture, state, last_error) = match source.open_capture() {
+            Ok(capture) => (Some(capture), ConnectionState::Connected, None),
+            // リモートのソースは起動時に繋がらなくても後から再接続する
+            Err(err) if source.is_remote() => {
+                (None, ConnectionState::Reconnecting, Some(err.to_string()))
+            }
+            Err(err) => {
+                let message = format!("cannot open source: {} ({})", named_source.name, err);
+                return Err(opencv::Error::new(StsError, message));
+            }
+        };
+        let frame_interval = match (source, &capture) {
+            (FrameSource::File { .. }, Some(capture)) => file_frame_interval(capture),
+            _ => None,
+        };
+        Ok(Self {
+            name: named_source.name.clone(),
+            source: source.clone(),
+            capture,
+            connecting: None,
+            state,
+            reconnects: 0,
+            last_error,
+            last_attempt: Instant::now(),
+            last_size: Size::new(DEVICE_WIDTH as i32, DEVICE_HEIGHT as i32),
+            status_changed: true,
+            frame_interval,
+            next_frame: Instant::now(),
+        })
+    }
+
+    pub fn read(&mut self) -> Result<Mat, opencv::Error> {
+        self.wait_next_frame();
+        if self.capture.is_none() {
+            self.reconnect();
+        }
+        let Some(capture) = self.capture.as_mut() else {
+            return self.placeholder_frame();
+        };
+
+        let mut frame = Mat::default();
+        let read_result = capture.read(&mut frame);
+        if frame.empty() {
+            if let FrameSource::File { .. } = self.source {
+                capture.set(videoio::CAP_PROP_POS_FRAMES, 0.0)?;
+                capture.read(&mut frame)?;
+            }
+        }
+        if frame.empty() {
+            let message = match read_result {
+                Err(err) => err.to_string(),
+                Ok(_) => format!("cannot read source: {}", self.name),
+            };
+            if self.source.is_remote() {
+                self.disconnect(message);
+                return self.placeholder_frame();
+            }
+            return Err(opencv::Error::new(StsError, message));
+        }
+        self.last_size = frame.size()?;
+        Ok(frame)
+    }
+
+    // 処理が間に合わず遅れた場合は、遅れを取り戻そうとせずその時点から数える
+    fn wait_next_frame(&mut self) {
+        let Some(interval) = self.frame_interval else {
+            return;
+        };
+        let now = Instant::now();
+        if let Some(wait) = self.next_frame.checked_duration_since(now) {
+            thread::sleep(wait);
+        }
+        self.next_frame = self.next_frame.max(now) + interval;
+    }
+
+    fn disconnect(&mut self, message: String) {
+        println!("WARN: SOURCE DISCONNECTED({}): {}", self.name, message);
+        self.capture = None;
+        self.state = ConnectionState::Reconnecting;
+        self.last_error = Some(message);
+        self.last_attempt = Instant::now();
+        self.status_changed = true;
+    }
+
+    fn reconnect(&mut self) {
+        if let Some(receiver) = &self.connecting {
+            let result = match receiver.try_recv() {
+                Ok(result) => result,
+                Err(TryRecvError::Empty) => return,
+                Err(TryRecvError::Disconnected) => Err("reconnect thread exited".to_string()),
+            };
+            self.connecting = None;
+            // 再接続の間隔は接続に失敗した時点から数える
+            self.last_attempt = Instant::now();
+            match result {
+                Ok(capture) => {
+                    self.capture = Some(capture);
+                    self.state = ConnectionState::Connected;
+                    self.status_changed = true;
+                }
+                Err(message) => self.last_error = Some(message),
+            }
+            return;
+        }
+        if self.last_attempt.elapsed() < RECONNECT_INTERVAL {
+            return;
+        }
+        self.reconnects += 1;
+        let source = self.source.clone();
+        let (sender, receiver) = mpsc::channel();
+        thread::spawn(move || {
+            let _ = sender.send(source.open_capture().map_err(|err| err.to_string()));
+        });
+        self.connecting = Some(receiver);
+    }
+
+    // 再接続中であることを示す黒い画像
+    fn placeholder_frame(&self) -> Result<Mat, opencv::Error> {
+        let mut frame = Mat::new_size_with_default(self.last_size, CV_8UC3, Scalar::all(0.0))?;
+        imgproc::put_text(
+            &mut frame,
+            &format!("{}: reconnecting...", self.name),
+            Point::new(10, 30),
+            imgproc::FONT_HERSHEY_SIMPLEX,
+            0.8,
+            Scalar::all(255.0),
+            2,
+            imgproc::LINE_AA,
+            false,
+        )?;
+        Ok(frame)
+    }
+
+    pub fn status(&self) -> Value {
+        json!({
+            "name": self.name,
+            "source": self.source,
+            "state": self.state,
+            "reconnects": self.reconnects,
+            "last_error": self.last_error,
+        })
+    }
+
+    // 接続状態が前回の呼び出しから変化した場合のみ状態を返す
+    pub fn take_status_change(&mut self) -> Option<Value> {
+        if !self.status_changed {
+            return None;
+        }
+        self.status_changed = false;
+        Some(self.status())
+    }
+}
+
+// FPSが取得できないファイルは待たずに読み込む
+fn file_frame_interval(capture: &VideoCapture) -> Option<Duration> {
+    let fps = capture.get(videoio::CAP_PROP_FPS).ok()?;
+    if fps <= 0.0 {
+        return None;
+    }
+    Duration::try_from_secs_f64(1.0 / fps).ok()
+}
+
+/*
+* 接続されているカメラ(cam0, cam1, ...)と、環境変数SOURCESで設定されたソースを列挙する
+* SOURCES="door=/data/door.mp4;lobby=rtsp://192.168.0.10/stream;test=gst:videotestsrc ! appsink"
+*/
+pub fn probe_sources() -> Vec<NamedSource> {
+    // DEV_NUMBERが上限を超える場合も確認対象に含める
+    let default_index = utils::get_dev_number();
+    let extra_index = Some(default_index).filter(|index| *index >= MAX_DEVICE_PROBE);
+    let mut sources: Vec<NamedSource> = (0..MAX_DEVICE_PROBE)
+        .chain(extra_index)
+        .filter(|index| {
+            VideoCapture::new(*index, videoio::CAP_ANY)
+                .and_then(|capture| capture.is_opened())
+                .unwrap_or(false)
+        })
+        .map(|index| NamedSource {
+            name: device_source_name(index),
+            source: FrameSource::Device { index },
+        })
+        .collect();
+
+    if let Ok(env) = std::env::var("SOURCES") {
+        for entry in env.split(';').filter(|entry| !entry.is_empty()) {
+            match entry.split_once('=') {
+                Some((name, spec)) => sources.push(NamedSource {
+                    name: name.to_string(),
+                    source: FrameSource::parse(spec),
+                }),
+                None => println!("WARN: INVALID SOURCES ENTRY({})", entry),
+            }
+        }
+    }
+    sources
+}
+
+// DEV_NUMBERで指定されたカメラを、ソース未指定時のデフォルトとする
+pub fn default_source_name() -> String {
+    device_source_name(utils::get_dev_number())
+}
+
+fn device_source_name(index: i32) -> String {
+    format!("cam{}", index)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn network_urls_are_parsed_as_network_sources() {
+        for spec in [
+            "rtsp://192.168.0.10/stream",
+            "http://host:8080/mjpeg?source=cam0",
+            "https://example.com/live.mjpg",
+        ] {
+            assert!(matches!(
+                FrameSource::parse(spec),
+                FrameSource::Network { url } if url == spec
+            ));
+        }
+    }
+
+    #[test]
+    fn gstreamer_prefix_is_stripped() {
+        let expected = "videotestsrc ! videoconvert ! appsink";
+        let source = FrameSource::parse(&format!("gst:{}", expected));
+        assert!(matches!(
+            source,
+            FrameSource::Gstreamer { pipeline } if pipeline == expected
+        ));
+    }
+
+    #[test]
+    fn other_specs_are_parsed_as_files() {
+        for spec in ["/data/door.mp4", "door.mp4", "ftp://host/video.mp4"] {
+            assert!(matches!(
+                FrameSource::parse(spec),
+                FrameSource::File { path } if path == spec
+            ));
+        }
+    }
+
+    #[test]
+    fn only_network_and_gstreamer_sources_reconnect() {
+        assert!(FrameSource::parse("rtsp://host/stream").is_remote());
+        assert!(FrameSource::parse("gst:videotestsrc ! appsink").is_remote());
+        assert!(!FrameSource::parse("/data/door.mp4").is_remote());
+        assert!(!FrameSource::Device { index: 0 }.is_remote());
+    }
+}
@@ -0,0 +1,234 @@
+use opencv::core::{Mat, Ptr, Scalar, Size, Vec3b};
+use opencv::ximgproc::{self, SuperpixelSEEDS};
+use opencv::{imgproc, prelude::*};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SuperpixelAlgorithm {
+    Slic,
+    Slico,
+    Mslic,
+    Seeds,
+    Lsc,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SuperpixelOverlay {
+    Mask,     // 境界線のみの白黒画像
+    Contours, // 元画像に境界線を重ねる
+    Mean,     // セグメントごとの平均色で塗りつぶす
+}
+
+/*
+* {"algorithm": "slico", "region_size": 20, "iterations": 10, "overlay": "mean"}
+* region_size, ruler     -> SLIC, SLICO, MSLIC
+* region_size, ratio     -> LSC
+* num_superpixels, num_levels, prior, histogram_bins -> SEEDS
+* label_map: true の場合、label_map_step画素ごとに間引いたラベルをメタデータに含める
+*/
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct SuperpixelParams {
+    pub algorithm: SuperpixelAlgorithm,
+    pub region_size: i32,
+    pub ruler: f32,
+    pub ratio: f32,
+    pub num_superpixels: i32,
+    pub num_levels: i32,
+    pub prior: i32,
+    pub histogram_bins: i32,
+    pub iterations: i32,
+    pub min_element_size: i32, // SLIC, LSCでこれより小さいセグメントを隣に統合する(%)
+    pub overlay: SuperpixelOverlay,
+    pub color: [f64; 3], // 境界線の色(BGR)
+    pub label_map: bool,
+    pub label_map_step: i32,
+}
+
+impl Default for SuperpixelParams {
+    fn default() -> Self {
+        Self {
+            algorithm: SuperpixelAlgorithm::Slic,
+            region_size: 25,
+            ruler: 10.0,
+            ratio: 0.075,
+            num_superpixels: 400,
+            num_levels: 4,
+            prior: 2,
+            histogram_bins: 5,
+            iterations: 10,
+            min_element_size: 25,
+            overlay: SuperpixelOverlay::Contours,
+            color: [0.0, 0.0, 255.0],
+            label_map: false,
+            label_map_step: 8,
+        }
+    }
+}
+
+pub struct Segmentation {
+    pub labels: Mat, // CV_32SC1
+    pub contour_mask: Mat,
+    pub count: i32,
+}
+
+// SEEDSは画像サイズとパラメータが変わらない限り使い回せるため、ノードの状態として保持する
+#[derive(Default)]
+pub struct SuperpixelCache {
+    seeds_key: Option<(Size, SuperpixelParams)>,
+    seeds: Option<Ptr<SuperpixelSEEDS>>,
+}
+
+impl SuperpixelCache {
+    fn seeds(
+        &mut self,
+        frame: &Mat,
+        params: &SuperpixelParams,
+    ) -> Result<&mut Ptr<SuperpixelSEEDS>, opencv::Error> {
+        let key = Some((frame.size()?, params.clone()));
+        if self.seeds.is_none() || self.seeds_key != key {
+            self.seeds = Some(ximgproc::create_superpixel_seeds(
+                frame.cols(),
+                frame.rows(),
+                frame.channels(),
+                params.num_superpixels,
+                params.num_levels,
+                params.prior,
+                params.histogram_bins,
+                false,
+            )?);
+            self.seeds_key = key;
+        }
+        Ok(self.seeds.as_mut().unwrap())
+    }
+}
+
+pub fn segment(
+    frame: &Mat,
+    cache: &mut SuperpixelCache,
+    params: &SuperpixelParams,
+) -> Result<Segmentation, opencv::Error> {
+    let mut labels = Mat::default();
+    let mut contour_mask = Mat::default();
+    let count = match params.algorithm {
+        SuperpixelAlgorithm::Slic | SuperpixelAlgorithm::Slico | SuperpixelAlgorithm::Mslic => {
+            let algorithm = match params.algorithm {
+                SuperpixelAlgorithm::Slico => ximgproc::SLICO,
+                SuperpixelAlgorithm::Mslic => ximgproc::MSLIC,
+                _ => ximgproc::SLIC,
+            };
+            let mut slic = ximgproc::create_superpixel_slic(
+                frame,
+                algorithm,
+                params.region_size,
+                params.ruler,
+            )?;
+            slic.iterate(params.iterations)?;
+            slic.enforce_label_connectivity(params.min_element_size)?;
+            slic.get_labels(&mut labels)?;
+            slic.get_label_contour_mask(&mut contour_mask, true)?;
+            slic.get_number_of_superpixels()?
+        }
+        SuperpixelAlgorithm::Seeds => {
+            let seeds = cache.seeds(frame, params)?;
+            seeds.iterate(frame, params.iterations)?;
+            seeds.get_labels(&mut labels)?;
+            seeds.get_label_contour_mask(&mut contour_mask, true)?;
+            seeds.get_number_of_superpixels()?
+        }
+        SuperpixelAlgorithm::Lsc => {
+            let mut lsc = ximgproc::create_superpixel_lsc(frame, params.region_size, params.ratio)?;
+            lsc.iterate(params.iterations)?;
+            lsc.enforce_label_connectivity(params.min_element_size)?;
+            lsc.get_labels(&mut labels)?;
+            lsc.get_label_contour_mask(&mut contour_mask, true)?;
+            lsc.get_number_of_superpixels()?
+        }
+    };
+    Ok(Segmentation {
+        labels,
+        contour_mask,
+        count,
+    })
+}
+
+pub fn render(
+    frame: &Mat,
+    segmentation: &Segmentation,
+    params: &SuperpixelParams,
+) -> Result<Mat, opencv::Error> {
+    let color = Scalar::new(params.color[0], params.color[1], params.color[2], 0.0);
+    match params.overlay {
+        SuperpixelOverlay::Mask => {
+            let mut mask_frame = Mat::default();
+            imgproc::cvt_color(
+                &segmentation.contour_mask,
+                &mut mask_frame,
+                imgproc::COLOR_GRAY2BGR,
+                0,
+            )?;
+            Ok(mask_frame)
+        }
+        SuperpixelOverlay::Contours => {
+            let mut result = frame.clone();
+            result.set_to(&color, &segmentation.contour_mask)?;
+            Ok(result)
+        }
+        SuperpixelOverlay::Mean => fill_mean_color(frame, segmentation),
+    }
+}
+
+// セグメントごとに平均色を求めて塗りつぶす
+fn fill_mean_color(frame: &Mat, segmentation: &Segmentation) -> Result<Mat, opencv::Error> {
+    let count = segmentation.count.max(0) as usize;
+    let labels = segmentation.labels.data_typed::<i32>()?;
+    let mut sums = vec![[0u64; 3]; count];
+    let mut counts = vec![0u64; count];
+
+    let mut result = frame.clone();
+    for (pixel, label) in result.data_typed::<Vec3b>()?.iter().zip(labels) {
+        let Some(sum) = sums.get_mut(*label as usize) else {
+            continue;
+        };
+        for (total, value) in sum.iter_mut().zip(pixel.iter()) {
+            *total += *value as u64;
+        }
+        counts[*label as usize] += 1;
+    }
+    let means: Vec<Vec3b> = sums
+        .iter()
+        .zip(counts.iter())
+        .map(|(sum, count)| {
+            let count = (*count).max(1);
+            Vec3b::from([
+                (sum[0] / count) as u8,
+                (sum[1] / count) as u8,
+                (sum[2] / count) as u8,
+            ])
+        })
+        .collect();
+    for (pixel, label) in result.data_typed_mut::<Vec3b>()?.iter_mut().zip(labels) {
+        if let Some(mean) = means.get(*label as usize) {
+            *pixel = *mean;
+        }
+    }
+    Ok(result)
+}
+
+/*
+* stepごとに間引いたラベル
+* {"width": 80, "height": 60, "step": 8, "labels": [0, 0, 1, ...]}
+*/
+pub fn label_map(labels: &Mat, step: i32) -> Result<serde_json::Value, opencv::Error> {
+    let step = step.max(1);
+    let size = Size::new((labels.cols() / step).max(1), (labels.rows() / step).max(1));
+    let mut sampled = Mat::default();
+    imgproc::resize(labels, &mut sampled, size, 0.0, 0.0, imgproc::INTER_NEAREST)?;
+    Ok(serde_json::json!({
+        "width": size.width,
+        "height": size.height,
+        "step": step,
+        "labels": sampled.data_typed::<i32>()?,
+    }))
+}
@@ -0,0 +1,230 @@
+use crate::camera::cascade::MODEL_DIR;
+use crate::camera::utils::MAX_IMAGE_SIZE;
+use opencv::core::{Mat, Ptr, Rect, Size, StsBadArg, StsError};
+use opencv::dnn_superres::DnnSuperResImpl;
+use opencv::{imgproc, prelude::*};
+use std::path::Path;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SuperResAlgorithm {
+    Fsrcnn,
+    Espcn,
+    Lapsrn,
+    Edsr,
+}
+
+impl SuperResAlgorithm {
+    // DnnSuperResImpl::set_modelに渡す名前
+    fn name(&self) -> &'static str {
+        match self {
+            SuperResAlgorithm::Fsrcnn => "fsrcnn",
+            SuperResAlgorithm::Espcn => "espcn",
+            SuperResAlgorithm::Lapsrn => "lapsrn",
+            SuperResAlgorithm::Edsr => "edsr",
+        }
+    }
+
+    // 配布されているモデルのファイル名(FSRCNN_x2.pbなど)
+    fn official_name(&self) -> &'static str {
+        match self {
+            SuperResAlgorithm::Fsrcnn => "FSRCNN",
+            SuperResAlgorithm::Espcn => "ESPCN",
+            SuperResAlgorithm::Lapsrn => "LapSRN",
+            SuperResAlgorithm::Edsr => "EDSR",
+        }
+    }
+
+    fn scales(&self) -> &'static [i32] {
+        match self {
+            SuperResAlgorithm::Lapsrn => &[2, 4, 8],
+            _ => &[2, 3, 4],
+        }
+    }
+
+    /*
+     * モデルディレクトリから倍率に合うモデルを探す
+     * fsrcnn, x2 -> fsrcnn2.pb, fsrcnn.pb, FSRCNN_x2.pb の順
+     * fsrcnn, x4 -> fsrcnn4.pb, FSRCNN_x4.pb の順
+     */
+    fn model_path(&self, scale: i32) -> Result<String, opencv::Error> {
+        if !self.scales().contains(&scale) {
+            let message = format!("{} does not support x{}", self.name(), scale);
+            return Err(opencv::Error::new(StsBadArg, message));
+        }
+        let mut candidates = vec![format!("{}{}.pb", self.name(), scale)];
+        if scale == 2 {
+            candidates.push(format!("{}.pb", self.name()));
+        }
+        candidates.push(format!("{}_x{}.pb", self.official_name(), scale));
+        candidates
+            .iter()
+            .map(|file| format!("{}/{}", MODEL_DIR, file))
+            .find(|path| Path::new(path).exists())
+            .ok_or_else(|| {
+                let message = format!("model not found: {} x{}", self.name(), scale);
+                opencv::Error::new(StsError, message)
+            })
+    }
+}
+
+/*
+* {"algorithm": "espcn", "scale": 4, "crop": [0.25, 0.25, 0.5, 0.5], "max_output": [1920, 1080]}
+* crop   -> 拡大前に切り出す範囲(画像に対する相対位置 [x, y, width, height])。デジタルズーム
+* max_output -> 出力サイズの上限。超える場合は入力を縮小してから拡大する
+*               MAX_IMAGE_SIZEを超える値や0以下の値はMAX_IMAGE_SIZEとして扱う
+*/
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct SuperResParams {
+    pub algorithm: SuperResAlgorithm,
+    pub scale: i32,
+    pub crop: Option<[f64; 4]>,
+    pub max_output: [i32; 2],
+}
+
+impl Default for SuperResParams {
+    fn default() -> Self {
+        Self {
+            algorithm: SuperResAlgorithm::Fsrcnn,
+            scale: 2,
+            crop: None,
+            max_output: [1920, 1080],
+        }
+    }
+}
+
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct SuperResResult {
+    pub algorithm: SuperResAlgorithm,
+    pub scale: i32,
+    pub input: [i32; 2],
+    pub output: [i32; 2],
+    pub limited: bool, // max_outputを超えるため入力を縮小した
+}
+
+// 読み込んだモデルをノードの状態として保持し、アルゴリズムか倍率が変わった場合のみ読み直す
+#[derive(Default)]
+pub struct SuperResCache {
+    model: Option<(SuperResAlgorithm, i32)>,
+    sr: Option<Ptr<DnnSuperResImpl>>,
+}
+
+impl SuperResCache {
+    fn get(
+        &mut self,
+        algorithm: SuperResAlgorithm,
+        scale: i32,
+    ) -> Result<&mut Ptr<DnnSuperResImpl>, opencv::Error> {
+        if self.sr.is_none() || self.model != Some((algorithm, scale)) {
+            let mut sr = DnnSuperResImpl::create()?;
+            sr.read_model(&algorithm.model_path(scale)?)?;
+            sr.set_model(algorithm.name(), scale)?;
+            self.sr = Some(sr);
+            self.model = Some((algorithm, scale));
+        }
+        Ok(self.sr.as_mut().unwrap())
+    }
+}
+
+// 拡大後のサイズが上限に収まるよう、入力に掛ける縮小率を求める(1以上なら縮小不要)
+fn input_ratio(size: Size, scale: i32, max_output: [i32; 2]) -> f64 {
+    let ratio_to = |max: i32, length: i32| {
+        let max = match max {
+            max if max > 0 => max.min(MAX_IMAGE_SIZE),
+            _ => MAX_IMAGE_SIZE,
+        };
+        max as f64 / (length * scale) as f64
+    };
+    let [max_width, max_height] = max_output;
+    f64::min(
+        ratio_to(max_width, size.width),
+        ratio_to(max_height, size.height),
+    )
+}
+
+pub fn upsample(
+    frame: &Mat,
+    cache: &mut SuperResCache,
+    params: &SuperResParams,
+) -> Result<(Mat, SuperResResult), opencv::Error> {
+    let sr = cache.get(params.algorithm, params.scale)?;
+    let mut input = match params.crop {
+        Some(crop) => crop_frame(frame, crop)?,
+        None => frame.clone(),
+    };
+
+    // 超解像を重ねた場合などに出力が大きくなりすぎないようにする
+    let size = input.size()?;
+    let ratio = input_ratio(size, params.scale, params.max_output);
+    let limited = ratio < 1.0;
+    if limited {
+        let limited_size = Size::new(
+            ((size.width as f64 * ratio) as i32).max(1),
+            ((size.height as f64 * ratio) as i32).max(1),
+        );
+        let mut resized = Mat::default();
+        imgproc::resize(
+            &input,
+            &mut resized,
+            limited_size,
+            0.0,
+            0.0,
+            imgproc::INTER_AREA,
+        )?;
+        input = resized;
+    }
+
+    let mut result = Mat::default();
+    sr.upsample(&input, &mut result)?;
+    let input_size = input.size()?;
+    let output_size = result.size()?;
+    let info = SuperResResult {
+        algorithm: params.algorithm,
+        scale: params.scale,
+        input: [input_size.width, input_size.height],
+        output: [output_size.width, output_size.height],
+        limited,
+    };
+    Ok((result, info))
+}
+
+fn crop_frame(frame: &Mat, crop: [f64; 4]) -> Result<Mat, opencv::Error> {
+    let [x, y, width, height] = crop;
+    let (cols, rows) = (frame.cols() as f64, frame.rows() as f64);
+    let rect = Rect::new(
+        (cols * x) as i32,
+        (rows * y) as i32,
+        (cols * width) as i32,
+        (rows * height) as i32,
+    ) & Rect::new(0, 0, frame.cols(), frame.rows());
+    if rect.empty() {
+        return Err(opencv::Error::new(StsBadArg, "crop is out of the frame"));
+    }
+    Mat::roi(frame, rect)?.try_clone()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn output_within_max_output_is_not_limited() {
+        let ratio = input_ratio(Size::new(640, 480), 2, [1920, 1080]);
+        assert!(ratio >= 1.0);
+    }
+
+    #[test]
+    fn max_output_limits_the_larger_side() {
+        let ratio = input_ratio(Size::new(1280, 480), 2, [1920, 1080]);
+        assert_eq!(ratio, 0.75);
+    }
+
+    #[test]
+    fn output_is_capped_at_max_image_size_without_max_output() {
+        for max_output in [[0, 0], [-1, 0], [10000, 10000]] {
+            let ratio = input_ratio(Size::new(4096, 1024), 4, max_output);
+            assert_eq!(ratio, 0.25);
+        }
+    }
+}
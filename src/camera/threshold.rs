@@ -0,0 +1,191 @@
+use crate::camera::utils;
+use opencv::core::{Mat, StsBadArg};
+use opencv::imgproc;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ThresholdMethod {
+    Fixed,            // thresholdの値をそのまま使う
+    Otsu,             // 大津の二値化(ヒストグラムから自動で決める)
+    Triangle,         // 三角法(ヒストグラムの山が1つの場合に向く)
+    AdaptiveMean,     // 周囲block_size画素の平均 - c
+    AdaptiveGaussian, // 周囲block_size画素のガウシアン加重平均 - c
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ThresholdType {
+    Binary,
+    BinaryInv,
+    Trunc,
+    ToZero,
+    ToZeroInv,
+}
+
+impl ThresholdType {
+    fn value(&self) -> i32 {
+        match self {
+            ThresholdType::Binary => imgproc::THRESH_BINARY,
+            ThresholdType::BinaryInv => imgproc::THRESH_BINARY_INV,
+            ThresholdType::Trunc => imgproc::THRESH_TRUNC,
+            ThresholdType::ToZero => imgproc::THRESH_TOZERO,
+            ThresholdType::ToZeroInv => imgproc::THRESH_TOZERO_INV,
+        }
+    }
+}
+
+/*
+* {"method": "otsu", "type": "binary_inv"}
+* {"method": "adaptive_gaussian", "block_size": 11, "c": 2.0}
+* 適応的二値化はbinary, binary_invのみ。block_sizeは3以上の奇数(偶数の場合は+1する)
+*/
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct ThresholdParams {
+    pub method: ThresholdMethod,
+    #[serde(rename = "type")]
+    pub threshold_type: ThresholdType,
+    pub threshold: f64,
+    pub max_value: f64,
+    pub block_size: i32,
+    pub c: f64,
+}
+
+impl Default for ThresholdParams {
+    fn default() -> Self {
+        Self {
+            method: ThresholdMethod::Fixed,
+            threshold_type: ThresholdType::Binary,
+            threshold: 200.0,
+            max_value: 255.0,
+            block_size: 11,
+            c: 2.0,
+        }
+    }
+}
+
+// 二値化した画像と、実際に使われた閾値(適応的二値化の場合はNone)
+pub fn threshold(
+    frame: &Mat,
+    params: &ThresholdParams,
+) -> Result<(Mat, Option<f64>), opencv::Error> {
+    let gray_frame = utils::to_gray(frame)?;
+    let mut binary_frame = Mat::default();
+    let adaptive_method = match params.method {
+        ThresholdMethod::AdaptiveMean => imgproc::ADAPTIVE_THRESH_MEAN_C,
+        ThresholdMethod::AdaptiveGaussian => imgproc::ADAPTIVE_THRESH_GAUSSIAN_C,
+        _ => {
+            let automatic = match params.method {
+                ThresholdMethod::Otsu => imgproc::THRESH_OTSU,
+                ThresholdMethod::Triangle => imgproc::THRESH_TRIANGLE,
+                _ => 0,
+            };
+            let computed = imgproc::threshold(
+                &gray_frame,
+                &mut binary_frame,
+                params.threshold,
+                params.max_value,
+                params.threshold_type.value() | automatic,
+            )?;
+            return Ok((binary_frame, Some(computed)));
+        }
+    };
+
+    if !matches!(
+        params.threshold_type,
+        ThresholdType::Binary | ThresholdType::BinaryInv
+    ) {
+        return Err(opencv::Error::new(
+            StsBadArg,
+            "adaptive threshold supports only binary and binary_inv",
+        ));
+    }
+    imgproc::adaptive_threshold(
+        &gray_frame,
+        &mut binary_frame,
+        params.max_value,
+        adaptive_method,
+        params.threshold_type.value(),
+        params.block_size.max(3) | 1,
+        params.c,
+    )?;
+    Ok((binary_frame, None))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use opencv::prelude::*;
+
+    // 左半分が暗く右半分が明るい画像
+    fn two_level_frame() -> Mat {
+        Mat::from_slice_2d(&[[20u8, 20, 200, 200], [20, 20, 200, 200]]).unwrap()
+    }
+
+    fn params(method: ThresholdMethod, threshold_type: ThresholdType) -> ThresholdParams {
+        ThresholdParams {
+            method,
+            threshold_type,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn fixed_threshold_returns_the_given_value() {
+        let params = ThresholdParams {
+            threshold: 100.0,
+            ..params(ThresholdMethod::Fixed, ThresholdType::BinaryInv)
+        };
+        let (binary, computed) = threshold(&two_level_frame(), &params).unwrap();
+        assert_eq!(computed, Some(100.0));
+        assert_eq!(
+            binary.data_bytes().unwrap(),
+            [255, 255, 0, 0, 255, 255, 0, 0]
+        );
+    }
+
+    #[test]
+    fn automatic_methods_compute_the_threshold() {
+        let otsu = params(ThresholdMethod::Otsu, ThresholdType::Binary);
+        let (binary, computed) = threshold(&two_level_frame(), &otsu).unwrap();
+        assert!((20.0..200.0).contains(&computed.unwrap()));
+        assert_eq!(
+            binary.data_bytes().unwrap(),
+            [0, 0, 255, 255, 0, 0, 255, 255]
+        );
+
+        let triangle = params(ThresholdMethod::Triangle, ThresholdType::Binary);
+        let (_, computed) = threshold(&two_level_frame(), &triangle).unwrap();
+        assert!(computed.is_some());
+    }
+
+    #[test]
+    fn adaptive_methods_accept_only_binary_types() {
+        for threshold_type in [
+            ThresholdType::Trunc,
+            ThresholdType::ToZero,
+            ThresholdType::ToZeroInv,
+        ] {
+            let params = params(ThresholdMethod::AdaptiveMean, threshold_type);
+            assert!(threshold(&two_level_frame(), &params).is_err());
+        }
+        let params = params(ThresholdMethod::AdaptiveGaussian, ThresholdType::BinaryInv);
+        let (_, computed) = threshold(&two_level_frame(), &params).unwrap();
+        assert_eq!(computed, None);
+    }
+
+    #[test]
+    fn even_or_small_block_sizes_are_corrected() {
+        for block_size in [-1, 0, 1, 2, 4] {
+            let params = ThresholdParams {
+                block_size,
+                ..params(ThresholdMethod::AdaptiveMean, ThresholdType::Binary)
+            };
+            assert!(
+                threshold(&two_level_frame(), &params).is_ok(),
+                "{}",
+                block_size
+            );
+        }
+    }
+}
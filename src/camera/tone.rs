@@ -0,0 +1,352 @@
+use opencv::core::{self, Mat, Size, StsBadArg};
+use opencv::{imgproc, prelude::*};
+
+// 8bitの画素値の数(ルックアップテーブルの大きさ)
+const LEVELS: usize = 256;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EqualizeMethod {
+    #[default]
+    Global,
+    Clahe, // 小領域ごとに平坦化する(明るさの偏った画像向き)
+}
+
+/*
+* {"method": "clahe", "clip_limit": 2.0, "tile_grid": [8, 8]}
+* カラー画像は輝度(YCrCbのY)のみ平坦化して色合いを保つ
+* clip_limit, tile_grid -> claheのコントラストの制限と、画像を分割する数
+*/
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct EqualizeParams {
+    pub method: EqualizeMethod,
+    pub clip_limit: f64,
+    pub tile_grid: [i32; 2],
+}
+
+impl Default for EqualizeParams {
+    fn default() -> Self {
+        Self {
+            method: EqualizeMethod::Global,
+            clip_limit: 2.0,
+            tile_grid: [8, 8],
+        }
+    }
+}
+
+pub fn equalize(frame: &Mat, params: &EqualizeParams) -> Result<Mat, opencv::Error> {
+    on_luminance(frame, |luminance| {
+        let mut equalized = Mat::default();
+        match params.method {
+            EqualizeMethod::Global => imgproc::equalize_hist(luminance, &mut equalized)?,
+            EqualizeMethod::Clahe => {
+                let [cols, rows] = params.tile_grid;
+                let mut clahe =
+                    imgproc::create_clahe(params.clip_limit, Size::new(cols.max(1), rows.max(1)))?;
+                clahe.apply(luminance, &mut equalized)?;
+            }
+        }
+        Ok(equalized)
+    })
+}
+
+// グレースケールはそのまま、カラーはYCrCbのYチャンネルのみ処理する
+fn on_luminance(
+    frame: &Mat,
+    process: impl Fn(&Mat) -> Result<Mat, opencv::Error>,
+) -> Result<Mat, opencv::Error> {
+    if frame.channels() == 1 {
+        return process(frame);
+    }
+    let mut ycrcb = Mat::default();
+    imgproc::cvt_color(frame, &mut ycrcb, imgproc::COLOR_BGR2YCrCb, 0)?;
+    let mut luminance = Mat::default();
+    core::extract_channel(&ycrcb, &mut luminance, 0)?;
+    core::insert_channel(&process(&luminance)?, &mut ycrcb, 0)?;
+    let mut result = Mat::default();
+    imgproc::cvt_color(&ycrcb, &mut result, imgproc::COLOR_YCrCb2BGR, 0)?;
+    Ok(result)
+}
+
+/*
+* {"gamma": 2.2}
+* 出力 = 255 * (入力 / 255)^(1 / gamma)。1.0より大きいと暗い部分が明るくなる
+*/
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct GammaParams {
+    pub gamma: f64,
+}
+
+impl Default for GammaParams {
+    fn default() -> Self {
+        Self { gamma: 2.2 }
+    }
+}
+
+pub fn gamma_lut(params: &GammaParams) -> Result<Mat, opencv::Error> {
+    if params.gamma <= 0.0 {
+        let message = format!("gamma must be positive: {}", params.gamma);
+        return Err(opencv::Error::new(StsBadArg, message));
+    }
+    let max = (LEVELS - 1) as f64;
+    build_lut(|value| max * (value / max).powf(1.0 / params.gamma))
+}
+
+/*
+* {"value": 30}
+* 全ての画素値にvalueを足す(-255〜255)
+*/
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct BrightnessParams {
+    pub value: f64,
+}
+
+impl Default for BrightnessParams {
+    fn default() -> Self {
+        Self { value: 30.0 }
+    }
+}
+
+pub fn brightness_lut(params: &BrightnessParams) -> Result<Mat, opencv::Error> {
+    build_lut(|value| value + params.value)
+}
+
+/*
+* {"value": 1.5, "pivot": 128}
+* 出力 = (入力 - pivot) * value + pivot。1.0より大きいとコントラストが強くなる
+*/
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct ContrastParams {
+    pub value: f64,
+    pub pivot: f64,
+}
+
+impl Default for ContrastParams {
+    fn default() -> Self {
+        Self {
+            value: 1.5,
+            pivot: 128.0,
+        }
+    }
+}
+
+pub fn contrast_lut(params: &ContrastParams) -> Result<Mat, opencv::Error> {
+    build_lut(|value| (value - params.pivot) * params.value + params.pivot)
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CurveInterpolation {
+    Linear, // 折れ線
+    #[default]
+    Smooth, // 単調な3次スプライン(点の間で行き過ぎない)
+}
+
+/*
+* {"points": [[0, 0], [64, 48], [192, 216], [255, 255]], "interpolation": "smooth"}
+* points -> 入力と出力の画素値(0〜255)の組。入力の順に並べ替えて使い、範囲外は端の点の出力とする
+*/
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct ToneCurveParams {
+    pub points: Vec<[f64; 2]>,
+    pub interpolation: CurveInterpolation,
+}
+
+impl Default for ToneCurveParams {
+    // 暗部を締めて明部を持ち上げるS字カーブ
+    fn default() -> Self {
+        Self {
+            points: vec![[0.0, 0.0], [64.0, 48.0], [192.0, 216.0], [255.0, 255.0]],
+            interpolation: CurveInterpolation::Smooth,
+        }
+    }
+}
+
+// 作ったルックアップテーブルをノードの状態として保持し、パラメータが変わった場合のみ作り直す
+#[derive(Default)]
+pub struct ToneCurveCache {
+    params: Option<ToneCurveParams>,
+    lut: Mat,
+}
+
+impl ToneCurveCache {
+    pub fn get(&mut self, params: &ToneCurveParams) -> Result<&Mat, opencv::Error> {
+        if self.params.as_ref() != Some(params) {
+            self.lut = tone_curve_lut(params)?;
+            self.params = Some(params.clone());
+        }
+        Ok(&self.lut)
+    }
+}
+
+fn tone_curve_lut(params: &ToneCurveParams) -> Result<Mat, opencv::Error> {
+    let mut points = params.points.clone();
+    points.sort_by(|a, b| a[0].total_cmp(&b[0]));
+    points.dedup_by(|a, b| a[0] == b[0]);
+    if points.len() < 2 {
+        let message = "tone curve needs at least 2 points with different inputs";
+        return Err(opencv::Error::new(StsBadArg, message));
+    }
+    let slopes = match params.interpolation {
+        CurveInterpolation::Linear => None,
+        CurveInterpolation::Smooth => Some(monotone_slopes(&points)),
+    };
+    let last = points.len() - 1;
+    build_lut(|value| {
+        if value <= points[0][0] {
+            return points[0][1];
+        }
+        if value >= points[last][0] {
+            return points[last][1];
+        }
+        let k = points
+            .iter()
+            .rposition(|point| point[0] <= value)
+            .unwrap_or(0);
+        let ([x0, y0], [x1, y1]) = (points[k], points[k + 1]);
+        let h = x1 - x0;
+        let t = (value - x0) / h;
+        match &slopes {
+            None => y0 + (y1 - y0) * t,
+            // 3次エルミート補間
+            Some(m) => {
+                let (t2, t3) = (t * t, t * t * t);
+                (2.0 * t3 - 3.0 * t2 + 1.0) * y0
+                    + (t3 - 2.0 * t2 + t) * h * m[k]
+                    + (-2.0 * t3 + 3.0 * t2) * y1
+                    + (t3 - t2) * h * m[k + 1]
+            }
+        }
+    })
+}
+
+// Fritsch-Carlson法で、単調な区間では単調性を保つ各点の傾きを求める
+fn monotone_slopes(points: &[[f64; 2]]) -> Vec<f64> {
+    let secants: Vec<f64> = points
+        .windows(2)
+        .map(|pair| (pair[1][1] - pair[0][1]) / (pair[1][0] - pair[0][0]))
+        .collect();
+    let n = points.len();
+    let mut slopes = vec![0.0; n];
+    slopes[0] = secants[0];
+    slopes[n - 1] = secants[n - 2];
+    // 前後で増減が変わる点は傾きを0にする
+    for (k, pair) in secants.windows(2).enumerate() {
+        if pair[0] * pair[1] > 0.0 {
+            slopes[k + 1] = (pair[0] + pair[1]) / 2.0;
+        }
+    }
+    for (k, secant) in secants.iter().enumerate() {
+        if *secant == 0.0 {
+            slopes[k] = 0.0;
+            slopes[k + 1] = 0.0;
+            continue;
+        }
+        let (a, b) = (slopes[k] / secant, slopes[k + 1] / secant);
+        let norm = a.hypot(b);
+        if norm > 3.0 {
+            slopes[k] = 3.0 / norm * a * secant;
+            slopes[k + 1] = 3.0 / norm * b * secant;
+        }
+    }
+    slopes
+}
+
+// 0〜255の各画素値に対する出力を丸めてテーブルにする
+fn build_lut(curve: impl Fn(f64) -> f64) -> Result<Mat, opencv::Error> {
+    let values: Vec<u8> = (0..LEVELS)
+        .map(|value| curve(value as f64).round().clamp(0.0, 255.0) as u8)
+        .collect();
+    Mat::from_slice(&values)?.try_clone()
+}
+
+// カラー画像は各チャンネルに同じテーブルを適用する
+pub fn apply_lut(frame: &Mat, lut: &Mat) -> Result<Mat, opencv::Error> {
+    let mut result = Mat::default();
+    core::lut(frame, lut, &mut result)?;
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lut_values(points: &[[f64; 2]], interpolation: CurveInterpolation) -> Vec<u8> {
+        let params = ToneCurveParams {
+            points: points.to_vec(),
+            interpolation,
+        };
+        let lut = tone_curve_lut(&params).unwrap();
+        lut.data_typed::<u8>().unwrap().to_vec()
+    }
+
+    fn is_monotonic(values: &[u8]) -> bool {
+        values.windows(2).all(|pair| pair[0] <= pair[1])
+    }
+
+    #[test]
+    fn identity_points_give_identity_lut() {
+        let identity: Vec<u8> = (0..=255).collect();
+        for interpolation in [CurveInterpolation::Linear, CurveInterpolation::Smooth] {
+            let points = [[0.0, 0.0], [128.0, 128.0], [255.0, 255.0]];
+            assert_eq!(lut_values(&points, interpolation), identity);
+        }
+    }
+
+    #[test]
+    fn smooth_curve_is_monotonic() {
+        let default_points = ToneCurveParams::default().points;
+        assert!(is_monotonic(&lut_values(
+            &default_points,
+            CurveInterpolation::Smooth
+        )));
+        // 急な区間の直後に緩やかな区間があっても行き過ぎない
+        let steep = [[0.0, 0.0], [10.0, 200.0], [20.0, 210.0], [255.0, 255.0]];
+        assert!(is_monotonic(&lut_values(
+            &steep,
+            CurveInterpolation::Smooth
+        )));
+    }
+
+    #[test]
+    fn flat_segment_stays_flat() {
+        let points = [[0.0, 0.0], [100.0, 100.0], [150.0, 100.0], [255.0, 255.0]];
+        let slopes = monotone_slopes(&points);
+        assert_eq!((slopes[1], slopes[2]), (0.0, 0.0));
+        let values = lut_values(&points, CurveInterpolation::Smooth);
+        assert!(values[100..=150].iter().all(|value| *value == 100));
+    }
+
+    #[test]
+    fn unsorted_points_and_outside_range_use_end_points() {
+        let points = [[200.0, 220.0], [50.0, 30.0]];
+        let values = lut_values(&points, CurveInterpolation::Linear);
+        assert_eq!(values[0], 30);
+        assert_eq!(values[50], 30);
+        assert_eq!(values[200], 220);
+        assert_eq!(values[255], 220);
+    }
+
+    #[test]
+    fn duplicate_inputs_keep_the_first_point() {
+        let points = [[0.0, 0.0], [128.0, 64.0], [128.0, 200.0], [255.0, 255.0]];
+        let values = lut_values(&points, CurveInterpolation::Linear);
+        assert_eq!(values[128], 64);
+    }
+
+    #[test]
+    fn fewer_than_two_points_is_an_error() {
+        for points in [vec![], vec![[10.0, 10.0]], vec![[10.0, 10.0], [10.0, 20.0]]] {
+            let params = ToneCurveParams {
+                points,
+                interpolation: CurveInterpolation::Smooth,
+            };
+            assert!(tone_curve_lut(&params).is_err());
+        }
+    }
+}
@@ -1,12 +1,31 @@
-use crate::camera::frame_handler;
-use opencv::core::{merge, no_array, prelude::*, split, Mat, Rect, Scalar, Size, Vector};
-use opencv::objdetect::CascadeClassifier;
+use opencv::core::{Mat, StsBadArg};
 use opencv::{imgproc, prelude::*};
 
+// パラメータで指定できる出力画像の幅・高さの上限
+pub const MAX_IMAGE_SIZE: i32 = 4096;
+
 pub fn is_grayscale(frame: &Mat) -> Result<bool, opencv::Error> {
     Ok(frame.channels() == 1)
 }
 
+pub fn to_gray(frame: &Mat) -> Result<Mat, opencv::Error> {
+    if is_grayscale(frame)? {
+        return Ok(frame.clone());
+    }
+    let mut gray_frame = Mat::default();
+    imgproc::cvt_color(frame, &mut gray_frame, imgproc::COLOR_BGR2GRAY, 0)?;
+    Ok(gray_frame)
+}
+
+// 出力画像の幅・高さが1〜MAX_IMAGE_SIZEでない場合はエラーを返す
+pub fn check_image_size(name: &str, value: i32) -> Result<(), opencv::Error> {
+    if !(1..=MAX_IMAGE_SIZE).contains(&value) {
+        let message = format!("{} must be between 1 and {}", name, MAX_IMAGE_SIZE);
+        return Err(opencv::Error::new(StsBadArg, message));
+    }
+    Ok(())
+}
+
 pub fn get_dev_number() -> i32 {
     const DEFAULT_DEV_NUMBER: i32 = 0;
     match std::env::var("DEV_NUMBER") {
@@ -23,48 +42,3 @@ pub fn get_dev_number() -> i32 {
         }
     }
 }
-
-pub fn remove_color_channel(frame: &Mat, channel_to_remove: usize) -> Result<Mat, opencv::Error> {
-    let mut channels: Vector<Mat> = Vector::new();
-    split(frame, &mut channels)?;
-
-    channels
-        .get(channel_to_remove as usize)?
-        .set_to(&Scalar::all(0.0), &no_array())?;
-
-    let mut result = Mat::default();
-    merge(&channels, &mut result)?;
-    Ok(result)
-}
-
-pub fn detect_object(frame: &Mat, model: &str, ract_color: Scalar) -> Result<Mat, opencv::Error> {
-    let mut cascade = CascadeClassifier::new(model)?;
-    let gray_frame = frame_handler::convert_to_gray(&frame)?;
-
-    // 物体を検出する
-    let mut objects = Vector::<Rect>::new();
-    cascade.detect_multi_scale(
-        &gray_frame,
-        &mut objects,
-        1.1,
-        2,
-        opencv::objdetect::CASCADE_FIND_BIGGEST_OBJECT,
-        Size::new(100, 100),
-        Size::new(0, 0),
-    )?;
-
-    // 検出された物体の周りに矩形を描画
-    let mut detected_frame = frame.clone();
-    for object in objects {
-        imgproc::rectangle(
-            &mut detected_frame,
-            object,
-            ract_color,
-            2,
-            imgproc::LINE_8,
-            0,
-        )?;
-    }
-
-    Ok(detected_frame)
-}
@@ -1,14 +1,31 @@
 mod camera;
 mod streaming;
 use axum::{routing::get, Router};
+use camera::source;
 use streaming::handlers;
+use streaming::sessions::AppState;
 
 #[tokio::main]
 async fn main() {
     let app = Router::new()
         .route("/", get(handlers::root_handler))
         .route("/ws", get(handlers::websocket_handler))
-        .route("/:file", get(handlers::static_content_handler));
+        .route("/mjpeg", get(handlers::mjpeg_handler))
+        .route("/api/sources", get(handlers::sources_handler))
+        .route("/api/models", get(handlers::models_handler))
+        .route("/api/aruco/marker", get(handlers::aruco_marker_handler))
+        .route("/api/aruco/board", get(handlers::aruco_board_handler))
+        .route(
+            "/api/sessions/:id/sources",
+            get(handlers::source_status_handler),
+        )
+        .route("/api/sessions/:id/codes", get(handlers::codes_handler))
+        .route(
+            "/api/sessions/:id/camera",
+            get(handlers::camera_properties_handler).put(handlers::set_camera_properties_handler),
+        )
+        .route("/:file", get(handlers::static_content_handler))
+        .with_state(AppState::new(source::probe_sources()));
     let listener = tokio::net::TcpListener::bind("0.0.0.0:8080").await.unwrap();
     axum::serve(listener, app).await.unwrap();
 }
@@ -0,0 +1,77 @@
+use crate::camera::calibration::CalibrationSettings;
+use crate::camera::camera::TapMode;
+use crate::streaming::encoding::EncodingSettings;
+use serde_json::{Map, Value};
+
+/*
+* {"type": "tap", "node": "canny", "mode": "pip"} -> 指定ノードの出力を小窓で表示
+* {"type": "tap", "node": null}                  -> タップを解除
+* {"type": "debug_grid", "enabled": true}        -> 全ステージの出力を格子状に並べる
+* {"type": "encoding", "codec": "webp", ...}       -> 配信する画像の形式・品質を変更
+* {"type": "ack_mode", "enabled": true}          -> 表示完了の通知を待ってから次のフレームを送る
+* {"type": "ack"}                                -> フレームの表示完了を通知
+* {"type": "get_camera_properties"}              -> カメラが対応するプロパティと現在値を取得
+* {"type": "set_camera_properties", "properties": {"exposure": -6, "fourcc": "MJPG"}}
+* {"type": "sample_color", "x": 0.5, "y": 0.5}   -> 画像上の相対位置の色と、追跡用のHSVの範囲を取得
+* {"type": "calibration_start", "pattern": "chessboard", "columns": 9, "rows": 6}
+*                                                -> キャリブレーションを開始し、パターンの検出結果を表示
+* {"type": "calibration_capture"}                -> 検出できているパターンを1枚分として追加
+* {"type": "calibration_finish"}                 -> 内部パラメータを求めてソースごとのファイルに保存
+* {"type": "calibration_cancel"}                 -> キャリブレーションを中止
+* {"type": "ptz", "pan": 0.5, "tilt": 0.5, "zoom": 2.0} -> 表示範囲の中心と拡大率を指定(省略した値は変更しない)
+* {"type": "ptz_drag", "dx": 0.1, "dy": 0.0}       -> 表示中の画像に対する相対的な移動量だけ動かす
+* {"type": "ptz_scroll", "factor": 1.25, "x": 0.5, "y": 0.5} -> 表示中の画像上のx, yを中心に拡大・縮小
+* {"type": "ptz_settings", "smoothing": 0.3, "auto_frame": true, ...} -> ePTZの設定を変更(省略した項目は変えない)
+* {"type": "ptz_reset"}                          -> 全体の表示に戻す
+*/
+#[derive(Debug, serde::Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Command {
+    Tap {
+        node: Option<String>,
+        #[serde(default)]
+        mode: TapMode,
+    },
+    DebugGrid {
+        enabled: bool,
+    },
+    Encoding(EncodingSettings),
+    AckMode {
+        enabled: bool,
+    },
+    Ack,
+    GetCameraProperties,
+    SetCameraProperties {
+        properties: Map<String, Value>,
+    },
+    SampleColor {
+        x: f64,
+        y: f64,
+    },
+    CalibrationStart(CalibrationSettings),
+    CalibrationCapture,
+    CalibrationFinish,
+    CalibrationCancel,
+    Ptz {
+        pan: Option<f64>,
+        tilt: Option<f64>,
+        zoom: Option<f64>,
+    },
+    PtzDrag {
+        dx: f64,
+        dy: f64,
+    },
+    PtzScroll {
+        factor: f64,
+        #[serde(default = "center")]
+        x: f64,
+        #[serde(default = "center")]
+        y: f64,
+    },
+    PtzSettings(Map<String, Value>),
+    PtzReset,
+}
+
+fn center() -> f64 {
+    0.5
+}
@@ -1,45 +1,142 @@
+use crate::camera::pipeline::ProcessNode;
+use serde_json::{Map, Value};
+use std::collections::HashMap;
+
+/*
+* source, target -> 処理名
+* source_id, target_id -> グラフ上のノードのID。省略した場合は処理名をIDとみなす
+*/
 #[derive(Debug, serde::Deserialize)]
 pub struct Connection {
     source: String,
     target: String,
+    #[serde(default)]
+    source_id: Option<String>,
+    #[serde(default)]
+    target_id: Option<String>,
 }
 
+impl Connection {
+    fn source_id(&self) -> &str {
+        self.source_id.as_deref().unwrap_or(&self.source)
+    }
+
+    fn target_id(&self) -> &str {
+        self.target_id.as_deref().unwrap_or(&self.target)
+    }
+}
+
+/*
+* {"nodes": [{"source": "cascade", "target": "camera", "source_id": "3", "target_id": "2"}],
+*  "params": {"3": {"model": "haarcascade_smile.xml", "min_neighbors": 20}}}
+* paramsはノードのIDごとのパラメータ(同じ処理のノードが複数あっても別々に指定できる)
+*/
 #[derive(Debug, serde::Deserialize)]
 pub struct Connections {
     pub nodes: Vec<Connection>,
+    #[serde(default)]
+    pub params: HashMap<String, Map<String, Value>>,
 }
 
 /*
 * connections = Vec({"3", "camera"}, {"2", "3"}, {"1", "2"});
 * convert_connections_to_process_chain(connections) -> Vec("1", "2", "3");
 */
-pub fn convert_connections_to_process_chain(connections: Vec<Connection>) -> Vec<String> {
+pub fn convert_connections_to_process_chain(connections: Connections) -> Vec<ProcessNode> {
     const LAST_TARGET: &str = "camera";
-    let mut successor: String = LAST_TARGET.to_string();
-    let mut process_chain: Vec<String> = vec![];
-
-    loop {
-        match find_preceding(&connections, successor.clone()) {
-            Some(preceding) => {
-                successor = preceding.clone();
-                process_chain.push(preceding);
-            }
-            None => break,
-        }
+    let mut process_chain: Vec<ProcessNode> = vec![];
+    let mut current = connections
+        .nodes
+        .iter()
+        .find(|conn| conn.target == LAST_TARGET);
+
+    // 循環している場合に止まるよう、接続の数を上限とする
+    while let Some(conn) = current.filter(|_| process_chain.len() < connections.nodes.len()) {
+        process_chain.push(ProcessNode {
+            name: conn.source.clone(),
+            params: connections
+                .params
+                .get(conn.source_id())
+                .cloned()
+                .unwrap_or_default(),
+        });
+        current = find_preceding(&connections.nodes, conn.source_id());
     }
     process_chain.reverse();
     process_chain
 }
 
 /*
-* find_preceding(Vec({"abc", "def"}, {"123", "456"}), "123") -> Some("456")
+* find_preceding(Vec({"abc", "def"}, {"123", "456"}), "456") -> Some({"123", "456"})
 * find_preceding(Vec({"abc", "def"}, {"123", "456"}), "xyz") -> None
 */
-fn find_preceding(connections: &Vec<Connection>, successor: String) -> Option<String> {
-    for conn in connections.iter() {
-        if conn.target == successor {
-            return Some(conn.source.clone());
-        }
+fn find_preceding<'a>(connections: &'a [Connection], successor_id: &str) -> Option<&'a Connection> {
+    connections
+        .iter()
+        .find(|conn| conn.target_id() == successor_id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn convert(connections: Value) -> Vec<ProcessNode> {
+        convert_connections_to_process_chain(serde_json::from_value(connections).unwrap())
+    }
+
+    fn names(chain: &[ProcessNode]) -> Vec<&str> {
+        chain.iter().map(|node| node.name.as_str()).collect()
+    }
+
+    #[test]
+    fn chain_is_ordered_from_first_node_to_camera() {
+        let chain = convert(json!({"nodes": [
+            {"source": "3", "target": "camera"},
+            {"source": "1", "target": "2"},
+            {"source": "2", "target": "3"},
+        ]}));
+        assert_eq!(names(&chain), ["1", "2", "3"]);
+    }
+
+    #[test]
+    fn nodes_not_connected_to_camera_are_ignored() {
+        let chain = convert(json!({"nodes": [
+            {"source": "gray", "target": "blur"},
+        ]}));
+        assert!(chain.is_empty());
+    }
+
+    #[test]
+    fn params_are_keyed_by_node_id() {
+        let chain = convert(json!({
+            "nodes": [
+                {"source": "blur", "target": "camera", "source_id": "4", "target_id": "2"},
+                {"source": "blur", "target": "blur", "source_id": "3", "target_id": "4"},
+            ],
+            "params": {"3": {"size": 3}, "4": {"size": 9}},
+        }));
+        assert_eq!(names(&chain), ["blur", "blur"]);
+        assert_eq!(chain[0].params["size"], 3);
+        assert_eq!(chain[1].params["size"], 9);
+    }
+
+    #[test]
+    fn params_are_keyed_by_name_without_ids() {
+        let chain = convert(json!({
+            "nodes": [{"source": "cascade", "target": "camera"}],
+            "params": {"cascade": {"min_neighbors": 20}},
+        }));
+        assert_eq!(chain[0].params["min_neighbors"], 20);
+    }
+
+    #[test]
+    fn cycles_do_not_loop_forever() {
+        let chain = convert(json!({"nodes": [
+            {"source": "a", "target": "camera"},
+            {"source": "b", "target": "a"},
+            {"source": "a", "target": "b"},
+        ]}));
+        assert_eq!(chain.len(), 3);
     }
-    None
 }
@@ -0,0 +1,337 @@
+use opencv::core::{Mat, Size, Vector};
+use opencv::{imgcodecs, imgproc, prelude::*};
+use std::fmt;
+use std::time::Duration;
+
+// 適応モードで下げられる品質・解像度の下限
+const MIN_QUALITY: i32 = 30;
+const MIN_SCALE: f64 = 0.25;
+const QUALITY_DOWN_STEP: i32 = 10;
+const QUALITY_UP_STEP: i32 = 5;
+const SCALE_STEP: f64 = 0.75;
+// 送信が余裕を持って間に合った回数がこれを超えたら品質を戻す
+const RECOVER_FRAMES: u32 = 30;
+// FPS未指定時に送信の遅延判定に使う間隔(30fps)
+const DEFAULT_FRAME_INTERVAL: Duration = Duration::from_millis(33);
+// 指定できるFPSの範囲
+const MIN_FPS: f64 = 0.1;
+const MAX_FPS: f64 = 240.0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Codec {
+    Jpeg,
+    Png,
+    Webp,
+}
+
+impl Codec {
+    fn extension(&self) -> &'static str {
+        match self {
+            Codec::Jpeg => ".jpg",
+            Codec::Png => ".png",
+            Codec::Webp => ".webp",
+        }
+    }
+}
+
+/*
+* {"type": "encoding", "codec": "webp", "quality": 80, "max_width": 640, "fps": 15, "adaptive": true}
+* 省略された項目はデフォルト値(jpeg, 品質95, 解像度・FPS制限なし, 適応なし)
+*/
+#[derive(Debug, Clone, serde::Deserialize)]
+#[serde(default)]
+pub struct EncodingSettings {
+    pub codec: Codec,
+    pub quality: i32,
+    pub max_width: Option<i32>,
+    pub max_height: Option<i32>,
+    pub fps: Option<f64>,
+    pub adaptive: bool,
+}
+
+impl Default for EncodingSettings {
+    fn default() -> Self {
+        Self {
+            codec: Codec::Jpeg,
+            quality: 95,
+            max_width: None,
+            max_height: None,
+            fps: None,
+            adaptive: false,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum EncodingError {
+    InvalidValue(&'static str),
+}
+
+impl fmt::Display for EncodingError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            EncodingError::InvalidValue(name) => write!(f, "invalid value for: {}", name),
+        }
+    }
+}
+
+pub struct Encoder {
+    settings: EncodingSettings,
+    // 適応モードで調整される現在の品質と縮小率
+    quality: i32,
+    scale: f64,
+    fast_frames: u32,
+}
+
+impl Default for Encoder {
+    fn default() -> Self {
+        let settings = EncodingSettings::default();
+        Self {
+            quality: settings.quality,
+            scale: 1.0,
+            fast_frames: 0,
+            settings,
+        }
+    }
+}
+
+impl Encoder {
+    /*
+     * 最大解像度が0以下の場合は全フレームが縮小できず送られなくなるため、設定を変えずにエラーを返す
+     * FPSが小さすぎるとフレーム間隔がDurationに収まらないため、MIN_FPSからMAX_FPSの範囲に限る
+     */
+    pub fn set_settings(&mut self, mut settings: EncodingSettings) -> Result<(), EncodingError> {
+        if settings
+            .fps
+            .is_some_and(|fps| !(MIN_FPS..=MAX_FPS).contains(&fps))
+        {
+            return Err(EncodingError::InvalidValue("fps"));
+        }
+        if settings.max_width.is_some_and(|width| width <= 0) {
+            return Err(EncodingError::InvalidValue("max_width"));
+        }
+        if settings.max_height.is_some_and(|height| height <= 0) {
+            return Err(EncodingError::InvalidValue("max_height"));
+        }
+        settings.quality = settings.quality.clamp(1, 100);
+        self.quality = settings.quality;
+        self.scale = 1.0;
+        self.fast_frames = 0;
+        self.settings = settings;
+        Ok(())
+    }
+
+    pub fn frame_interval(&self) -> Option<Duration> {
+        self.settings
+            .fps
+            .map(|fps| Duration::from_secs_f64(1.0 / fps))
+    }
+
+    pub fn encode(&self, frame: &Mat) -> Result<Vec<u8>, opencv::Error> {
+        let resized_frame = self.resize(frame)?;
+        let mut buf = Vector::new();
+        imgcodecs::imencode(
+            self.settings.codec.extension(),
+            &resized_frame,
+            &mut buf,
+            &self.encode_params(),
+        )?;
+        Ok(buf.to_vec())
+    }
+
+    fn encode_params(&self) -> Vector<i32> {
+        match self.settings.codec {
+            Codec::Jpeg => Vector::from_slice(&[imgcodecs::IMWRITE_JPEG_QUALITY, self.quality]),
+            Codec::Webp => Vector::from_slice(&[imgcodecs::IMWRITE_WEBP_QUALITY, self.quality]),
+            // PNGは可逆圧縮のため、品質を圧縮レベル(0-9)に読み替える
+            Codec::Png => Vector::from_slice(&[
+                imgcodecs::IMWRITE_PNG_COMPRESSION,
+                (100 - self.quality) * 9 / 100,
+            ]),
+        }
+    }
+
+    // 最大解像度に収まるよう、縦横比を保って縮小する
+    fn resize(&self, frame: &Mat) -> Result<Mat, opencv::Error> {
+        let width = frame.cols() as f64;
+        let height = frame.rows() as f64;
+        let mut scale = self.scale;
+        if let Some(max_width) = self.settings.max_width {
+            scale = scale.min(max_width as f64 / width);
+        }
+        if let Some(max_height) = self.settings.max_height {
+            scale = scale.min(max_height as f64 / height);
+        }
+        if scale >= 1.0 {
+            return Ok(frame.clone());
+        }
+
+        let mut resized_frame = Mat::default();
+        let size = Size::new(
+            ((width * scale) as i32).max(1),
+            ((height * scale) as i32).max(1),
+        );
+        imgproc::resize(
+            frame,
+            &mut resized_frame,
+            size,
+            0.0,
+            0.0,
+            imgproc::INTER_AREA,
+        )?;
+        Ok(resized_frame)
+    }
+
+    /*
+     * 送信にかかった時間から回線の混雑を判定し、品質・解像度を調整する
+     * 品質か縮小率が変わった場合はtrueを返す
+     */
+    pub fn report_send_duration(&mut self, elapsed: Duration) -> bool {
+        if !self.settings.adaptive {
+            return false;
+        }
+        let budget = self.frame_interval().unwrap_or(DEFAULT_FRAME_INTERVAL);
+        let (quality, scale) = (self.quality, self.scale);
+
+        if elapsed > budget {
+            // 混雑時は品質を先に下げ、下限に達したら解像度を下げる
+            self.fast_frames = 0;
+            if self.quality > MIN_QUALITY {
+                self.quality = (self.quality - QUALITY_DOWN_STEP).max(MIN_QUALITY);
+            } else {
+                self.scale = (self.scale * SCALE_STEP).max(MIN_SCALE);
+            }
+        } else if elapsed < budget / 2 {
+            self.fast_frames += 1;
+            if self.fast_frames >= RECOVER_FRAMES {
+                // 回復時は解像度を先に戻し、その後品質を戻す
+                self.fast_frames = 0;
+                if self.scale < 1.0 {
+                    self.scale = (self.scale / SCALE_STEP).min(1.0);
+                } else {
+                    self.quality = (self.quality + QUALITY_UP_STEP).min(self.settings.quality);
+                }
+            }
+        }
+        quality != self.quality || scale != self.scale
+    }
+
+    pub fn status(&self) -> serde_json::Value {
+        serde_json::json!({
+            "type": "encoding",
+            "codec": self.settings.codec,
+            "quality": self.quality,
+            "scale": self.scale,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SLOW: Duration = Duration::from_millis(100);
+    const FAST: Duration = Duration::from_millis(1);
+
+    fn adaptive_encoder() -> Encoder {
+        let mut encoder = Encoder::default();
+        let settings = EncodingSettings {
+            adaptive: true,
+            ..Default::default()
+        };
+        encoder.set_settings(settings).unwrap();
+        encoder
+    }
+
+    #[test]
+    fn non_adaptive_encoder_is_not_adjusted() {
+        let mut encoder = Encoder::default();
+        assert!(!encoder.report_send_duration(SLOW));
+        assert_eq!((encoder.quality, encoder.scale), (95, 1.0));
+    }
+
+    #[test]
+    fn slow_sends_lower_quality_before_scale() {
+        let mut encoder = adaptive_encoder();
+        assert!(encoder.report_send_duration(SLOW));
+        assert_eq!((encoder.quality, encoder.scale), (85, 1.0));
+
+        while encoder.quality > MIN_QUALITY {
+            encoder.report_send_duration(SLOW);
+        }
+        assert_eq!(encoder.scale, 1.0);
+        assert!(encoder.report_send_duration(SLOW));
+        assert_eq!((encoder.quality, encoder.scale), (MIN_QUALITY, SCALE_STEP));
+
+        for _ in 0..100 {
+            encoder.report_send_duration(SLOW);
+        }
+        assert_eq!(encoder.scale, MIN_SCALE);
+        assert!(!encoder.report_send_duration(SLOW));
+    }
+
+    #[test]
+    fn fast_sends_restore_scale_before_quality() {
+        let mut encoder = adaptive_encoder();
+        encoder.quality = MIN_QUALITY;
+        encoder.scale = SCALE_STEP;
+
+        for _ in 0..RECOVER_FRAMES - 1 {
+            assert!(!encoder.report_send_duration(FAST));
+        }
+        assert!(encoder.report_send_duration(FAST));
+        assert_eq!((encoder.quality, encoder.scale), (MIN_QUALITY, 1.0));
+
+        for _ in 0..RECOVER_FRAMES {
+            encoder.report_send_duration(FAST);
+        }
+        assert_eq!(encoder.quality, MIN_QUALITY + QUALITY_UP_STEP);
+    }
+
+    #[test]
+    fn quality_is_not_restored_above_the_setting() {
+        let mut encoder = adaptive_encoder();
+        for _ in 0..RECOVER_FRAMES * 10 {
+            encoder.report_send_duration(FAST);
+        }
+        assert_eq!((encoder.quality, encoder.scale), (95, 1.0));
+    }
+
+    #[test]
+    fn non_positive_max_size_is_rejected() {
+        let mut encoder = Encoder::default();
+        for (max_width, max_height) in [(Some(0), None), (None, Some(-1))] {
+            let settings = EncodingSettings {
+                max_width,
+                max_height,
+                ..Default::default()
+            };
+            assert!(encoder.set_settings(settings).is_err());
+        }
+        assert_eq!(encoder.settings.max_width, None);
+    }
+
+    #[test]
+    fn out_of_range_fps_is_rejected() {
+        let mut encoder = Encoder::default();
+        for fps in [0.0, -1.0, 1e-300, MAX_FPS + 1.0, f64::NAN, f64::INFINITY] {
+            let settings = EncodingSettings {
+                fps: Some(fps),
+                ..Default::default()
+            };
+            assert!(matches!(
+                encoder.set_settings(settings),
+                Err(EncodingError::InvalidValue("fps"))
+            ));
+        }
+        assert_eq!(encoder.frame_interval(), None);
+
+        let settings = EncodingSettings {
+            fps: Some(MAX_FPS),
+            ..Default::default()
+        };
+        encoder.set_settings(settings).unwrap();
+        assert!(encoder.frame_interval().is_some());
+    }
+}
@@ -0,0 +1,71 @@
+use axum::extract::ws::Message;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use tokio::sync::Notify;
+
+/*
+* クライアントごとに最新のフレームを1つだけ保持する
+* 送信が間に合わず未送信のフレームが上書きされた場合はドロップとして数える
+*/
+#[derive(Default)]
+pub struct FrameSlot {
+    latest: std::sync::Mutex<Option<Vec<Message>>>,
+    updated: Notify,
+    dropped: AtomicU64,
+    closed: AtomicBool,
+    // ackモード: クライアントが表示完了を通知するまで次のフレームを送らない
+    ack_mode: AtomicBool,
+    acked: Notify,
+}
+
+impl FrameSlot {
+    pub fn put(&self, messages: Vec<Message>) {
+        let mut latest = self.latest.lock().unwrap();
+        if latest.replace(messages).is_some() {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+        self.updated.notify_one();
+    }
+
+    // 閉じられた場合は空のVecを返す
+    pub async fn take(&self) -> Vec<Message> {
+        while !self.is_closed() {
+            let latest = self.latest.lock().unwrap().take();
+            if let Some(messages) = latest {
+                return messages;
+            }
+            self.updated.notified().await;
+        }
+        vec![]
+    }
+
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    // 切断された場合に生成側・送信側を止める
+    pub fn close(&self) {
+        self.closed.store(true, Ordering::Relaxed);
+        self.updated.notify_one();
+        self.acked.notify_one();
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.closed.load(Ordering::Relaxed)
+    }
+
+    pub fn set_ack_mode(&self, enabled: bool) {
+        self.ack_mode.store(enabled, Ordering::Relaxed);
+        // 無効化された場合に待機中の送信を再開させる
+        self.acked.notify_one();
+    }
+
+    pub fn ack(&self) {
+        self.acked.notify_one();
+    }
+
+    pub async fn wait_ack(&self) {
+        if self.ack_mode.load(Ordering::Relaxed) && !self.is_closed() {
+            self.acked.notified().await;
+        }
+    }
+}
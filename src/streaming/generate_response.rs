@@ -1,4 +1,6 @@
-use axum::{body::Body, http::StatusCode, response::Response};
+use crate::streaming::mjpeg;
+use axum::{body::Body, http::header, http::StatusCode, response::Response};
+use serde_json::Value;
 
 pub fn generate_not_found_response(error_message: &'static str) -> Response<Body> {
     Response::builder()
@@ -13,3 +15,35 @@ pub fn generate_text_response(body: &'static str) -> Response<Body> {
         .body(Body::from(body))
         .unwrap()
 }
+
+pub fn generate_json_response(body: Value) -> Response<Body> {
+    Response::builder()
+        .status(StatusCode::OK)
+        .header(header::CONTENT_TYPE, "application/json")
+        .body(Body::from(body.to_string()))
+        .unwrap()
+}
+
+pub fn generate_png_response(body: Vec<u8>) -> Response<Body> {
+    Response::builder()
+        .status(StatusCode::OK)
+        .header(header::CONTENT_TYPE, "image/png")
+        .body(Body::from(body))
+        .unwrap()
+}
+
+pub fn generate_bad_request_response(error_message: String) -> Response<Body> {
+    Response::builder()
+        .status(StatusCode::BAD_REQUEST)
+        .body(Body::from(error_message))
+        .unwrap()
+}
+
+pub fn generate_mjpeg_response(body: Body) -> Response<Body> {
+    let content_type = format!("multipart/x-mixed-replace; boundary={}", mjpeg::BOUNDARY);
+    Response::builder()
+        .status(StatusCode::OK)
+        .header(header::CONTENT_TYPE, content_type)
+        .body(body)
+        .unwrap()
+}
@@ -1,45 +1,179 @@
-use crate::camera::camera::Camera;
+use crate::camera::calibration::CameraIntrinsics;
+use crate::camera::camera::{Camera, Tap};
+use crate::camera::ptz::Ptz;
+use crate::streaming::commands::Command;
 use crate::streaming::connections::{convert_connections_to_process_chain, Connections};
+use crate::streaming::encoding::Encoder;
+use crate::streaming::frame_slot::FrameSlot;
 use axum::extract::ws::{Message, WebSocket};
 use futures::{stream, StreamExt};
 use futures_util::SinkExt;
-use opencv::{core, imgcodecs, prelude::VectorToVec};
+use opencv::core::{Mat, StsError};
+use serde_json::{json, Value};
 use std::sync::Arc;
-use tokio::sync::Mutex;
+use std::time::Duration;
+use tokio::sync::{mpsc, Mutex};
+use tokio::time::{self, Instant};
 
+// バイナリメッセージの先頭1バイトでストリームを区別する
+const MAIN_STREAM: u8 = 0;
+const TAP_STREAM: u8 = 1;
+
+/*
+* カメラの取得・画像処理・エンコードを行い、最新フレームとして置く
+* カメラの読み込みはブロックするため、フレームごとにブロッキング用のスレッドで行う
+*/
+pub async fn produce_camera_frame(
+    camera: Arc<Mutex<Camera>>,
+    encoder: Arc<Mutex<Encoder>>,
+    frame_slot: Arc<FrameSlot>,
+    event_sender: mpsc::UnboundedSender<Message>,
+) {
+    let mut last_error: Option<String> = None;
+    while !frame_slot.is_closed() {
+        let frame_start = Instant::now();
+        let mut camera = camera.clone().lock_owned().await;
+        let encoder = encoder.clone().lock_owned().await;
+        let event_sender = event_sender.clone();
+        let result = tokio::task::spawn_blocking(move || {
+            let messages = produce_frame(&mut camera, &encoder, &mut last_error, &event_sender);
+            (messages, encoder.frame_interval(), last_error)
+        })
+        .await;
+        let Ok((messages, frame_interval, error)) = result else {
+            frame_slot.close();
+            return;
+        };
+        last_error = error;
+        frame_slot.put(messages);
+
+        // FPSの上限が指定されている場合は次のフレームまで待つ
+        match frame_interval {
+            Some(interval) => time::sleep_until(frame_start + interval).await,
+            None => tokio::task::yield_now().await,
+        }
+    }
+}
+
+// 1フレーム分を処理し、フレームと一緒に置くメッセージを返す。イベントはすぐに送る
+fn produce_frame(
+    camera: &mut Camera,
+    encoder: &Encoder,
+    last_error: &mut Option<String>,
+    event_sender: &mpsc::UnboundedSender<Message>,
+) -> Vec<Message> {
+    // 同じエラーが毎フレーム送られないよう、変化した場合のみ通知
+    let error = camera.capture_frame().err().map(|err| err.to_string());
+    if let Some(message) = error.as_ref().filter(|_| error != *last_error) {
+        let _ = event_sender.send(Message::Text(error_event(message).to_string()));
+    }
+    *last_error = error;
+    // ソースの切断・再接続をクライアントに通知
+    for status in camera.take_source_status_changes() {
+        let event = json!({ "type": "source_status", "status": status });
+        let _ = event_sender.send(Message::Text(event.to_string()));
+    }
+    // 読み取ったコードなどはフレームと違い破棄されないよう、イベントとして送る
+    for event in camera.take_events() {
+        let _ = event_sender.send(Message::Text(event.to_string()));
+    }
+
+    // メタデータはフレームと一緒に置き、フレームと同時に破棄されるようにする
+    let mut messages = vec![];
+    let metadata = camera.take_metadata();
+    if !metadata.is_empty() {
+        let event = json!({ "type": "metadata", "stages": metadata });
+        messages.push(Some(Message::Text(event.to_string())));
+    }
+    messages.push(encode_frame(encoder, MAIN_STREAM, &camera.frame));
+    if let Some(tap_frame) = &camera.tap_frame {
+        messages.push(encode_frame(encoder, TAP_STREAM, tap_frame));
+    }
+    messages.into_iter().flatten().collect()
+}
+
+// 最新フレームとイベントを送信する。送信が遅れた間のフレームは破棄される
 pub async fn send_camera_frame(
     mut send_socket: stream::SplitSink<WebSocket, Message>,
-    camera: Arc<Mutex<Camera>>,
+    encoder: Arc<Mutex<Encoder>>,
+    frame_slot: Arc<FrameSlot>,
+    mut event_receiver: mpsc::UnboundedReceiver<Message>,
 ) {
-    loop {
-        let mut camera = camera.lock().await;
-        let _ = camera.capture_frame();
-        let mut buf = core::Vector::new();
-        imgcodecs::imencode(".jpg", &camera.frame, &mut buf, &Default::default()).unwrap();
+    const STATS_INTERVAL: Duration = Duration::from_secs(1);
+    let mut sent_frames: u64 = 0;
+    let mut stats_time = Instant::now();
+
+    while !frame_slot.is_closed() {
+        let messages = tokio::select! {
+            Some(event) = event_receiver.recv() => vec![event],
+            messages = frame_slot.take() => messages,
+        };
+        let is_frame = messages
+            .iter()
+            .any(|message| matches!(message, Message::Binary(_)));
 
         // WebSocketでバイナリデータとして送信
-        if send_socket
-            .send(Message::Binary(buf.to_vec()))
-            .await
-            .is_err()
+        let send_start = Instant::now();
+        for message in messages {
+            if send_socket.send(message).await.is_err() {
+                frame_slot.close();
+                return;
+            }
+        }
+        if !is_frame {
+            continue;
+        }
+        sent_frames += 1;
+
+        let mut events = vec![];
         {
-            break;
+            let mut encoder = encoder.lock().await;
+            if encoder.report_send_duration(send_start.elapsed()) {
+                events.push(encoder.status());
+            }
+        }
+        if stats_time.elapsed() >= STATS_INTERVAL {
+            stats_time = Instant::now();
+            events.push(json!({
+                "type": "stats",
+                "sent": sent_frames,
+                "dropped": frame_slot.dropped(),
+            }));
+        }
+        for event in events {
+            if send_socket
+                .send(Message::Text(event.to_string()))
+                .await
+                .is_err()
+            {
+                frame_slot.close();
+                return;
+            }
         }
+        frame_slot.wait_ack().await;
     }
 }
 
+fn encode_frame(encoder: &Encoder, stream_id: u8, frame: &Mat) -> Option<Message> {
+    let mut data = vec![stream_id];
+    data.extend(encoder.encode(frame).ok()?);
+    Some(Message::Binary(data))
+}
+
 pub async fn recv_key_event(
     mut recv_socket: stream::SplitStream<WebSocket>,
     camera: Arc<Mutex<Camera>>,
+    encoder: Arc<Mutex<Encoder>>,
+    frame_slot: Arc<FrameSlot>,
+    event_sender: mpsc::UnboundedSender<Message>,
 ) {
     while let Some(Ok(msg)) = recv_socket.next().await {
         match msg {
             Message::Text(text) => {
-                if let Ok(connections_data) = serde_json::from_str::<Connections>(&text) {
-                    let camera_chain: Vec<String> =
-                        convert_connections_to_process_chain(connections_data.nodes);
-                    let mut camera = camera.lock().await;
-                    camera.set_process_chain(camera_chain.clone());
+                if let Some(event) =
+                    handle_text_message(&text, &camera, &encoder, &frame_slot).await
+                {
+                    let _ = event_sender.send(Message::Text(event.to_string()));
                 }
             }
             Message::Binary(_) => {}
@@ -47,4 +181,155 @@ pub async fn recv_key_event(
             _ => {}
         }
     }
+    frame_slot.close();
+}
+
+// クライアントへ返すイベントがあればJSONで返す
+async fn handle_text_message(
+    text: &str,
+    camera: &Mutex<Camera>,
+    encoder: &Mutex<Encoder>,
+    frame_slot: &FrameSlot,
+) -> Option<Value> {
+    if let Ok(connections_data) = serde_json::from_str::<Connections>(text) {
+        let camera_chain = convert_connections_to_process_chain(connections_data);
+        let result = camera.lock().await.set_process_chain(camera_chain);
+        return Some(match result {
+            Ok(stages) => json!({ "type": "pipeline", "stages": stages }),
+            Err(err) => error_event(err),
+        });
+    }
+
+    match serde_json::from_str::<Command>(text).ok()? {
+        Command::Tap { node, mode } => {
+            let tap = node.map(|node| Tap { node, mode });
+            camera.lock().await.set_tap(tap).err().map(error_event)
+        }
+        Command::DebugGrid { enabled } => {
+            camera.lock().await.set_debug_grid(enabled);
+            None
+        }
+        Command::Encoding(settings) => {
+            let mut encoder = encoder.lock().await;
+            Some(match encoder.set_settings(settings) {
+                Ok(()) => encoder.status(),
+                Err(err) => error_event(err),
+            })
+        }
+        Command::AckMode { enabled } => {
+            frame_slot.set_ack_mode(enabled);
+            None
+        }
+        Command::Ack => {
+            frame_slot.ack();
+            None
+        }
+        Command::GetCameraProperties => {
+            let result = camera.lock().await.query_properties();
+            Some(match result {
+                Ok(capabilities) => {
+                    json!({ "type": "camera_properties", "capabilities": capabilities })
+                }
+                Err(err) => error_event(err),
+            })
+        }
+        Command::SetCameraProperties { properties } => {
+            let result = camera.lock().await.set_properties(&properties);
+            Some(match result {
+                Ok(results) => json!({ "type": "camera_properties_result", "results": results }),
+                Err(err) => error_event(err),
+            })
+        }
+        Command::SampleColor { x, y } => {
+            let result = camera.lock().await.sample_color(x, y);
+            Some(match result {
+                Ok(sample) => json!({ "type": "color_sample", "sample": sample }),
+                Err(err) => error_event(err),
+            })
+        }
+        Command::CalibrationStart(settings) => {
+            let result = camera.lock().await.start_calibration(settings);
+            Some(match result {
+                Ok(status) => json!({ "type": "calibration", "status": status }),
+                Err(err) => error_event(err),
+            })
+        }
+        Command::CalibrationCapture => {
+            let result = camera.lock().await.capture_calibration_view();
+            Some(match result {
+                Ok(status) => json!({ "type": "calibration", "status": status }),
+                Err(err) => error_event(err),
+            })
+        }
+        Command::CalibrationFinish => {
+            let result = finish_calibration(camera).await;
+            Some(match result {
+                Ok((source, intrinsics)) => json!({
+                    "type": "calibration_result",
+                    "source": source,
+                    "intrinsics": intrinsics,
+                }),
+                Err(err) => error_event(err),
+            })
+        }
+        Command::CalibrationCancel => {
+            camera.lock().await.cancel_calibration();
+            Some(json!({ "type": "calibration", "status": null }))
+        }
+        Command::Ptz { pan, tilt, zoom } => {
+            let mut camera = camera.lock().await;
+            camera.ptz().set_viewport(pan, tilt, zoom);
+            Some(ptz_event(camera.ptz()))
+        }
+        Command::PtzDrag { dx, dy } => {
+            let mut camera = camera.lock().await;
+            camera.ptz().drag(dx, dy);
+            Some(ptz_event(camera.ptz()))
+        }
+        Command::PtzScroll { factor, x, y } => {
+            let mut camera = camera.lock().await;
+            camera.ptz().scroll(factor, x, y);
+            Some(ptz_event(camera.ptz()))
+        }
+        Command::PtzSettings(settings) => {
+            let mut camera = camera.lock().await;
+            Some(match camera.ptz().update_settings(settings) {
+                Ok(()) => ptz_event(camera.ptz()),
+                Err(err) => error_event(err),
+            })
+        }
+        Command::PtzReset => {
+            let mut camera = camera.lock().await;
+            camera.ptz().reset();
+            Some(ptz_event(camera.ptz()))
+        }
+    }
+}
+
+fn ptz_event(ptz: &Ptz) -> Value {
+    json!({ "type": "ptz", "status": ptz.status() })
+}
+
+/*
+* 内部パラメータを求めて calibration/<ソース名>.json に保存し、キャリブレーションを終える
+* 計算には時間がかかるため、カメラのロックを外してブロッキング用のスレッドで行う
+*/
+async fn finish_calibration(
+    camera: &Mutex<Camera>,
+) -> Result<(String, CameraIntrinsics), opencv::Error> {
+    let (source, views) = camera.lock().await.calibration_views()?;
+    let name = source.clone();
+    let intrinsics = tokio::task::spawn_blocking(move || {
+        let intrinsics = views.compute()?;
+        intrinsics.save(&name)?;
+        Ok::<CameraIntrinsics, opencv::Error>(intrinsics)
+    })
+    .await
+    .map_err(|err| opencv::Error::new(StsError, err.to_string()))??;
+    camera.lock().await.cancel_calibration();
+    Ok((source, intrinsics))
+}
+
+fn error_event(err: impl std::fmt::Display) -> Value {
+    json!({ "type": "error", "message": err.to_string() })
 }
@@ -1,13 +1,21 @@
+use crate::camera::aruco::{self, BoardImageParams, MarkerImageParams};
 use crate::camera::camera::Camera;
-use crate::camera::utils;
+use crate::camera::{cascade, source};
+use crate::streaming::encoding::Encoder;
+use crate::streaming::frame_slot::FrameSlot;
 use crate::streaming::generate_response::*;
 use crate::streaming::handle_websocket::*;
-use axum::extract::{ws, Path};
+use crate::streaming::mjpeg;
+use crate::streaming::sessions::AppState;
+use axum::body::Body;
+use axum::extract::{ws, Path, Query, State};
 use axum::response::IntoResponse;
+use axum::Json;
 use futures::StreamExt;
 use phf::phf_map;
+use serde_json::{json, Map, Value};
 use std::sync::Arc;
-use tokio::sync::Mutex;
+use tokio::sync::{mpsc, Mutex};
 
 pub async fn root_handler() -> impl IntoResponse {
     static_content_handler(Path("".to_string())).await
@@ -26,21 +34,169 @@ pub async fn static_content_handler(Path(file): Path<String>) -> impl IntoRespon
     }
 }
 
-pub async fn websocket_handler(ws: ws::WebSocketUpgrade) -> impl IntoResponse {
-    ws.on_upgrade(|socket| {
-        let camera_number: i32 = utils::get_dev_number();
-        let camera = Arc::new(Mutex::new(Camera::new(camera_number)));
+#[derive(Debug, serde::Deserialize)]
+pub struct SourceQuery {
+    source: Option<String>,
+}
+
+pub async fn websocket_handler(
+    ws: ws::WebSocketUpgrade,
+    State(state): State<AppState>,
+    Query(query): Query<SourceQuery>,
+) -> impl IntoResponse {
+    let camera = match open_camera(&state, &query).await {
+        Ok(camera) => Arc::new(Mutex::new(camera)),
+        Err(message) => return generate_bad_request_response(message),
+    };
+
+    ws.on_upgrade(move |socket| {
         let (send_socket, recv_socket) = socket.split();
+        let (event_sender, event_receiver) = mpsc::unbounded_channel();
+
+        // REST APIから参照するためのセッションIDをクライアントに通知
+        let session_id = state.register(Arc::clone(&camera));
+        let session_event = json!({ "type": "session", "id": session_id });
+        let _ = event_sender.send(ws::Message::Text(session_event.to_string()));
+
+        let encoder = Arc::new(Mutex::new(Encoder::default()));
+        let frame_slot = Arc::new(FrameSlot::default());
 
         let camera_for_recv = Arc::clone(&camera);
-        let camera_for_send = Arc::clone(&camera);
+        let encoder_for_recv = Arc::clone(&encoder);
+        let frame_slot_for_recv = Arc::clone(&frame_slot);
+        let encoder_for_send = Arc::clone(&encoder);
+        let frame_slot_for_send = Arc::clone(&frame_slot);
+        let event_sender_for_produce = event_sender.clone();
 
         tokio::spawn(async move {
-            recv_key_event(recv_socket, camera_for_recv).await;
+            recv_key_event(
+                recv_socket,
+                camera_for_recv,
+                encoder_for_recv,
+                frame_slot_for_recv,
+                event_sender,
+            )
+            .await;
+            state.unregister(session_id);
+        });
+        tokio::spawn(async move {
+            produce_camera_frame(camera, encoder, frame_slot, event_sender_for_produce).await;
         });
         tokio::spawn(async move {
-            send_camera_frame(send_socket, camera_for_send).await;
+            send_camera_frame(
+                send_socket,
+                encoder_for_send,
+                frame_slot_for_send,
+                event_receiver,
+            )
+            .await;
         });
         async { () }
     })
 }
+
+// ネットワークソースは接続のタイムアウトまで開く処理が止まるため、ブロッキング用のスレッドで開く
+async fn open_camera(state: &AppState, query: &SourceQuery) -> Result<Camera, String> {
+    let sources = state.resolve_sources(query.source.as_deref())?;
+    tokio::task::spawn_blocking(move || Camera::open(&sources))
+        .await
+        .map_err(|err| err.to_string())?
+        .map_err(|err| err.to_string())
+}
+
+pub async fn mjpeg_handler(
+    State(state): State<AppState>,
+    Query(query): Query<SourceQuery>,
+) -> impl IntoResponse {
+    match open_camera(&state, &query).await {
+        Ok(camera) => generate_mjpeg_response(Body::from_stream(mjpeg::mjpeg_stream(camera))),
+        Err(message) => generate_bad_request_response(message),
+    }
+}
+
+pub async fn camera_properties_handler(
+    State(state): State<AppState>,
+    Path(session_id): Path<u64>,
+) -> impl IntoResponse {
+    let Some(camera) = state.camera(session_id) else {
+        return generate_not_found_response("Error: session not found");
+    };
+    let result = camera.lock().await.query_properties();
+    match result {
+        Ok(capabilities) => generate_json_response(capabilities),
+        Err(err) => generate_bad_request_response(err.to_string()),
+    }
+}
+
+pub async fn set_camera_properties_handler(
+    State(state): State<AppState>,
+    Path(session_id): Path<u64>,
+    Json(requested): Json<Map<String, Value>>,
+) -> impl IntoResponse {
+    let Some(camera) = state.camera(session_id) else {
+        return generate_not_found_response("Error: session not found");
+    };
+    let result = camera.lock().await.set_properties(&requested);
+    match result {
+        Ok(results) => generate_json_response(results),
+        Err(err) => generate_bad_request_response(err.to_string()),
+    }
+}
+
+pub async fn source_status_handler(
+    State(state): State<AppState>,
+    Path(session_id): Path<u64>,
+) -> impl IntoResponse {
+    match state.camera(session_id) {
+        Some(camera) => generate_json_response(json!(camera.lock().await.source_status())),
+        None => generate_not_found_response("Error: session not found"),
+    }
+}
+
+#[derive(Debug, serde::Deserialize)]
+pub struct CodesQuery {
+    limit: Option<usize>,
+}
+
+// 読み取ったコードを新しい順に返す(/api/sessions/0/codes?limit=5)
+pub async fn codes_handler(
+    State(state): State<AppState>,
+    Path(session_id): Path<u64>,
+    Query(query): Query<CodesQuery>,
+) -> impl IntoResponse {
+    const DEFAULT_LIMIT: usize = 20;
+    match state.camera(session_id) {
+        Some(camera) => {
+            let limit = query.limit.unwrap_or(DEFAULT_LIMIT);
+            generate_json_response(json!(camera.lock().await.decoded_codes(limit)))
+        }
+        None => generate_not_found_response("Error: session not found"),
+    }
+}
+
+pub async fn sources_handler(State(state): State<AppState>) -> impl IntoResponse {
+    generate_json_response(json!({
+        "default": source::default_source_name(),
+        "sources": state.sources(),
+    }))
+}
+
+pub async fn models_handler() -> impl IntoResponse {
+    generate_json_response(json!(cascade::list_models()))
+}
+
+// 印刷用のマーカー画像(/api/aruco/marker?dictionary=4x4_50&id=3&size=400)
+pub async fn aruco_marker_handler(Query(params): Query<MarkerImageParams>) -> impl IntoResponse {
+    match aruco::marker_image(&params) {
+        Ok(png) => generate_png_response(png),
+        Err(err) => generate_bad_request_response(err.to_string()),
+    }
+}
+
+// 印刷用のChArUcoボード画像(/api/aruco/board?squares_x=5&squares_y=7)
+pub async fn aruco_board_handler(Query(params): Query<BoardImageParams>) -> impl IntoResponse {
+    match aruco::board_image(&params) {
+        Ok(png) => generate_png_response(png),
+        Err(err) => generate_bad_request_response(err.to_string()),
+    }
+}
@@ -0,0 +1,35 @@
+use crate::camera::camera::Camera;
+use crate::streaming::encoding::Encoder;
+use futures::{stream, Stream};
+use std::convert::Infallible;
+
+pub const BOUNDARY: &str = "frame";
+
+/*
+* multipart/x-mixed-replaceの各パートとしてJPEGを送り続ける
+* 別のインスタンスからネットワークソース(http://host:8080/mjpeg)として読み込める
+* カメラの読み込みはブロックするため、フレームごとにブロッキング用のスレッドで取得・エンコードする
+*/
+pub fn mjpeg_stream(camera: Camera) -> impl Stream<Item = Result<Vec<u8>, Infallible>> {
+    let encoder = Encoder::default();
+    stream::unfold((camera, encoder), |(mut camera, encoder)| async move {
+        let (camera, encoder, jpeg) = tokio::task::spawn_blocking(move || {
+            let jpeg = camera
+                .capture_frame()
+                .and_then(|_| encoder.encode(&camera.frame));
+            (camera, encoder, jpeg)
+        })
+        .await
+        .ok()?;
+        let jpeg = jpeg.ok()?;
+        let header = format!(
+            "--{}\r\nContent-Type: image/jpeg\r\nContent-Length: {}\r\n\r\n",
+            BOUNDARY,
+            jpeg.len()
+        );
+        let mut part = header.into_bytes();
+        part.extend(jpeg);
+        part.extend(b"\r\n");
+        Some((Ok(part), (camera, encoder)))
+    })
+}
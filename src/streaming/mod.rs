@@ -1,4 +1,9 @@
+pub mod commands;
 pub mod connections;
+pub mod encoding;
+pub mod frame_slot;
 pub mod generate_response;
 pub mod handle_websocket;
 pub mod handlers;
+pub mod mjpeg;
+pub mod sessions;
@@ -0,0 +1,63 @@
+use crate::camera::camera::Camera;
+use crate::camera::source::{self, NamedSource};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use tokio::sync::Mutex;
+
+// 利用可能なソースと、REST APIから参照するためのセッションごとのカメラを保持する
+#[derive(Clone)]
+pub struct AppState {
+    sources: Arc<Vec<NamedSource>>,
+    cameras: Arc<std::sync::Mutex<HashMap<u64, Arc<Mutex<Camera>>>>>,
+    next_session_id: Arc<AtomicU64>,
+}
+
+impl AppState {
+    pub fn new(sources: Vec<NamedSource>) -> Self {
+        Self {
+            sources: Arc::new(sources),
+            cameras: Arc::default(),
+            next_session_id: Arc::default(),
+        }
+    }
+
+    pub fn sources(&self) -> &[NamedSource] {
+        &self.sources
+    }
+
+    /*
+     * resolve_sources(None)              -> [DEV_NUMBERのカメラ]
+     * resolve_sources(Some("cam0,door")) -> [cam0, door] (格子状に並べて配信)
+     */
+    pub fn resolve_sources(&self, names: Option<&str>) -> Result<Vec<NamedSource>, String> {
+        let names = match names {
+            Some(names) => names.split(',').map(str::to_string).collect(),
+            None => vec![source::default_source_name()],
+        };
+        names
+            .iter()
+            .map(|name| {
+                self.sources
+                    .iter()
+                    .find(|source| source.name == *name)
+                    .cloned()
+                    .ok_or_else(|| format!("unknown source: {}", name))
+            })
+            .collect()
+    }
+
+    pub fn register(&self, camera: Arc<Mutex<Camera>>) -> u64 {
+        let session_id = self.next_session_id.fetch_add(1, Ordering::Relaxed);
+        self.cameras.lock().unwrap().insert(session_id, camera);
+        session_id
+    }
+
+    pub fn unregister(&self, session_id: u64) {
+        self.cameras.lock().unwrap().remove(&session_id);
+    }
+
+    pub fn camera(&self, session_id: u64) -> Option<Arc<Mutex<Camera>>> {
+        self.cameras.lock().unwrap().get(&session_id).cloned()
+    }
+}
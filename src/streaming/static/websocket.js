@@ -14,22 +14,312 @@ var node_details = [
 	"gray",
 	"reverse",
 	"eye",
+	"cascade",
+	"face_parts",
+	"superres",
+	"color_space",
+	"channels",
+	"color_tracker",
+	"blur",
+	"sharpen",
+	"morphology",
+	"convolve",
+	"sobel",
+	"scharr",
+	"laplacian",
+	"gradient_orientation",
+	"contours",
+	"hough_lines",
+	"hough_circles",
+	"code_reader",
+	"aruco",
+	"undistort",
+	"crop",
+	"resize",
+	"rotate",
+	"flip",
+	"affine",
+	"perspective",
+	"letterbox",
+	"equalize",
+	"gamma",
+	"brightness",
+	"contrast",
+	"tone_curve",
 ];
+// グラフ上のノードのIDごとのパラメータ(ノードをダブルクリックしてJSONで編集)
+var nodeParams = {};
 var camera = "camera";
+// バイナリメッセージの先頭1バイト(ストリーム番号)
+var MAIN_STREAM = 0;
+var TAP_STREAM = 1;
+var MIME_TYPES = { jpeg: 'image/jpeg', png: 'image/png', webp: 'image/webp' };
+var mimeType = MIME_TYPES.jpeg;
+// 最後にクリックして取得した色
+var colorSample = null;
+// 表示する読み取ったコードの件数
+var MAX_CODES = 10;
+// ePTZのホイール1段あたりの拡大率と、ドラッグとみなす移動量(画素)
+var PTZ_SCROLL_FACTOR = 1.1;
+var PTZ_DRAG_THRESHOLD = 3;
+var dragStart = null;
+var dragged = false;
+// タップ中のステージ名
+var tappedNode = null;
+// perspectiveの四隅を選択中の場合はクリックした位置(左上, 右上, 右下, 左下の順)
+var pickedCorners = null;
 
 function initializeWebSocket() {
-	ws = new WebSocket('ws://' + window.location.host + '/ws');
+	// ページのクエリ(?source=cam1 など)をそのままWebSocketに渡す
+	ws = new WebSocket('ws://' + window.location.host + '/ws' + window.location.search);
 	ws.binaryType = 'arraybuffer';
 
 	ws.onopen = function() { console.log('WebSocket connection opened'); };
 	ws.onclose = function() { console.log('WebSocket connection closed'); };
 	ws.onerror = function(error) { console.error('WebSocket error: ', error); };
 	ws.onmessage = function(event) {
-		var img = document.getElementById('stream');
-		img.src = URL.createObjectURL(new Blob([event.data], { type: 'image/jpeg' }));
+		if (typeof event.data === 'string') {
+			handleServerEvent(JSON.parse(event.data));
+			return;
+		}
+		var bytes = new Uint8Array(event.data);
+		var img = document.getElementById(bytes[0] === TAP_STREAM ? 'tap' : 'stream');
+		img.src = URL.createObjectURL(new Blob([bytes.subarray(1)], { type: mimeType }));
+	};
+
+	// ackモードではメイン画像の表示完了を通知する
+	document.getElementById('stream').onload = function() {
+		if (document.getElementById('ackMode').checked) {
+			ws.send(JSON.stringify({ type: 'ack' }));
+		}
 	};
 }
 
+// クリックしたノードの出力をタップする(モードがoffの場合は解除)
+function sendTap(node) {
+	var mode = document.getElementById('tapMode').value;
+	var tapImg = document.getElementById('tap');
+	tapImg.style.display = mode === 'stream' && node !== null ? 'block' : 'none';
+	if (mode === 'off') { node = null; }
+	tappedNode = node;
+	ws.send(JSON.stringify({ type: 'tap', node: node, mode: mode === 'off' ? 'stream' : mode }));
+}
+
+function sendDebugGrid() {
+	var enabled = document.getElementById('debugGrid').checked;
+	ws.send(JSON.stringify({ type: 'debug_grid', enabled: enabled }));
+}
+
+// サーバからのテキストイベント(パイプラインの構成やエラー)を表示
+function handleServerEvent(data) {
+	var status = document.getElementById('status');
+	if (data.type === 'pipeline') {
+		status.textContent = 'camera -> ' + data.stages.join(' -> ');
+		// タップしていたステージがなくなった場合はサーバ側で解除されている
+		if (tappedNode !== null && tappedNode !== camera && data.stages.indexOf(tappedNode) < 0) {
+			tappedNode = null;
+			document.getElementById('tap').style.display = 'none';
+		}
+	} else if (data.type === 'error') {
+		status.textContent = 'Error: ' + data.message;
+	} else if (data.type === 'metadata') {
+		document.getElementById('metadata').textContent = JSON.stringify(data.stages, null, 1);
+	} else if (data.type === 'source_status') {
+		var source = data.status;
+		document.getElementById('sourceStatus').textContent = source.name + ': ' + source.state +
+			(source.last_error ? ' (' + source.last_error + ')' : '');
+	} else if (data.type === 'session') {
+		document.getElementById('session').textContent = 'session=' + data.id;
+	} else if (data.type === 'camera_properties') {
+		renderCameraProperties(data.capabilities);
+	} else if (data.type === 'camera_properties_result') {
+		updateCameraProperties(data.results);
+	} else if (data.type === 'stats') {
+		document.getElementById('stats').textContent =
+			'sent=' + data.sent + ' dropped=' + data.dropped;
+	} else if (data.type === 'color_sample') {
+		renderColorSample(data.sample);
+	} else if (data.type === 'code') {
+		renderCode(data);
+	} else if (data.type === 'ptz') {
+		var target = data.status.target;
+		document.getElementById('ptzAutoFrame').checked = data.status.auto_frame;
+		document.getElementById('ptzStatus').textContent = 'zoom=' + target.zoom.toFixed(2) +
+			' pan=' + target.pan.toFixed(2) + ' tilt=' + target.tilt.toFixed(2);
+	} else if (data.type === 'calibration') {
+		var calibration = data.status;
+		document.getElementById('calibrationStatus').textContent = calibration ?
+			calibration.pattern + ' views=' + calibration.views + '/' + calibration.required : '';
+	} else if (data.type === 'calibration_result') {
+		document.getElementById('calibrationStatus').textContent =
+			'saved ' + data.source + ' rms=' + data.intrinsics.rms.toFixed(3);
+	} else if (data.type === 'encoding') {
+		mimeType = MIME_TYPES[data.codec];
+		document.getElementById('encodingStatus').textContent =
+			data.codec + ' q=' + data.quality + ' scale=' + data.scale.toFixed(2);
+	}
+}
+
+// 利用可能なソースへのリンクと、全ソースを並べるモザイクへのリンクを表示する
+function renderSources() {
+	fetch('/api/sources')
+		.then(function(response) { return response.json(); })
+		.then(function(data) {
+			var panel = document.getElementById('sources');
+			var names = data.sources.map(function(source) { return source.name; });
+			names.concat([names.join(',')]).forEach(function(name) {
+				var link = document.createElement('a');
+				link.href = '?source=' + encodeURIComponent(name);
+				link.textContent = name.indexOf(',') < 0 ? name : 'mosaic';
+				panel.append(link, ' ');
+			});
+		});
+}
+
+// 対応しているプロパティのみ入力欄を表示する
+function renderCameraProperties(capabilities) {
+	var panel = document.getElementById('cameraProperties');
+	panel.textContent = capabilities.backend + ' ';
+	Object.keys(capabilities.properties).forEach(function(name) {
+		var property = capabilities.properties[name];
+		if (!property.supported) { return; }
+		var label = document.createElement('label');
+		var input = document.createElement('input');
+		input.id = 'property_' + name;
+		input.value = property.value;
+		input.addEventListener('change', function() {
+			var properties = {};
+			properties[name] = name === 'fourcc' ? input.value : Number(input.value);
+			ws.send(JSON.stringify({ type: 'set_camera_properties', properties: properties }));
+		});
+		label.append(name + ' ', input);
+		panel.append(label);
+	});
+}
+
+// ドライバが反映しなかった値は赤く表示し、実際の値に戻す
+function updateCameraProperties(results) {
+	Object.keys(results).forEach(function(name) {
+		var input = document.getElementById('property_' + name);
+		if (!input) { return; }
+		input.value = results[name].actual;
+		input.style.color = results[name].applied ? '' : 'red';
+	});
+}
+
+// クリックした位置を画像に対する相対位置で送る(配信時の縮小に影響されない)
+function sendSampleColor(event) {
+	if (dragged) {
+		dragged = false;
+		return;
+	}
+	var img = event.target;
+	var x = event.offsetX / img.clientWidth;
+	var y = event.offsetY / img.clientHeight;
+	if (pickedCorners) {
+		pickCorner(x, y);
+		return;
+	}
+	ws.send(JSON.stringify({ type: 'sample_color', x: x, y: y }));
+}
+
+// ドラッグで表示範囲を移動し、ホイールでカーソルの位置を中心に拡大・縮小する
+function setupPtz() {
+	var img = document.getElementById('stream');
+	img.addEventListener('mousedown', function(event) {
+		dragStart = { x: event.clientX, y: event.clientY };
+		dragged = false;
+		event.preventDefault();
+	});
+	window.addEventListener('mousemove', function(event) {
+		if (!dragStart) { return; }
+		var dx = event.clientX - dragStart.x;
+		var dy = event.clientY - dragStart.y;
+		if (!dragged && Math.abs(dx) < PTZ_DRAG_THRESHOLD && Math.abs(dy) < PTZ_DRAG_THRESHOLD) { return; }
+		dragged = true;
+		dragStart = { x: event.clientX, y: event.clientY };
+		ws.send(JSON.stringify({ type: 'ptz_drag', dx: dx / img.clientWidth, dy: dy / img.clientHeight }));
+	});
+	window.addEventListener('mouseup', function() { dragStart = null; });
+	img.addEventListener('wheel', function(event) {
+		event.preventDefault();
+		ws.send(JSON.stringify({
+			type: 'ptz_scroll',
+			factor: event.deltaY < 0 ? PTZ_SCROLL_FACTOR : 1 / PTZ_SCROLL_FACTOR,
+			x: event.offsetX / img.clientWidth,
+			y: event.offsetY / img.clientHeight
+		}));
+	}, { passive: false });
+	document.getElementById('ptzAutoFrame').addEventListener('change', function() {
+		var enabled = document.getElementById('ptzAutoFrame').checked;
+		ws.send(JSON.stringify({ type: 'ptz_settings', auto_frame: enabled }));
+	});
+	document.getElementById('ptzReset').addEventListener('click', function() {
+		ws.send(JSON.stringify({ type: 'ptz_reset' }));
+	});
+}
+
+// 選択中は変形前の画像を表示するため、四隅を画像全体にしておく
+function startPickingCorners() {
+	var id = nodeId('perspective');
+	var params = nodeParams[id] || {};
+	params.corners = [[0, 0], [1, 0], [1, 1], [0, 1]];
+	nodeParams[id] = params;
+	pickedCorners = [];
+	document.getElementById('cornerStatus').textContent = 'click 4 corners (top-left, top-right, bottom-right, bottom-left)';
+	sendNodeConnections();
+}
+
+function pickCorner(x, y) {
+	pickedCorners.push([x, y]);
+	document.getElementById('cornerStatus').textContent = pickedCorners.length + '/4';
+	if (pickedCorners.length < 4) { return; }
+	nodeParams[nodeId('perspective')].corners = pickedCorners;
+	pickedCorners = null;
+	sendNodeConnections();
+}
+
+function renderColorSample(sample) {
+	colorSample = sample;
+	var bgr = sample.bgr;
+	document.getElementById('colorSwatch').style.background = 'rgb(' + bgr[2] + ',' + bgr[1] + ',' + bgr[0] + ')';
+	document.getElementById('colorSample').textContent =
+		'(' + sample.x + ', ' + sample.y + ') hsv=' + sample.hsv.join(',');
+	document.getElementById('trackColor').disabled = false;
+}
+
+// 読み取ったコードを新しい順に一覧の先頭へ追加する
+function renderCode(code) {
+	var list = document.getElementById('codes');
+	var item = document.createElement('li');
+	item.textContent = new Date(code.timestamp).toLocaleTimeString() + ' [' + code.kind + '] ' + code.data;
+	list.insertBefore(item, list.firstChild);
+	while (list.children.length > MAX_CODES) {
+		list.removeChild(list.lastChild);
+	}
+}
+
+// 取得した色の範囲をcolor_trackerのパラメータにする
+function trackSampledColor() {
+	if (!colorSample) { return; }
+	var id = nodeId('color_tracker');
+	var params = nodeParams[id] || {};
+	params.ranges = colorSample.ranges;
+	nodeParams[id] = params;
+	sendNodeConnections();
+}
+
+// 空欄の項目はサーバ側のデフォルト値を使う
+function sendEncoding() {
+	var settings = { type: 'encoding', codec: document.getElementById('codec').value };
+	['quality', 'max_width', 'max_height', 'fps'].forEach(function(id) {
+		var value = document.getElementById(id).value;
+		if (value !== '') { settings[id] = Number(value); }
+	});
+	settings.adaptive = document.getElementById('adaptive').checked;
+	ws.send(JSON.stringify(settings));
+}
+
 function sendNodeConnections() {
 	var cells = graph.getModel().cells;
 	var connections = Object.keys(cells)
@@ -37,15 +327,42 @@ function sendNodeConnections() {
 		.filter(cell => cell.edge)
 		.map(cell => ({
 			source: cell.source ? cell.source.value : null,
-			target: cell.target ? cell.target.value : null
+			target: cell.target ? cell.target.value : null,
+			source_id: cell.source ? cell.source.id : null,
+			target_id: cell.target ? cell.target.id : null
 		}));
-	ws.send(JSON.stringify({ nodes: connections }));
+	ws.send(JSON.stringify({ nodes: connections, params: nodeParams }));
+}
+
+// 指定した処理のノードのうち、グラフ上で最初に見つかったもののID
+function nodeId(name) {
+	var cells = graph.getModel().cells;
+	var id = Object.keys(cells).find(cellId => cells[cellId].vertex && cells[cellId].value === name);
+	return id === undefined ? name : id;
+}
+
+function editNodeParams(cell) {
+	fetch('/api/models')
+		.then(function(response) { return response.json(); })
+		.then(function(models) {
+			var current = JSON.stringify(nodeParams[cell.id] || {});
+			var input = window.prompt(cell.value + ' params (JSON)\nmodels: ' + models.join(', '), current);
+			if (input === null) { return; }
+			try {
+				nodeParams[cell.id] = JSON.parse(input || '{}');
+			} catch (e) {
+				document.getElementById('status').textContent = 'Error: invalid JSON';
+				return;
+			}
+			sendNodeConnections();
+		});
 }
 
 function main(container) {
 	graph = new mxGraph(container);
 	var parent = graph.getDefaultParent();
 	initializeWebSocket();
+	renderSources();
 
 	graph.getModel().beginUpdate();
 	try {
@@ -99,6 +416,40 @@ function main(container) {
 		});
 	});
 
+	graph.addListener(mxEvent.DOUBLE_CLICK, function(_, evt) {
+		var cell = evt.getProperty('cell');
+		if (cell && cell.vertex && cell.value !== camera) { editNodeParams(cell); }
+		evt.consume();
+	});
+	graph.addListener(mxEvent.CLICK, function(_, evt) {
+		var cell = evt.getProperty('cell');
+		if (cell && cell.vertex) { sendTap(cell.value); }
+	});
+	document.getElementById('tapMode').addEventListener('change', function() { sendTap(null); });
+	document.getElementById('debugGrid').addEventListener('change', sendDebugGrid);
+	document.getElementById('applyEncoding').addEventListener('click', sendEncoding);
+	document.getElementById('stream').addEventListener('click', sendSampleColor);
+	document.getElementById('trackColor').addEventListener('click', trackSampledColor);
+	document.getElementById('pickCorners').addEventListener('click', startPickingCorners);
+	setupPtz();
+	document.getElementById('calibrationStart').addEventListener('click', function() {
+		var pattern = document.getElementById('calibrationPattern').value;
+		ws.send(JSON.stringify({ type: 'calibration_start', pattern: pattern }));
+	});
+	['capture', 'finish', 'cancel'].forEach(function(action) {
+		var id = 'calibration' + action.charAt(0).toUpperCase() + action.slice(1);
+		document.getElementById(id).addEventListener('click', function() {
+			ws.send(JSON.stringify({ type: 'calibration_' + action }));
+		});
+	});
+	document.getElementById('queryCamera').addEventListener('click', function() {
+		ws.send(JSON.stringify({ type: 'get_camera_properties' }));
+	});
+	document.getElementById('ackMode').addEventListener('change', function() {
+		var enabled = document.getElementById('ackMode').checked;
+		ws.send(JSON.stringify({ type: 'ack_mode', enabled: enabled }));
+	});
+
 	document.addEventListener('keydown', function(event) {
 		if (event.ctrlKey && event.key === 'x') {
 			var selectedCells = graph.getSelectionCells();